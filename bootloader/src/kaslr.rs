@@ -0,0 +1,128 @@
+// KASLR: カーネルを置く物理アドレスと仮想アドレスを起動のたびにランダムに選ぶ
+// カーネルはPICでビルドされていてload_kernelで再配置するので、どこに置いても動くはず
+
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ptr::NonNull;
+
+use common::address::{DIRECT_MAP_SIZE, KERNEL_BASE, KERNEL_SPACE_SIZE};
+use log::{info, warn};
+use uefi::boot::{self, AllocateType, MemoryType, PAGE_SIZE};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::proto::rng::Rng;
+
+// 2MiBページでマップできるように揃える
+const KERNEL_ALIGN: u64 = 2 * 1024 * 1024;
+// カーネルが自分の物理ページにdirect map経由で触れるように、その範囲に置く
+const KERNEL_MAX_ADDR: u64 = DIRECT_MAP_SIZE as u64;
+
+#[derive(Debug, Clone, Copy)]
+enum RandomSource {
+    Uefi,
+    Rdrand,
+    Rdtsc,
+}
+
+fn uefi_random() -> Option<u64> {
+    let handle = boot::get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = boot::open_protocol_exclusive::<Rng>(handle).ok()?;
+    let mut buf = [0u8; 8];
+    rng.get_rng(None, &mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+fn rdrand() -> Option<u64> {
+    // CPUID.01H:ECX[30]
+    if __cpuid(1).ecx & (1 << 30) == 0 {
+        return None;
+    }
+    // エントロピーが足りないと失敗するので何度か試す
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) ok,
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// UEFIのRNG、RDRAND、RDTSCの順に試す
+// RDTSCは予測できてしまうが、配置がずれるという目的には足りる
+fn random_u64() -> (u64, RandomSource) {
+    if let Some(value) = uefi_random() {
+        (value, RandomSource::Uefi)
+    } else if let Some(value) = rdrand() {
+        (value, RandomSource::Rdrand)
+    } else {
+        (unsafe { _rdtsc() }, RandomSource::Rdtsc)
+    }
+}
+
+// 空き領域の中でsizeバイトを置ける、揃ったアドレスを一様に選ぶ
+fn choose_base(size: u64, random: u64) -> Option<u64> {
+    let memmap = boot::memory_map(MemoryType::LOADER_DATA).ok()?;
+    let slots = |phys_start: u64, page_count: u64| -> (u64, u64) {
+        let start = u64::max(phys_start, KERNEL_ALIGN).next_multiple_of(KERNEL_ALIGN);
+        let end = u64::min(phys_start + page_count * PAGE_SIZE as u64, KERNEL_MAX_ADDR);
+        if start + size > end {
+            (start, 0)
+        } else {
+            (start, (end - size - start) / KERNEL_ALIGN + 1)
+        }
+    };
+    let conventional = || memmap.entries().filter(|d| d.ty == MemoryType::CONVENTIONAL);
+
+    let total: u64 = conventional().map(|d| slots(d.phys_start, d.page_count).1).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut index = random % total;
+    for d in conventional() {
+        let (start, count) = slots(d.phys_start, d.page_count);
+        if index < count {
+            return Some(start + index * KERNEL_ALIGN);
+        }
+        index -= count;
+    }
+    None
+}
+
+// カーネルを置くページを確保する。KASLRが無効か、うまくいかなければどこでもよい
+pub fn allocate_kernel_pages(pages: usize, kaslr: bool) -> NonNull<u8> {
+    if kaslr {
+        let (random, source) = random_u64();
+        match choose_base(pages as u64 * PAGE_SIZE as u64, random) {
+            Some(addr) => match boot::allocate_pages(AllocateType::Address(addr), MemoryType::LOADER_DATA, pages) {
+                Ok(ptr) => {
+                    info!("kaslr: kernel physical base {:x} (random source: {:?})", addr, source);
+                    return ptr;
+                }
+                Err(e) => warn!("kaslr: failed to allocate {:x}: {:?}", addr, e),
+            },
+            None => warn!("kaslr: no room for the kernel"),
+        }
+    }
+    boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).unwrap()
+}
+
+// カーネルを置く仮想アドレス。KERNEL_BASEからKERNEL_SPACE_SIZEの範囲に収める
+pub fn choose_virtual_base(size: usize, kaslr: bool) -> usize {
+    assert!(size <= KERNEL_SPACE_SIZE, "kernel is too large: {:x}", size);
+    if !kaslr {
+        return KERNEL_BASE;
+    }
+    let (random, source) = random_u64();
+    let slots = ((KERNEL_SPACE_SIZE - size) as u64) / KERNEL_ALIGN + 1;
+    let base = KERNEL_BASE + ((random % slots) * KERNEL_ALIGN) as usize;
+    info!("kaslr: kernel virtual base {:x} (random source: {:?})", base, source);
+    base
+}
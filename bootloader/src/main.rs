@@ -2,11 +2,12 @@
 #![no_main]
 
 use elf_rs::ElfFile;
-use log::info;
+use log::{error, info, warn};
 use uefi::boot::PAGE_SIZE;
-use uefi::mem::memory_map::MemoryMap;
+use uefi::mem::memory_map::{MemoryMap, MemoryMapOwned};
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::console::gop::{Mode, ModeInfo, PixelFormat as GopPixelFormat};
 use uefi::proto::media::file::Directory;
 use uefi::proto::media::file::File;
 use uefi::proto::media::file::FileAttribute;
@@ -22,19 +23,17 @@ use uefi::table::cfg::ACPI2_GUID;
 
 use core::arch::asm;
 use core::ffi::c_void;
+use core::mem::MaybeUninit;
 use core::ops::Deref;
-use core::ptr::null_mut;
-use common::{Config, EntryFn, writer_config::*};
+use core::ptr::{null, null_mut};
+use alloc::vec::Vec;
+use common::{BootInfoHeader, Config, EntryFn, address::direct_map, boot_config::*, elf::*, memory_map::MemoryDescriptor, writer_config::*};
 
 #[macro_use]
 extern crate alloc;
 
-#[repr(C, packed)]
-struct Rela {
-    r_offset: u64,
-    r_info: u64,
-    r_addend: i64,
-}
+mod kaslr;
+mod paging;
 
 fn write_memmap(dir: &mut Directory) -> uefi::Result {
     let mut str_buf = [0; 100];
@@ -54,9 +53,131 @@ fn write_memmap(dir: &mut Directory) -> uefi::Result {
     Ok(())
 }
 
-fn load_kernel(dir: &mut Directory) -> Option<(EntryFn, usize, *const c_void, u64, *const c_void)> {
+fn read_file(dir: &mut Directory, path: &str) -> Option<Vec<u8>> {
+    let mut str_buf = [0; 100];
+    let name = uefi::CStr16::from_str_with_buf(path, &mut str_buf).ok()?;
+    let file = dir.open(name, FileMode::Read, FileAttribute::READ_ONLY).ok()?.into_type().ok()?;
+    if let Regular(mut file) = file {
+        let buf = &mut [0u8; 2048];
+        let file_info: &mut FileInfo = file.get_info(buf).ok()?;
+        let mut data = vec![0u8; file_info.file_size() as usize];
+        let read = file.read(&mut data).ok()?;
+        data.truncate(read);
+        Some(data)
+    } else {
+        None
+    }
+}
+
+// boot.cfgはカーネルからも参照するため解放しない
+fn read_boot_config(dir: &mut Directory) -> Option<&'static str> {
+    let data = read_file(dir, BOOT_CONFIG_PATH)?;
+    match core::str::from_utf8(data.leak()) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            warn!("boot.cfg is not valid UTF-8: {:?}", e);
+            None
+        }
+    }
+}
+
+fn convert_pixel_format(info: &ModeInfo) -> PixelFormat {
+    match info.pixel_format() {
+        GopPixelFormat::Rgb => PixelFormat::Rgb,
+        GopPixelFormat::Bgr => PixelFormat::Bgr,
+        GopPixelFormat::Bitmask => match info.pixel_bitmask() {
+            Some(mask) => PixelFormat::Bitmask(PixelBitmask {
+                red: mask.red,
+                green: mask.green,
+                blue: mask.blue,
+                reserved: mask.reserved,
+            }),
+            None => PixelFormat::BltOnly,
+        },
+        GopPixelFormat::BltOnly => PixelFormat::BltOnly,
+    }
+}
+
+// 指定された解像度があればそれを、なければ最大のモードを選ぶ
+// BltOnlyのモードはフレームバッファに書けないので候補にしない
+fn select_video_mode(gop: &mut GraphicsOutput, preferred: Option<(usize, usize)>) -> VideoModeList {
+    let mut list = VideoModeList::empty();
+    let modes: Vec<Mode> = gop.modes().collect();
+    for mode in modes.iter() {
+        let info = mode.info();
+        let video_mode = VideoMode {
+            horizontal_resolution: info.resolution().0,
+            vertical_resolution: info.resolution().1,
+            pixel_format: convert_pixel_format(info),
+        };
+        if !list.push(video_mode) {
+            warn!("too many video modes: {}", modes.len());
+            break;
+        }
+    }
+
+    let usable = || modes.iter().filter(|m| convert_pixel_format(m.info()) != PixelFormat::BltOnly);
+    let preferred_mode = preferred.and_then(|res| {
+        let found = usable().find(|m| m.info().resolution() == res);
+        if found.is_none() {
+            warn!("video mode {}x{} is not available", res.0, res.1);
+        }
+        found
+    });
+    let selected = preferred_mode.or_else(|| usable().max_by_key(|m| {
+        let (w, h) = m.info().resolution();
+        w * h
+    }));
+
+    if let Some(mode) = selected {
+        if let Err(e) = gop.set_mode(mode) {
+            warn!("failed to set video mode: {:?}", e);
+        }
+    }
+
+    let current = gop.current_mode_info();
+    let current_mode = VideoMode {
+        horizontal_resolution: current.resolution().0,
+        vertical_resolution: current.resolution().1,
+        pixel_format: convert_pixel_format(&current),
+    };
+    list.current = list.modes().iter().position(|m| *m == current_mode).unwrap_or(0);
+    info!("video mode: {}x{} ({} modes)", current_mode.horizontal_resolution, current_mode.vertical_resolution, list.len);
+    list
+}
+
+struct LoadedKernel {
+    entry: EntryFn,
+    base: usize,
+    phys_base: u64,
+    size: usize,
+    symtab: *const c_void,
+    symtab_num: u64,
+    strtab: *const c_void,
+    debug_line: *const u8,
+    debug_line_len: usize,
+    segments: LoadSegmentList,
+}
+
+// .dynsymのindex番目のシンボルのロード後のアドレス
+// 未定義のweakシンボルは0として扱う
+fn resolve_symbol(dynsym: &[Elf64Sym], index: u32, base: u64) -> u64 {
+    let sym = match dynsym.get(index as usize) {
+        Some(sym) => sym,
+        None => panic!("symbol index out of range: {}", index),
+    };
+    if sym.st_shndx != SHN_UNDEF {
+        base + sym.st_value
+    } else if sym.binding() == STB_WEAK {
+        0
+    } else {
+        panic!("undefined symbol: {}", index)
+    }
+}
+
+fn load_kernel(dir: &mut Directory, path: &str, kaslr: bool) -> Option<LoadedKernel> {
     let mut str_buf = [0; 100];
-    let name = uefi::CStr16::from_str_with_buf("\\kernel", &mut str_buf).unwrap();
+    let name = uefi::CStr16::from_str_with_buf(path, &mut str_buf).unwrap();
     let kernel_file = dir.open(name, FileMode::Read, FileAttribute::READ_ONLY).unwrap().into_type().unwrap();
     if let Regular(mut kernel_file) = kernel_file {
         let buf = &mut [0u8; 2048];
@@ -70,6 +191,19 @@ fn load_kernel(dir: &mut Directory) -> Option<(EntryFn, usize, *const c_void, u6
         unsafe { kernel_buffer.write_bytes(0, kernel_file_size); }
         let kernel_buffer_slice = unsafe { core::slice::from_raw_parts_mut(kernel_buffer.as_ptr(), kernel_file_size) };
         kernel_file.read(kernel_buffer_slice).unwrap();
+
+        let ehdr = match Elf64Ehdr::parse(kernel_buffer_slice) {
+            Ok(ehdr) => ehdr,
+            Err(e) => {
+                error!("{}: {}", path, e);
+                return None;
+            }
+        };
+        // どこに置いても動くように、PIEとしてリンクされている必要がある
+        if ehdr.e_type != ET_DYN {
+            error!("{}: kernel must be a position independent executable (e_type = {})", path, ehdr.e_type);
+            return None;
+        }
         let elf = Elf::from_bytes(kernel_buffer_slice).unwrap();
         let elf64 = match elf {
             Elf::Elf64(elf) => elf,
@@ -92,14 +226,16 @@ fn load_kernel(dir: &mut Directory) -> Option<(EntryFn, usize, *const c_void, u6
             last
         };
 
-        let kernel_ptr = boot::allocate_pages(
-            boot::AllocateType::AnyPages,
-            boot::MemoryType::LOADER_DATA,
-            last.div_ceil(PAGE_SIZE) // (last + 0xfff) / 0x1000
-        ).unwrap();
+        let kernel_ptr = kaslr::allocate_kernel_pages(
+            last.div_ceil(PAGE_SIZE), // (last + 0xfff) / 0x1000
+            kaslr,
+        );
         let kernel_ptr_len = last.div_ceil(PAGE_SIZE) * PAGE_SIZE;
         unsafe { kernel_ptr.write_bytes(0, kernel_ptr_len); }
         let kernel_slice = unsafe { core::slice::from_raw_parts_mut(kernel_ptr.as_ptr(), kernel_ptr_len) };
+        // 物理的にはkernel_sliceに置き、上位半分の仮想アドレスで動くように再配置する
+        let phys_base = kernel_slice.as_ptr() as u64;
+        let base = kaslr::choose_virtual_base(kernel_ptr_len, kaslr) as u64;
 
         for hw in elf64.program_header_iter() {
             let h = hw.deref();
@@ -111,11 +247,30 @@ fn load_kernel(dir: &mut Directory) -> Option<(EntryFn, usize, *const c_void, u6
             }
         }
 
+        let phdrs = unsafe {
+            let ptr = kernel_buffer_slice.as_ptr().add(ehdr.e_phoff as usize) as *const Elf64Phdr;
+            core::slice::from_raw_parts(ptr, ehdr.e_phnum as usize)
+        };
+        let segments = LoadSegmentList::from_program_headers(phdrs, base as usize);
+        for segment in segments.segments() {
+            info!("segment: {:x}-{:x} {}", segment.addr, segment.addr + segment.size, segment.flags);
+        }
+
         let rela_dyn_section = elf64.lookup_section(b".rela.dyn");
         let rela_plt_section = elf64.lookup_section(b".rela.plt");
-        // let dynsym_section = elf64.lookup_section(b".dynsym").unwrap();
-
-        // let sym_table = ((kernel_buffer as u64) + dynsym_section.offset()) as *const Sym;
+        let dynsym: &[Elf64Sym] = match elf64.lookup_section(b".dynsym") {
+            Some(s) => {
+                let Some(count) = s.size().checked_div(s.entsize()) else {
+                    error!("{}: .dynsym has zero entsize", path);
+                    return None;
+                };
+                unsafe {
+                    let ptr = kernel_buffer_slice.as_ptr().add(s.offset() as usize) as *const Elf64Sym;
+                    core::slice::from_raw_parts(ptr, count as usize)
+                }
+            },
+            None => &[],
+        };
 
         // https://docs.oracle.com/cd/E23824_01/html/819-0690/chapter6-54839.html#chapter7-2
         let iter = [rela_dyn_section, rela_plt_section].into_iter();
@@ -124,50 +279,118 @@ fn load_kernel(dir: &mut Directory) -> Option<(EntryFn, usize, *const c_void, u6
                 Some(x) => x,
                 None => continue
             };
-            let rela_dyn = (&kernel_buffer_slice[sec.offset() as usize]) as *const u8 as *const Rela;
+            // entsizeが0の壊れたELFでは数えられない
+            let Some(count) = sec.size().checked_div(sec.entsize()) else {
+                error!("{}: relocation section has zero entsize", path);
+                return None;
+            };
+            let rela_dyn = (&kernel_buffer_slice[sec.offset() as usize]) as *const u8 as *const Elf64Rela;
             unsafe {
-                for i in 0..sec.size() / sec.entsize() {
+                for i in 0..count {
                     let j = i as usize;
-                    let r = &*rela_dyn.add(j);
-                    let ty = r.r_info & 0xffffffff;
-                    match ty {
-                        8 => { // R_X86_64_RELATIVE
-                            let to = (kernel_slice.as_mut_ptr().add(r.r_offset as usize)) as *mut u64;
-                            *to = kernel_slice.as_ptr().add(r.r_addend as usize) as u64;
+                    let r = rela_dyn.add(j).read_unaligned();
+                    let offset = r.r_offset as usize;
+                    if offset + size_of::<u64>() > kernel_ptr_len {
+                        panic!("relocation offset out of range: {:x}", offset);
+                    }
+                    let to = kernel_slice.as_mut_ptr().add(offset) as *mut u64;
+                    // S: シンボルの値, A: addend, B: ロードしたベースアドレス
+                    match r.ty() {
+                        R_X86_64_NONE => {}
+                        R_X86_64_64 => { // S + A
+                            to.write_unaligned(resolve_symbol(dynsym, r.sym(), base).wrapping_add_signed(r.r_addend));
+                        }
+                        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => { // S
+                            to.write_unaligned(resolve_symbol(dynsym, r.sym(), base));
                         }
-                        _ => panic!("unsupported reallocation type: {}", ty)
+                        R_X86_64_RELATIVE => { // B + A
+                            to.write_unaligned(base.wrapping_add_signed(r.r_addend));
+                        }
+                        ty => panic!("unsupported reallocation type: {}", ty)
                     }
                 }
             }
         }
 
         let (symtab_section, symtab_num) = if let Some(s) = elf64.lookup_section(b".symtab") {
-            (kernel_buffer_slice.as_ptr().addr() as u64 + s.offset(), s.size() / s.entsize())
+            let Some(count) = s.size().checked_div(s.entsize()) else {
+                error!("{}: .symtab has zero entsize", path);
+                return None;
+            };
+            (direct_map(kernel_buffer_slice.as_ptr().addr() as u64 + s.offset()), count)
         } else {
             (0, 0)
         };
         let strtab_section = if let Some(s) = elf64.lookup_section(b".strtab") {
-            kernel_buffer_slice.as_ptr().addr() as u64 + s.offset()
+            direct_map(kernel_buffer_slice.as_ptr().addr() as u64 + s.offset())
         } else {
             0
         };
+        // デバッグ情報があれば行番号も出せる
+        let (debug_line, debug_line_len) = if let Some(s) = elf64.lookup_section(b".debug_line") {
+            (direct_map(kernel_buffer_slice.as_ptr().addr() as u64 + s.offset()), s.size() as usize)
+        } else {
+            (0, 0)
+        };
         // バックトレースの際に関数名が参照できるようにfreeしない
         // unsafe { boot::free_pool(kernel_buffer).unwrap(); }
 
-        let entry_point_address =  unsafe { *(kernel_slice.as_ptr().add(24) as *const usize) };
-        let entry_point = entry_point_address + kernel_slice.as_ptr() as usize;
+        let entry_point = base as usize + ehdr.e_entry as usize;
         info!("entry point: {:x}", entry_point);
+        let entry_executable = segments.segments().iter().any(|s| {
+            s.flags.contains(SegmentFlags::EXECUTE) && (s.addr..s.addr + s.size).contains(&entry_point)
+        });
+        if !entry_executable {
+            error!("{}: entry point {:x} is not in an executable segment", path, entry_point);
+            return None;
+        }
 
         let kernel_entry = unsafe {
             let f: EntryFn = core::mem::transmute(entry_point);
             f
         };
-        Some((kernel_entry, kernel_slice.as_ptr().addr(), symtab_section as *const c_void, symtab_num, strtab_section as *const c_void))
+        Some(LoadedKernel {
+            entry: kernel_entry,
+            base: base as usize,
+            phys_base,
+            size: kernel_ptr_len,
+            symtab: symtab_section as *const c_void,
+            symtab_num,
+            strtab: strtab_section as *const c_void,
+            debug_line: debug_line as *const u8,
+            debug_line_len,
+            segments,
+        })
     } else {
         None
     }
 }
 
+// exit_boot_services後はメモリを確保できないので、先に余裕をもって確保しておく
+fn allocate_memmap_buffer() -> &'static mut [MaybeUninit<MemoryDescriptor>] {
+    let len = boot::memory_map(boot::MemoryType::LOADER_DATA).map(|m| m.len()).unwrap_or(0) + 64;
+    let ptr = boot::allocate_pool(boot::MemoryType::LOADER_DATA, len * size_of::<MemoryDescriptor>()).unwrap();
+    unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr() as *mut MaybeUninit<MemoryDescriptor>, len) }
+}
+
+// uefiの型に依存しない配列にしてphys_startの順に並べる
+// bufに入りきらない分は捨てる(この時点ではログも出せない)
+fn convert_memmap(memmap: &MemoryMapOwned, buf: &'static mut [MaybeUninit<MemoryDescriptor>]) -> common::memory_map::MemoryMap {
+    let mut len = 0;
+    for (d, slot) in memmap.entries().zip(buf.iter_mut()) {
+        slot.write(MemoryDescriptor {
+            ty: common::memory_map::MemoryType(d.ty.0),
+            phys_start: d.phys_start,
+            page_count: d.page_count,
+            attribute: d.att.bits(),
+        });
+        len += 1;
+    }
+    let descriptors = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut MemoryDescriptor, len) };
+    descriptors.sort_unstable_by_key(|d| d.phys_start);
+    common::memory_map::MemoryMap { descriptors: descriptors.as_ptr(), len }
+}
+
 #[entry]
 fn main() -> Status {
     uefi::helpers::init().unwrap();
@@ -177,7 +400,11 @@ fn main() -> Status {
     info!("Hello, World!");
     let mut fs = boot::get_image_file_system(boot::image_handle()).unwrap();
     let mut root_dir = fs.open_volume().unwrap();
-    let k = load_kernel(&mut root_dir);
+    let boot_config_text = read_boot_config(&mut root_dir);
+    let boot_config = boot_config_text.map(BootConfig::parse).unwrap_or_default();
+    info!("boot config: {:?}", boot_config);
+
+    let k = load_kernel(&mut root_dir, boot_config.kernel_path(), boot_config.kaslr_enabled());
 
     let d = write_memmap(&mut root_dir);
     info!("write_memmap: {:?}", d);
@@ -201,39 +428,58 @@ fn main() -> Status {
     let gop_handle = boot::get_handle_for_protocol::<GraphicsOutput>().unwrap();
     let mut gop = boot::open_protocol_exclusive::<GraphicsOutput>(gop_handle).unwrap();
 
+    let video_modes = select_video_mode(&mut gop, boot_config.video);
     let mode = gop.current_mode_info();
 
+    let pixel_format = convert_pixel_format(&mode);
+    if pixel_format == PixelFormat::BltOnly {
+        // フレームバッファに直接書けないのでカーネルは画面を扱えない
+        error!("GOP mode is BltOnly: linear frame buffer is not available");
+        return Status::UNSUPPORTED;
+    }
+
     let config = FrameBufferConfig {
-        frame_buffer: gop.frame_buffer().as_mut_ptr(),
+        frame_buffer: direct_map(gop.frame_buffer().as_mut_ptr() as u64) as *mut u8,
         pixels_per_scan_line: mode.stride(),
         horizontal_resolution: mode.resolution().0,
         vertical_resolution: mode.resolution().1,
-        pixel_format: {
-            let n = mode.pixel_format() as usize;
-            match n {
-                0 => Some(PixelFormat::Rgb),
-                1 => Some(PixelFormat::Bgr),
-                // 2 => Some(PixelFormat::Bitmask),
-                // 3 => Some(PixelFormat::BltOnly),
-                _ => None
-            }.unwrap()
-        },
+        pixel_format,
     };
 
     let config_ptr = boot::allocate_pool(boot::MemoryType::LOADER_DATA, size_of::<Config>()).unwrap().as_ptr() as *mut Config;
-    unsafe { core::ptr::write(&raw mut (*config_ptr).frame_buffer_config, config); }
+    unsafe {
+        core::ptr::write(&raw mut (*config_ptr).header, BootInfoHeader::new());
+        core::ptr::write(&raw mut (*config_ptr).frame_buffer_config, config);
+        core::ptr::write(&raw mut (*config_ptr).video_modes, video_modes);
+        core::ptr::write(&raw mut (*config_ptr).boot_config, boot_config_text.map_or(null(), |t| direct_map(t.as_ptr() as u64) as *const u8));
+        core::ptr::write(&raw mut (*config_ptr).boot_config_len, boot_config_text.map_or(0, |t| t.len()));
+    }
 
-    if let Some((kernel_entry, kernel_addr, symtab_ptr, symtab_num, strtab_ptr)) = k {
+    if let Some(kernel) = k {
+        // ページテーブル用のページもメモリマップに載るように、exit_boot_servicesより前に作る
+        let mut page_tables = paging::PageTables::new();
+        page_tables.map(kernel.base, kernel.phys_base, kernel.size);
+        let memmap_buffer = allocate_memmap_buffer();
         let memmap = unsafe { boot::exit_boot_services(None) };
+        let mut boot_memmap = convert_memmap(&memmap, memmap_buffer);
+        boot_memmap.descriptors = direct_map(boot_memmap.descriptors as u64) as *const MemoryDescriptor;
+        // boot servicesはもう使えないので解放させない
+        core::mem::forget(memmap);
         unsafe {
-            core::ptr::write(&raw mut (*config_ptr).memmap, memmap);
-            core::ptr::write(&raw mut (*config_ptr).acpi_table_ptr, acpi_table_ptr);
-            core::ptr::write(&raw mut (*config_ptr).base, kernel_addr);
-            core::ptr::write(&raw mut (*config_ptr).symtab, symtab_ptr);
-            core::ptr::write(&raw mut (*config_ptr).symtab_num, symtab_num as usize);
-            core::ptr::write(&raw mut (*config_ptr).strtab, strtab_ptr);
+            core::ptr::write(&raw mut (*config_ptr).memmap, boot_memmap);
+            core::ptr::write(&raw mut (*config_ptr).acpi_table_ptr, direct_map(acpi_table_ptr as u64) as *const c_void);
+            core::ptr::write(&raw mut (*config_ptr).base, kernel.base);
+            core::ptr::write(&raw mut (*config_ptr).kernel_phys_base, kernel.phys_base as usize);
+            core::ptr::write(&raw mut (*config_ptr).symtab, kernel.symtab);
+            core::ptr::write(&raw mut (*config_ptr).symtab_num, kernel.symtab_num as usize);
+            core::ptr::write(&raw mut (*config_ptr).strtab, kernel.strtab);
+            core::ptr::write(&raw mut (*config_ptr).debug_line, kernel.debug_line);
+            core::ptr::write(&raw mut (*config_ptr).debug_line_len, kernel.debug_line_len);
+            core::ptr::write(&raw mut (*config_ptr).segments, kernel.segments);
         }
-        kernel_entry(config_ptr);
+        // 下位半分もidentity mapしてあるので、切り替えた後もこのまま動ける
+        unsafe { paging::switch(&page_tables); }
+        (kernel.entry)(direct_map(config_ptr as u64) as *const Config);
     }
     loop {
         unsafe {
@@ -0,0 +1,82 @@
+// カーネルに渡すページテーブルを作る
+//
+// - 上位半分: direct mapとカーネルイメージ(common::address)
+// - 下位半分: 切り替え直後もブートローダ自身が動けるように、direct mapと同じものでidentity mapしておく
+//   (カーネルは自分のページテーブルに切り替えるときにこれを外す)
+
+use core::arch::asm;
+
+use common::address::{DIRECT_MAP_BASE, DIRECT_MAP_SIZE};
+use uefi::boot::{self, AllocateType, MemoryType, PAGE_SIZE};
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+
+const PAGE_SIZE_2M: usize = 512 * PAGE_SIZE;
+const PAGE_SIZE_1G: usize = 512 * PAGE_SIZE_2M;
+
+type PageTable = [u64; 512];
+
+// ページテーブル用のページはLOADER_DATAなので、カーネルが上書きすることはない
+fn allocate_table() -> &'static mut PageTable {
+    let ptr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1).unwrap();
+    unsafe {
+        ptr.write_bytes(0, PAGE_SIZE);
+        &mut *(ptr.as_ptr() as *mut PageTable)
+    }
+}
+
+// 子のテーブルがなければ作る
+// UEFIの間はidentity mapなので、テーブルのアドレスはそのまま物理アドレスとして使える
+fn next_table(table: &mut PageTable, index: usize) -> &'static mut PageTable {
+    if table[index] & PRESENT == 0 {
+        let next = allocate_table();
+        table[index] = next.as_ptr() as u64 | PRESENT | WRITABLE;
+    }
+    unsafe { &mut *((table[index] & !0xfff) as *mut PageTable) }
+}
+
+const fn index(virt: usize, level: u32) -> usize {
+    (virt >> (12 + 9 * level)) & 0x1ff
+}
+
+pub struct PageTables {
+    pml4: &'static mut PageTable,
+}
+
+impl PageTables {
+    pub fn new() -> Self {
+        let pml4 = allocate_table();
+        for i in 0..DIRECT_MAP_SIZE / PAGE_SIZE_1G {
+            let pd = next_table(next_table(pml4, index(DIRECT_MAP_BASE, 3)), i);
+            for (j, entry) in pd.iter_mut().enumerate() {
+                *entry = (i * PAGE_SIZE_1G + j * PAGE_SIZE_2M) as u64 | PRESENT | WRITABLE | HUGE_PAGE;
+            }
+        }
+        pml4[0] = pml4[index(DIRECT_MAP_BASE, 3)];
+        PageTables { pml4 }
+    }
+
+    // [virt, virt + size)をphysからの連続した領域に4KiBページでマップする
+    pub fn map(&mut self, virt: usize, phys: u64, size: usize) {
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let v = virt + offset;
+            let pdpt = next_table(self.pml4, index(v, 3));
+            let pd = next_table(pdpt, index(v, 2));
+            let pt = next_table(pd, index(v, 1));
+            pt[index(v, 0)] = (phys + offset as u64) | PRESENT | WRITABLE;
+        }
+    }
+
+    pub fn pml4_address(&self) -> u64 {
+        self.pml4.as_ptr() as u64
+    }
+}
+
+// safety: 実行中のコードとスタック、以降触るデータがすべて新しいテーブルでもマップされていること
+pub unsafe fn switch(tables: &PageTables) {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) tables.pml4_address());
+    }
+}
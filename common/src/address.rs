@@ -0,0 +1,16 @@
+// カーネルの仮想アドレス空間の配置(ブートローダとカーネルで共有する)
+// 下位半分はユーザ空間のために空けておき、カーネルは上位半分だけを使う
+//
+//   0xffff_8000_0000_0000 - : 物理メモリのdirect map (DIRECT_MAP_SIZE分)
+//   0xffff_ffff_8000_0000 - : カーネルイメージ (KERNEL_SPACE_SIZEの範囲でKASLRする)
+
+pub const DIRECT_MAP_BASE: usize = 0xffff_8000_0000_0000;
+pub const DIRECT_MAP_SIZE: usize = 64 << 30;
+
+pub const KERNEL_BASE: usize = 0xffff_ffff_8000_0000;
+pub const KERNEL_SPACE_SIZE: usize = 1 << 30;
+
+// 物理アドレスをdirect map上の仮想アドレスにする
+pub const fn direct_map(phys: u64) -> usize {
+    DIRECT_MAP_BASE + phys as usize
+}
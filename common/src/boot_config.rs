@@ -0,0 +1,72 @@
+// ESP上のboot.cfgの内容
+// `key=value`を空白か改行で区切って並べ、`#`以降はコメントとして扱う
+// (Multiboot2のコマンドラインもそのまま解釈できる)
+//
+//   kernel=\kernel
+//   log=info
+//   video=1280x800
+//   test=timer
+//   kaslr=off
+//   gdb=com2
+
+pub const BOOT_CONFIG_PATH: &str = "\\boot.cfg";
+pub const DEFAULT_KERNEL_PATH: &str = "\\kernel";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootConfig<'a> {
+    pub kernel: Option<&'a str>,
+    pub log: Option<&'a str>,
+    pub video: Option<(usize, usize)>,
+    pub test: Option<&'a str>,
+    pub kaslr: Option<bool>,
+    pub gdb: Option<&'a str>,
+}
+
+impl<'a> BootConfig<'a> {
+    pub fn parse(text: &'a str) -> Self {
+        let mut config = BootConfig::default();
+        for (key, value) in entries(text) {
+            match key {
+                "kernel" => config.kernel = Some(value),
+                "log" => config.log = Some(value),
+                "video" => config.video = parse_resolution(value),
+                "test" => config.test = Some(value),
+                "kaslr" => config.kaslr = parse_switch(value),
+                "gdb" => config.gdb = Some(value),
+                _ => {}
+            }
+        }
+        config
+    }
+    pub fn kernel_path(&self) -> &'a str {
+        self.kernel.unwrap_or(DEFAULT_KERNEL_PATH)
+    }
+    // 指定がなければ有効
+    pub fn kaslr_enabled(&self) -> bool {
+        self.kaslr.unwrap_or(true)
+    }
+}
+
+// コメントを除いた`key=value`を順に返す
+pub fn entries(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines().flat_map(|line| {
+        let line = match line.split_once('#') {
+            Some((l, _)) => l,
+            None => line,
+        };
+        line.split_whitespace().filter_map(|token| token.split_once('='))
+    })
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (w, h) = value.split_once('x')?;
+    Some((w.parse().ok()?, h.parse().ok()?))
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,197 @@
+// カーネルのELFを読むための最小限の定義と、ロードしたセグメントの情報
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+pub const ELFMAG: [u8; 4] = *b"\x7fELF";
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const EM_X86_64: u16 = 62;
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const STB_WEAK: u8 = 2;
+pub const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    InvalidMagic,
+    UnsupportedClass(u8),
+    UnsupportedEndian(u8),
+    UnsupportedMachine(u16),
+}
+
+impl Elf64Ehdr {
+    // x86_64向けの64bit ELFかを確かめてヘッダを返す
+    pub fn parse(bytes: &[u8]) -> Result<Elf64Ehdr, ElfError> {
+        if bytes.len() < size_of::<Elf64Ehdr>() {
+            return Err(ElfError::TooShort);
+        }
+        let ehdr = unsafe { (bytes.as_ptr() as *const Elf64Ehdr).read_unaligned() };
+        if ehdr.e_ident[..4] != ELFMAG {
+            Err(ElfError::InvalidMagic)
+        } else if ehdr.e_ident[4] != ELFCLASS64 {
+            Err(ElfError::UnsupportedClass(ehdr.e_ident[4]))
+        } else if ehdr.e_ident[5] != ELFDATA2LSB {
+            Err(ElfError::UnsupportedEndian(ehdr.e_ident[5]))
+        } else if ehdr.e_machine != EM_X86_64 {
+            Err(ElfError::UnsupportedMachine(ehdr.e_machine))
+        } else {
+            Ok(ehdr)
+        }
+    }
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ElfError::TooShort => write!(f, "file is too short for an ELF header"),
+            ElfError::InvalidMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedClass(c) => write!(f, "unsupported ELF class: {}", c),
+            ElfError::UnsupportedEndian(e) => write!(f, "unsupported ELF data encoding: {}", e),
+            ElfError::UnsupportedMachine(m) => write!(f, "unsupported machine: {}", m),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+impl Elf64Sym {
+    pub const fn binding(&self) -> u8 {
+        self.st_info >> 4
+    }
+    pub const fn sym_type(&self) -> u8 {
+        self.st_info & 0xf
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf64Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+impl Elf64Rela {
+    pub const fn ty(&self) -> u32 {
+        (self.r_info & 0xffffffff) as u32
+    }
+    pub const fn sym(&self) -> u32 {
+        (self.r_info >> 32) as u32
+    }
+}
+
+// p_flagsと同じ値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct SegmentFlags(pub u32);
+
+impl SegmentFlags {
+    pub const EXECUTE: SegmentFlags = SegmentFlags(1);
+    pub const WRITE: SegmentFlags = SegmentFlags(2);
+    pub const READ: SegmentFlags = SegmentFlags(4);
+
+    pub const fn contains(&self, other: SegmentFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::fmt::Display for SegmentFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let flag = |bit, c| if self.contains(bit) { c } else { '-' };
+        write!(f, "{}{}{}", flag(SegmentFlags::READ, 'r'), flag(SegmentFlags::WRITE, 'w'), flag(SegmentFlags::EXECUTE, 'x'))
+    }
+}
+
+// PT_LOADセグメントの配置。addrはロード後の仮想アドレス
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct LoadSegment {
+    pub addr: usize,
+    pub size: usize,
+    pub flags: SegmentFlags,
+}
+
+pub const MAX_LOAD_SEGMENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct LoadSegmentList {
+    pub len: usize,
+    pub segments: [LoadSegment; MAX_LOAD_SEGMENTS],
+}
+
+impl LoadSegmentList {
+    pub const fn empty() -> Self {
+        LoadSegmentList { len: 0, segments: [LoadSegment { addr: 0, size: 0, flags: SegmentFlags(0) }; MAX_LOAD_SEGMENTS] }
+    }
+    // 入りきらなければfalse
+    pub fn push(&mut self, segment: LoadSegment) -> bool {
+        if self.len >= MAX_LOAD_SEGMENTS {
+            return false;
+        }
+        self.segments[self.len] = segment;
+        self.len += 1;
+        true
+    }
+    pub fn segments(&self) -> &[LoadSegment] {
+        &self.segments[..self.len]
+    }
+    // program headerの並びからPT_LOADだけを拾う
+    pub fn from_program_headers(phdrs: &[Elf64Phdr], base: usize) -> Self {
+        let mut list = LoadSegmentList::empty();
+        for ph in phdrs.iter().filter(|ph| ph.p_type == PT_LOAD) {
+            list.push(LoadSegment { addr: base + ph.p_vaddr as usize, size: ph.p_memsz as usize, flags: SegmentFlags(ph.p_flags) });
+        }
+        list
+    }
+}
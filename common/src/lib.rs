@@ -1,19 +1,115 @@
 #![no_std]
 pub mod writer_config;
+pub mod boot_config;
+pub mod memory_map;
+pub mod elf;
+pub mod address;
+pub mod symbols;
 
 use core::ffi::c_void;
-use uefi::mem::memory_map::MemoryMapOwned;
+
+// ブートローダとカーネルの間の取り決め
+// 先頭のheaderとframe_buffer_configの位置はversionが変わっても動かさない
+// (versionが合わなくてもパニック画面は出せるように)
+// ポインタはすべて仮想アドレスで、カーネルイメージ以外はdirect map(address.rs)上を指す
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"MIKANBOO");
+pub const BOOT_INFO_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootInfoHeader {
+    pub magic: u64,
+    pub version: u32,
+    pub size: u32,
+}
+
+impl BootInfoHeader {
+    pub const fn new() -> Self {
+        BootInfoHeader {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: size_of::<Config>() as u32,
+        }
+    }
+}
+
+impl Default for BootInfoHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    NullPointer,
+    InvalidMagic(u64),
+    VersionMismatch { expected: u32, found: u32 },
+    SizeMismatch { expected: u32, found: u32 },
+}
+
+impl BootInfoError {
+    // frame_buffer_configを信用してよいか
+    pub fn frame_buffer_usable(&self) -> bool {
+        matches!(self, BootInfoError::VersionMismatch { .. } | BootInfoError::SizeMismatch { .. })
+    }
+}
+
+impl core::fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            BootInfoError::NullPointer => write!(f, "boot info is null"),
+            BootInfoError::InvalidMagic(m) => write!(f, "invalid boot info magic: {:#018x}", m),
+            BootInfoError::VersionMismatch { expected, found } => write!(f, "boot info version mismatch: expected {}, found {}", expected, found),
+            BootInfoError::SizeMismatch { expected, found } => write!(f, "boot info size mismatch: expected {}, found {}", expected, found),
+        }
+    }
+}
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct Config {
+    pub header: BootInfoHeader,
     pub frame_buffer_config: writer_config::FrameBufferConfig,
-    pub memmap: MemoryMapOwned,
+    pub memmap: memory_map::MemoryMap,
     pub acpi_table_ptr: *const c_void,
     pub base: usize,
+    // baseに対応する物理アドレス
+    pub kernel_phys_base: usize,
     pub symtab: *const c_void,
     pub symtab_num: usize,
     pub strtab: *const c_void,
+    // バックトレースで行番号を出すための.debug_line。なければnull
+    pub debug_line: *const u8,
+    pub debug_line_len: usize,
+    pub video_modes: writer_config::VideoModeList,
+    // boot.cfgの中身(UTF-8)。ファイルがなければnull
+    pub boot_config: *const u8,
+    pub boot_config_len: usize,
+    // カーネルのPT_LOADセグメントとその権限(W^Xの強制に使う)
+    pub segments: elf::LoadSegmentList,
+}
+
+impl Config {
+    /// headerだけを読んで、このカーネルが解釈できる形式かを確かめる
+    ///
+    /// # Safety
+    /// ptrがnullでなければ、少なくともBootInfoHeaderの分だけ読めること
+    pub unsafe fn validate(ptr: *const Config) -> Result<(), BootInfoError> {
+        if ptr.is_null() {
+            return Err(BootInfoError::NullPointer);
+        }
+        let header = unsafe { (ptr as *const BootInfoHeader).read_unaligned() };
+        let expected = BootInfoHeader::new();
+        if header.magic != expected.magic {
+            Err(BootInfoError::InvalidMagic(header.magic))
+        } else if header.version != expected.version {
+            Err(BootInfoError::VersionMismatch { expected: expected.version, found: header.version })
+        } else if header.size != expected.size {
+            Err(BootInfoError::SizeMismatch { expected: expected.size, found: header.size })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 pub type EntryFn = extern "sysv64" fn(*const Config) -> !;
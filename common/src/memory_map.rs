@@ -0,0 +1,63 @@
+// UEFIのメモリマップをuefiクレートに依存しない形で渡すための型
+// 値はUEFI仕様のEFI_MEMORY_TYPE, EFI_MEMORY_DESCRIPTORと同じ
+
+pub const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct MemoryType(pub u32);
+
+impl MemoryType {
+    pub const RESERVED: MemoryType = MemoryType(0);
+    pub const LOADER_CODE: MemoryType = MemoryType(1);
+    pub const LOADER_DATA: MemoryType = MemoryType(2);
+    pub const BOOT_SERVICES_CODE: MemoryType = MemoryType(3);
+    pub const BOOT_SERVICES_DATA: MemoryType = MemoryType(4);
+    pub const RUNTIME_SERVICES_CODE: MemoryType = MemoryType(5);
+    pub const RUNTIME_SERVICES_DATA: MemoryType = MemoryType(6);
+    pub const CONVENTIONAL: MemoryType = MemoryType(7);
+    pub const UNUSABLE: MemoryType = MemoryType(8);
+    pub const ACPI_RECLAIM: MemoryType = MemoryType(9);
+    pub const ACPI_NON_VOLATILE: MemoryType = MemoryType(10);
+    pub const MMIO: MemoryType = MemoryType(11);
+    pub const MMIO_PORT_SPACE: MemoryType = MemoryType(12);
+    pub const PAL_CODE: MemoryType = MemoryType(13);
+    pub const PERSISTENT_MEMORY: MemoryType = MemoryType(14);
+
+    // カーネルが自由に使ってよい領域か
+    pub const fn is_available(&self) -> bool {
+        self.0 == Self::CONVENTIONAL.0 || self.0 == Self::BOOT_SERVICES_CODE.0 || self.0 == Self::BOOT_SERVICES_DATA.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub ty: MemoryType,
+    pub phys_start: u64,
+    pub page_count: u64,
+    pub attribute: u64,
+}
+
+// phys_startの昇順に並んだ記述子の配列
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryMap {
+    pub descriptors: *const MemoryDescriptor,
+    pub len: usize,
+}
+
+impl MemoryMap {
+    pub const fn empty() -> Self {
+        MemoryMap { descriptors: core::ptr::null(), len: 0 }
+    }
+    /// # Safety
+    /// descriptorsがnullでなければ、len個の有効な記述子を指していること
+    pub unsafe fn entries(&self) -> &[MemoryDescriptor] {
+        if self.descriptors.is_null() {
+            &[]
+        } else {
+            unsafe { core::slice::from_raw_parts(self.descriptors, self.len) }
+        }
+    }
+}
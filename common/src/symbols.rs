@@ -0,0 +1,82 @@
+// カーネルに埋め込むシンボルテーブルの形式(kernel/build.rsが作り、backtraceが引く)
+//
+//   SymbolTableHeader
+//   [SymbolEntry; count]  addrの昇順
+//   名前(UTF-8)を並べたもの
+//
+// アドレスはすべてカーネルイメージ先頭からのオフセット
+// 埋め込んでもレイアウトが変わらないように、全体をSYMBOL_TABLE_CAPACITYに0埋めする
+
+pub const SYMBOL_TABLE_MAGIC: u64 = u64::from_le_bytes(*b"KSYMTAB\0");
+pub const SYMBOL_TABLE_CAPACITY: usize = 1024 * 1024;
+pub const SYMBOL_TABLE_SECTION: &str = ".kernel_symbols";
+// テーブル自身を指すstaticの名前(マングルされた名前にこれが含まれる)
+pub const SYMBOL_TABLE_STATIC: &str = "KERNEL_SYMBOLS";
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SymbolTableHeader {
+    pub magic: u64,
+    // テーブル自身のオフセット。実際の位置と比べてレイアウトが変わっていないかを確かめる
+    pub self_offset: u64,
+    pub count: u32,
+    pub names_len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SymbolEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub name_offset: u32,
+    pub name_len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    pub header: SymbolTableHeader,
+    entries: &'a [SymbolEntry],
+    names: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    // 中身が空(0埋め)か壊れていればNone
+    // bytesはSymbolEntryの境界に揃っていること
+    pub fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        let header_size = size_of::<SymbolTableHeader>();
+        if bytes.len() < header_size || bytes.as_ptr().align_offset(align_of::<SymbolEntry>()) != 0 {
+            return None;
+        }
+        let header = unsafe { (bytes.as_ptr() as *const SymbolTableHeader).read() };
+        if header.magic != SYMBOL_TABLE_MAGIC {
+            return None;
+        }
+        let entries_size = header.count as usize * size_of::<SymbolEntry>();
+        let names_start = header_size + entries_size;
+        let names_end = names_start + header.names_len as usize;
+        if names_end > bytes.len() {
+            return None;
+        }
+        let entries = unsafe { core::slice::from_raw_parts(bytes.as_ptr().add(header_size) as *const SymbolEntry, header.count as usize) };
+        Some(SymbolTable { header, entries, names: &bytes[names_start..names_end] })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // offsetを含むシンボルの名前と、シンボル先頭からのずれを返す
+    pub fn lookup(&self, offset: u64) -> Option<(&'a str, u64)> {
+        let idx = self.entries.partition_point(|e| e.addr <= offset).checked_sub(1)?;
+        let entry = &self.entries[idx];
+        if offset >= entry.addr + entry.size {
+            return None;
+        }
+        let name = self.names.get(entry.name_offset as usize..(entry.name_offset + entry.name_len) as usize)?;
+        Some((core::str::from_utf8(name).ok()?, offset - entry.addr))
+    }
+}
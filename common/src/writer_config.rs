@@ -3,10 +3,30 @@
 pub enum PixelFormat {
     Rgb = 0,
     Bgr,
-    Bitmask,
+    Bitmask(PixelBitmask),
     BltOnly,
 }
 
+// GOPのPixelBitmaskと同じ並び
+// 各maskは1pixelをlittle endianで読んだ値に対するビット位置を表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PixelBitmask {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
+impl PixelBitmask {
+    // 使われている最上位ビットから1pixelのビット数を決める(8の倍数に切り上げ)
+    pub const fn bits_per_pixel(&self) -> usize {
+        let all = self.red | self.green | self.blue | self.reserved;
+        let used = (u32::BITS - all.leading_zeros()) as usize;
+        used.div_ceil(8) * 8
+    }
+}
+
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
@@ -17,3 +37,47 @@ pub struct FrameBufferConfig {
     pub vertical_resolution: usize,
     pub pixel_format: PixelFormat,
 }
+
+pub const MAX_VIDEO_MODES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct VideoMode {
+    pub horizontal_resolution: usize,
+    pub vertical_resolution: usize,
+    pub pixel_format: PixelFormat,
+}
+
+// ブートローダが列挙したGOPのモード一覧
+// allocが使えない前提なので固定長配列で持つ
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct VideoModeList {
+    pub current: usize,
+    pub len: usize,
+    pub modes: [VideoMode; MAX_VIDEO_MODES],
+}
+
+impl VideoModeList {
+    pub const fn empty() -> Self {
+        VideoModeList {
+            current: 0,
+            len: 0,
+            modes: [VideoMode { horizontal_resolution: 0, vertical_resolution: 0, pixel_format: PixelFormat::BltOnly }; MAX_VIDEO_MODES],
+        }
+    }
+    pub fn push(&mut self, mode: VideoMode) -> bool {
+        if self.len >= MAX_VIDEO_MODES {
+            return false;
+        }
+        self.modes[self.len] = mode;
+        self.len += 1;
+        true
+    }
+    pub fn modes(&self) -> &[VideoMode] {
+        &self.modes[..usize::min(self.len, MAX_VIDEO_MODES)]
+    }
+    pub fn current(&self) -> Option<&VideoMode> {
+        self.modes().get(self.current)
+    }
+}
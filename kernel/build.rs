@@ -1,17 +1,47 @@
 use std::process::Command;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::ffi::CStr;
+
+use common::elf::{Elf64Sym, SHN_UNDEF, STT_FUNC};
+use common::symbols::*;
+use elf_rs::{Elf, ElfFile};
+
+// シンボルテーブルを作るためだけにカーネルをビルドしているときに立てる
+const SYMBOLS_PASS_ENV: &str = "KERNEL_SYMBOLS_PASS";
 
 fn main() {
+    let profile = std::env::var("PROFILE").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    // Multiboot2のヘッダをファイルの先頭に置く
+    println!("cargo:rustc-link-arg=-T{}", manifest_dir.join("multiboot2.ld").display());
+
+    if env::var_os(SYMBOLS_PASS_ENV).is_some() {
+        // 中身が空でも大きさは同じにしておく(レイアウトを変えないため)
+        write_symbol_table(&out_dir, &[], 0);
+    } else {
+        build_bootloader(&profile);
+        let kernel_path = build_kernel_for_symbols(&profile);
+        let (symbols, self_offset) = read_symbols(&kernel_path);
+        write_symbol_table(&out_dir, &symbols, self_offset);
+    }
+
+    println!("cargo:rerun-if-env-changed={}", SYMBOLS_PASS_ENV);
+    // 常に実行されないと困るので
+    println!("cargo:rerun-if-changed=");
+}
+
+fn build_bootloader(profile: &str) {
     // bootloaderとkernelは別のtargetである必要があり、一方でworkspaceとper-package-targetとbuild-stdを合わせるとcargoが落ちる
     // そのため、現状はbuild.rsでbootloaderをビルドすることで回避する
 
     let current_dir = env::current_dir().expect("Failed to get current directory");
     let bootloader_path = Path::new("../bootloader");
     env::set_current_dir(&bootloader_path).expect("Failed to change directory");
-    let profile = std::env::var("PROFILE").unwrap();
 
-    let bootloader_image_path = match profile.as_str() {
+    let bootloader_image_path = match profile {
         "debug" => {
             let status = Command::new("cargo")
                 .args(&["build"])
@@ -37,7 +67,129 @@ fn main() {
 
     let out_dir = Path::new("target");
     std::fs::copy(&bootloader_image_path, out_dir.join("BOOTX64.EFI")).expect("Failed to copy bootloader image");
+}
 
-    // 常に実行されないと困るので
-    println!("cargo:rerun-if-changed=");
+// リンク前のbuild.rsからは自分自身のシンボルが見えないので、空のテーブルを入れたカーネルを一度別にビルドし、
+// そのシンボルを本番のビルドに埋め込む(Linuxのkallsymsと同じ方法)
+// テーブルは固定長なので二つのビルドでアドレスは変わらない
+fn build_kernel_for_symbols(profile: &str) -> PathBuf {
+    let mut args = vec!["build", "--bin", "kernel", "--target-dir", "target/symbols"];
+    match profile {
+        "debug" => {}
+        "release" => args.push("--release"),
+        _ => panic!("Building with an unknown profile: {}", profile),
+    }
+    let status = Command::new("cargo")
+        .args(&args)
+        .env(SYMBOLS_PASS_ENV, "1")
+        .status()
+        .expect("Failed to execute cargo build");
+    assert!(status.success(), "Kernel build for symbols failed");
+    Path::new("target/symbols/x86_64-unknown-none").join(profile).join("kernel")
+}
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+// 関数シンボルをアドレス順に並べて返す。あわせてテーブル自身のオフセットも返す
+fn read_symbols(path: &Path) -> (Vec<Symbol>, u64) {
+    let buf = std::fs::read(path).expect("Failed to read kernel image");
+    let elf = match Elf::from_bytes(&buf) {
+        Ok(Elf::Elf64(elf)) => elf,
+        _ => panic!("{}: not an ELF64 file", path.display()),
+    };
+    let symtab = elf.lookup_section(b".symtab").expect("kernel has no .symtab");
+    let strtab = elf.lookup_section(b".strtab").expect("kernel has no .strtab");
+    let strtab = &buf[strtab.offset() as usize..(strtab.offset() + strtab.size()) as usize];
+
+    let mut symbols = Vec::new();
+    let mut self_offset = None;
+    for i in 0..symtab.size() / symtab.entsize() {
+        let offset = (symtab.offset() + i * symtab.entsize()) as usize;
+        let sym = unsafe { (buf[offset..].as_ptr() as *const Elf64Sym).read_unaligned() };
+        let Ok(name) = CStr::from_bytes_until_nul(&strtab[sym.st_name as usize..]) else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        if name.contains(SYMBOL_TABLE_STATIC) {
+            self_offset = Some(sym.st_value);
+        }
+        if sym.sym_type() != STT_FUNC || sym.st_size == 0 || sym.st_shndx == SHN_UNDEF {
+            continue;
+        }
+        symbols.push(Symbol {
+            addr: sym.st_value,
+            size: sym.st_size,
+            // {:#}でハッシュを落とす
+            name: strip_generic_args(&format!("{:#}", rustc_demangle::demangle(&name))),
+        });
+    }
+    symbols.sort_by_key(|s| s.addr);
+    symbols.dedup_by_key(|s| s.addr);
+
+    let self_offset = self_offset.unwrap_or_else(|| panic!("{} not found in {}", SYMBOL_TABLE_STATIC, path.display()));
+    (symbols, self_offset)
+}
+
+// v0のマングリングでは型引数まで名前に入り、テーブルに収まらない
+// "<X as Trait>::f"の形は残し、"Vec<u8>"や"f::<T>"の型引数だけを落とす
+fn strip_generic_args(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut depth = 0;
+    let mut prev = ' ';
+    for c in name.chars() {
+        if depth > 0 {
+            match c {
+                '<' => depth += 1,
+                // fn() -> Tの矢印は括弧ではない
+                '>' if prev != '-' => depth -= 1,
+                _ => {}
+            }
+        } else if c == '<' && (prev.is_alphanumeric() || prev == '_' || prev == ':') {
+            depth = 1;
+            if out.ends_with("::") {
+                out.truncate(out.len() - 2);
+            }
+        } else {
+            out.push(c);
+        }
+        prev = c;
+    }
+    out
+}
+
+fn write_symbol_table(out_dir: &Path, symbols: &[Symbol], self_offset: u64) {
+    let header_size = size_of::<SymbolTableHeader>();
+    let entry_size = size_of::<SymbolEntry>();
+
+    // 一部を捨てると、そこを指すアドレスが手前の関数の名前になってしまうので、入りきらなければビルドを止める
+    let count = symbols.len();
+    let names_len: usize = symbols.iter().map(|s| s.name.len()).sum();
+    let size = header_size + count * entry_size + names_len;
+    assert!(size <= SYMBOL_TABLE_CAPACITY,
+        "symbol table needs {} bytes for {} symbols, but SYMBOL_TABLE_CAPACITY is {}", size, count, SYMBOL_TABLE_CAPACITY);
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_CAPACITY);
+    if !symbols.is_empty() {
+        table.extend_from_slice(&SYMBOL_TABLE_MAGIC.to_le_bytes());
+        table.extend_from_slice(&self_offset.to_le_bytes());
+        table.extend_from_slice(&(count as u32).to_le_bytes());
+        table.extend_from_slice(&(names_len as u32).to_le_bytes());
+        let mut name_offset = 0;
+        for s in symbols {
+            table.extend_from_slice(&s.addr.to_le_bytes());
+            table.extend_from_slice(&s.size.to_le_bytes());
+            table.extend_from_slice(&(name_offset as u32).to_le_bytes());
+            table.extend_from_slice(&(s.name.len() as u32).to_le_bytes());
+            name_offset += s.name.len();
+        }
+        for s in symbols {
+            table.extend_from_slice(s.name.as_bytes());
+        }
+    }
+    table.resize(SYMBOL_TABLE_CAPACITY, 0);
+    std::fs::write(out_dir.join("symbols.bin"), &table).expect("Failed to write symbol table");
 }
@@ -8,6 +8,12 @@ mmd -i disk.img ::/EFI/BOOT
 mcopy -i disk.img $1 ::/kernel
 mcopy -i disk.img target/BOOTX64.EFI ::/EFI/BOOT/BOOTX64.EFI
 
+# KERNEL_TESTが指定されていれば名前にそれを含むテストだけ実行する
+if [ -n "$KERNEL_TEST" ]; then
+    echo "test=$KERNEL_TEST" > target/boot.cfg
+    mcopy -i disk.img target/boot.cfg ::/boot.cfg
+fi
+
 cd ..
 ./run_qemu.sh kernel/disk.img
 
@@ -1,6 +1,15 @@
+// ACPIのテーブル
+// init_acpiでXSDT(ACPI 1.0ならRSDT)をたどり、チェックサムの合うテーブルを全部覚えておく
+// 各テーブルはfindやfadt, madtなどで型付きで取り出す
+
 use core::ffi::c_void;
+use core::fmt::{self, Write};
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
 
 use crate::io_port::ind;
+use crate::paging::phys_to_virt;
 
 #[repr(C, packed)]
 struct RSDP {
@@ -19,11 +28,10 @@ impl RSDP {
     fn is_valid(&self) -> bool {
         if self.signature != *b"RSD PTR " {
             false
-        } else if self.revision != 2 {
-            false
         } else if sum_bytes(self, 20) != 0 {
             false
-        } else if sum_bytes(self, 36) != 0 {
+        // ACPI 1.0のRSDPは20byteしかなく、XSDTもない
+        } else if self.revision >= 2 && sum_bytes(self, 36) != 0 {
             false
         } else {
             true
@@ -32,7 +40,7 @@ impl RSDP {
 }
 
 #[repr(C, packed)]
-struct DescriptionHeader {
+pub struct DescriptionHeader {
     signature: [u8; 4],
     length: u32,
     revision: u8,
@@ -45,6 +53,7 @@ struct DescriptionHeader {
 }
 
 impl DescriptionHeader {
+    // safety: lengthの分だけ読めること
     unsafe fn is_valid(&self, signature: [u8; 4]) -> bool {
         if self.signature != signature {
             false
@@ -54,52 +63,247 @@ impl DescriptionHeader {
             true
         }
     }
+    pub fn signature(&self) -> &str {
+        ascii(&self.signature)
+    }
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+    pub fn oem_id(&self) -> &str {
+        ascii(&self.oem_id)
+    }
+    pub fn oem_table_id(&self) -> &str {
+        ascii(&self.oem_table_id)
+    }
+    pub fn oem_revision(&self) -> u32 {
+        self.oem_revision
+    }
+    // ヘッダの後ろ。DSDTやSSDTならAMLのバイト列
+    pub fn data(&self) -> &[u8] {
+        let len = (self.length as usize).saturating_sub(size_of::<DescriptionHeader>());
+        unsafe { core::slice::from_raw_parts((&raw const *self as *const u8).add(size_of::<DescriptionHeader>()), len) }
+    }
 }
 
-#[repr(C, packed)]
-struct XSDT {
-    header: DescriptionHeader,
+// IDは空白で埋められている
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim_end_matches([' ', '\0'])
 }
 
-impl XSDT {
-    unsafe fn from_raw(ptr: *mut u8) -> Option<&'static XSDT> {
-        let header = unsafe { &*(ptr as *const DescriptionHeader) };
-        if unsafe { header.is_valid(*b"XSDT") } {
-            Some(unsafe { &*(ptr as *const XSDT) })
+// XSDTは64bit, RSDTは32bitの物理アドレスがヘッダの後に並ぶ
+fn root_entries(root: &DescriptionHeader, entry_size: usize) -> impl Iterator<Item = u64> + '_ {
+    let count = (root.length as usize).saturating_sub(size_of::<DescriptionHeader>()) / entry_size;
+    let base = unsafe { (&raw const *root as *const u8).add(size_of::<DescriptionHeader>()) };
+    (0..count).map(move |i| unsafe {
+        let entry = base.add(i * entry_size);
+        if entry_size == size_of::<u64>() {
+            (entry as *const u64).read_unaligned()
         } else {
-            None
+            (entry as *const u32).read_unaligned() as u64
         }
+    })
+}
+
+// DescriptionHeaderから始まる、型の決まったテーブル
+pub trait Table: Sized {
+    const SIGNATURE: [u8; 4];
+    // これより短いものは壊れているとみなす
+    const MIN_LENGTH: usize = size_of::<Self>();
+}
+
+fn cast<T: Table>(header: &'static DescriptionHeader) -> Option<&'static T> {
+    if header.signature != T::SIGNATURE || (header.length as usize) < T::MIN_LENGTH {
+        return None;
     }
-    fn entry(&self, idx: usize) -> &'static DescriptionHeader {
-        if idx >= self.header.length as usize {
-            panic!("idx is large");
+    Some(unsafe { &*(header as *const DescriptionHeader as *const T) })
+}
+
+pub struct AcpiTables {
+    revision: u8,
+    oem_id: [u8; 6],
+    root: &'static DescriptionHeader,
+    // (物理アドレス, テーブル)
+    tables: Vec<(u64, &'static DescriptionHeader)>,
+}
+
+impl AcpiTables {
+    pub fn iter(&self) -> impl Iterator<Item = &'static DescriptionHeader> + '_ {
+        self.tables.iter().map(|&(_, t)| t)
+    }
+    // SSDTのように同じシグネチャのものが複数あることがある
+    pub fn find_by_signature<'a>(&'a self, signature: &'a str) -> impl Iterator<Item = &'static DescriptionHeader> + 'a {
+        self.iter().filter(move |t| t.signature == signature.as_bytes())
+    }
+    pub fn find<T: Table>(&self) -> Option<&'static T> {
+        self.iter().find_map(cast)
+    }
+    pub fn dump(&self, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "RSDP rev {} OEM {} -> {} ({} tables)", self.revision, ascii(&self.oem_id), self.root.signature(), self.tables.len())?;
+        for &(phys, t) in self.tables.iter() {
+            writeln!(out, "  {} {:#010x} len {:>6} rev {:>2} OEM {:<6} {:<8} {:#x}",
+                t.signature(), phys, t.length(), t.revision(), t.oem_id(), t.oem_table_id(), t.oem_revision())?;
         }
-        unsafe {
-            (self as *const XSDT as *const &'static DescriptionHeader)
-                .byte_add(size_of::<DescriptionHeader>())
-                .add(idx)
-                .read_unaligned()
+        Ok(())
+    }
+}
+
+static TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+unsafe fn header_at(phys: u64) -> &'static DescriptionHeader {
+    unsafe { &*(phys_to_virt(phys) as *const DescriptionHeader) }
+}
+
+// safety: ptr is valid rsdp. メモリアロケータの初期化後に呼ぶ
+pub unsafe fn init_acpi(ptr: *const c_void) -> bool {
+    let rsdp = unsafe { &*(ptr as *const RSDP) };
+    if !rsdp.is_valid() {
+        log::warn!("RSDP is invalid");
+        return false
+    }
+
+    let (root_address, root_signature, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, *b"XSDT", size_of::<u64>())
+    } else {
+        (rsdp.rsdt_address as u64, *b"RSDT", size_of::<u32>())
+    };
+    let root = unsafe { header_at(root_address) };
+    if !unsafe { root.is_valid(root_signature) } {
+        log::warn!("{} at {:#x} is invalid", ascii(&root_signature), root_address);
+        return false
+    }
+
+    let mut tables = Vec::new();
+    let add = |tables: &mut Vec<_>, phys: u64| {
+        let header = unsafe { header_at(phys) };
+        if unsafe { header.is_valid(header.signature) } {
+            tables.push((phys, header));
+        } else {
+            log::warn!("ACPI table {} at {:#x} has a bad checksum", header.signature(), phys);
         }
+    };
+    for phys in root_entries(root, entry_size) {
+        add(&mut tables, phys);
     }
+    // DSDTはXSDTに載っておらず、FADTから指される
+    let dsdt = tables.iter().find_map(|&(_, t)| cast::<FADT>(t)).map(|fadt| fadt.dsdt_address());
+    if let Some(phys) = dsdt.filter(|&phys| phys != 0) {
+        add(&mut tables, phys);
+    }
+
+    let tables = AcpiTables { revision: rsdp.revision, oem_id: rsdp.oem_id, root, tables };
+    log::info!("ACPI rev {}: {} tables from {}", tables.revision, tables.tables.len(), root.signature());
+    if TABLES.try_init_once(|| tables).is_err() {
+        log::warn!("ACPI tables are already initialized");
+    }
+    true
+}
+
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+pub fn find<T: Table>() -> Option<&'static T> {
+    tables()?.find()
+}
+
+pub fn fadt() -> Option<&'static FADT> {
+    find()
+}
+
+pub fn madt() -> Option<&'static MADT> {
+    find()
+}
+
+pub fn hpet() -> Option<&'static HPET> {
+    find()
+}
+
+pub fn mcfg() -> Option<&'static MCFG> {
+    find()
+}
+
+pub fn bgrt() -> Option<&'static BGRT> {
+    find()
+}
+
+pub fn dsdt() -> Option<&'static DescriptionHeader> {
+    tables()?.find_by_signature("DSDT").next()
+}
+
+pub fn dump(out: &mut dyn Write) -> fmt::Result {
+    match tables() {
+        Some(tables) => tables.dump(out),
+        None => writeln!(out, "ACPI tables are not found"),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub space_id: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
 }
 
 #[repr(C, packed)]
 pub struct FADT {
     header: DescriptionHeader,
-    _rsvd1: [u8; 76 - size_of::<DescriptionHeader>()],
+    _rsvd1: [u8; 40 - size_of::<DescriptionHeader>()],
+    dsdt: u32,
+    _rsvd2: [u8; 48 - 44],
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    _rsvd3: [u8; 64 - 54],
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    _rsvd4: [u8; 76 - 72],
     pub pm_tmr_blk: u32,
-    _rsvd2: [u8; 112 - 80],
+    _rsvd5: [u8; 108 - 80],
+    century: u8,
+    _rsvd6: [u8; 112 - 109],
     pub flags: u32,
-    _rsvd3: [u8; 276 - 116]
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    _rsvd7: [u8; 140 - 129],
+    x_dsdt: u64,
+    _rsvd8: [u8; 276 - 148]
+}
+
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+impl Table for FADT {
+    const SIGNATURE: [u8; 4] = *b"FACP";
+    // ACPI 1.0のFADTはflagsまで
+    const MIN_LENGTH: usize = 116;
 }
 
 impl FADT {
-    unsafe fn from_raw(header: &'static DescriptionHeader) -> Option<&'static FADT> {
-        if unsafe { header.is_valid(*b"FACP") } {
-            Some(unsafe { &*(header as *const DescriptionHeader as *const FADT) })
+    fn has(&self, end: usize) -> bool {
+        self.header.length as usize >= end
+    }
+    pub fn dsdt_address(&self) -> u64 {
+        if self.has(148) && self.x_dsdt != 0 {
+            self.x_dsdt
         } else {
-            None
+            self.dsdt as u64
+        }
+    }
+    // リセットレジスタと、そこに書く値
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if !self.has(129) || self.flags & FADT_RESET_REG_SUP == 0 {
+            return None;
         }
+        Some((self.reset_reg, self.reset_value))
+    }
+    // RTCの世紀が入っているCMOSのレジスタ。0ならない
+    pub fn century_register(&self) -> Option<u8> {
+        Some(self.century).filter(|&r| r != 0)
     }
     pub fn wait_milliseconds(&self, ms: u32) {
         let pm_timer_32 = self.flags & (1 << 8) != 0;
@@ -117,6 +321,209 @@ impl FADT {
     }
 }
 
+// High Precision Event Timer
+#[repr(C, packed)]
+pub struct HPET {
+    header: DescriptionHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+impl Table for HPET {
+    const SIGNATURE: [u8; 4] = *b"HPET";
+}
+
+impl HPET {
+    pub fn base_address(&self) -> u64 {
+        self.base_address.address
+    }
+    pub fn number(&self) -> u8 {
+        self.hpet_number
+    }
+    pub fn comparator_count(&self) -> u8 {
+        (self.event_timer_block_id >> 8 & 0x1f) as u8 + 1
+    }
+    // 周期モードで壊れずに使える最小のカウント
+    pub fn minimum_tick(&self) -> u16 {
+        self.minimum_tick
+    }
+}
+
+// PCI Expressの拡張コンフィギュレーション空間(ECAM)の場所
+#[repr(C, packed)]
+pub struct MCFG {
+    header: DescriptionHeader,
+    _reserved: [u8; 8],
+}
+
+impl Table for MCFG {
+    const SIGNATURE: [u8; 4] = *b"MCFG";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+const MCFG_ENTRY_LEN: usize = 16;
+
+fn mcfg_entries(data: &[u8]) -> impl Iterator<Item = McfgEntry> + '_ {
+    data.chunks_exact(MCFG_ENTRY_LEN).map(|e| McfgEntry {
+        base_address: u64::from_le_bytes(e[0..8].try_into().unwrap()),
+        segment: u16::from_le_bytes(e[8..10].try_into().unwrap()),
+        start_bus: e[10],
+        end_bus: e[11],
+    })
+}
+
+impl MCFG {
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+        let len = (self.header.length as usize).saturating_sub(size_of::<MCFG>());
+        let data: &'static [u8] = unsafe { core::slice::from_raw_parts((&raw const *self as *const u8).add(size_of::<MCFG>()), len) };
+        mcfg_entries(data)
+    }
+}
+
+// Boot Graphics Resource Table
+// ファームウェアが起動時に表示したロゴの場所
+#[repr(C, packed)]
+pub struct BGRT {
+    header: DescriptionHeader,
+    version: u16,
+    status: u8,
+    image_type: u8,
+    image_address: u64,
+    image_offset_x: u32,
+    image_offset_y: u32,
+}
+
+impl Table for BGRT {
+    const SIGNATURE: [u8; 4] = *b"BGRT";
+}
+
+impl BGRT {
+    pub fn is_displayed(&self) -> bool {
+        self.status & 1 != 0
+    }
+    // 0ならBMP
+    pub fn image_type(&self) -> u8 {
+        self.image_type
+    }
+    pub fn image_address(&self) -> u64 {
+        self.image_address
+    }
+    pub fn image_offset(&self) -> (u32, u32) {
+        (self.image_offset_x, self.image_offset_y)
+    }
+}
+
+// Multiple APIC Description Table
+// ヘッダの後に(種類, 長さ)から始まる可変長のエントリが並ぶ
+#[repr(C, packed)]
+pub struct MADT {
+    header: DescriptionHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+// 8259も載っている
+const MADT_PCAT_COMPAT: u32 = 1;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic { processor_uid: u32, apic_id: u32, enabled: bool },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    // ISAのIRQ sourceがGSI gsiにつながっている。flagsは極性とトリガモード
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    LocalApicAddressOverride { address: u64 },
+    Other { ty: u8 },
+}
+
+pub struct MadtEntries<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MadtEntries<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        MadtEntries { data }
+    }
+}
+
+impl Iterator for MadtEntries<'_> {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<MadtEntry> {
+        let (&ty, &len) = (self.data.first()?, self.data.get(1)?);
+        let Some(body) = self.data.get(2..len as usize) else {
+            // 壊れていればそこでやめる
+            self.data = &[];
+            return None;
+        };
+        self.data = &self.data[len as usize..];
+        let u16_at = |i: usize| Some(u16::from_le_bytes(body.get(i..i + 2)?.try_into().ok()?));
+        let u32_at = |i: usize| Some(u32::from_le_bytes(body.get(i..i + 4)?.try_into().ok()?));
+        let u64_at = |i: usize| Some(u64::from_le_bytes(body.get(i..i + 8)?.try_into().ok()?));
+        let entry = match ty {
+            MADT_LOCAL_APIC => (|| Some(MadtEntry::LocalApic {
+                processor_uid: *body.first()? as u32,
+                apic_id: *body.get(1)? as u32,
+                enabled: u32_at(2)? & 1 != 0,
+            }))(),
+            MADT_IO_APIC => (|| Some(MadtEntry::IoApic { id: *body.first()?, address: u32_at(2)?, gsi_base: u32_at(6)? }))(),
+            MADT_INTERRUPT_SOURCE_OVERRIDE => (|| Some(MadtEntry::InterruptSourceOverride {
+                bus: *body.first()?,
+                source: *body.get(1)?,
+                gsi: u32_at(2)?,
+                flags: u16_at(6)?,
+            }))(),
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => u64_at(2).map(|address| MadtEntry::LocalApicAddressOverride { address }),
+            MADT_LOCAL_X2APIC => (|| Some(MadtEntry::LocalApic {
+                processor_uid: u32_at(10)?,
+                apic_id: u32_at(2)?,
+                enabled: u32_at(6)? & 1 != 0,
+            }))(),
+            _ => None,
+        };
+        Some(entry.unwrap_or(MadtEntry::Other { ty }))
+    }
+}
+
+impl Table for MADT {
+    const SIGNATURE: [u8; 4] = *b"APIC";
+}
+
+impl MADT {
+    pub fn entries(&self) -> MadtEntries<'static> {
+        let len = (self.header.length as usize).saturating_sub(size_of::<MADT>());
+        // ヘッダを確かめたときに長さ分のチェックサムを見ている
+        let data = unsafe { core::slice::from_raw_parts((&raw const *self as *const u8).add(size_of::<MADT>()), len) };
+        MadtEntries::new(data)
+    }
+    // 64bitのアドレスで上書きされていればそちら
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|e| match e {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+    pub fn has_8259(&self) -> bool {
+        self.flags & MADT_PCAT_COMPAT != 0
+    }
+}
+
 unsafe fn sum_bytes_unchecked(ptr: *const u8, size: usize) -> u8 {
     let mut sum = 0u8;
     for i in 0..size {
@@ -133,22 +540,57 @@ fn sum_bytes<T>(ptr: &T, size: usize) -> u8 {
     unsafe { sum_bytes_unchecked(ptr, size) }
 }
 
-// safety: ptr is valid rsdp
-pub unsafe fn get_fadt(ptr: *const c_void) -> Option<&'static FADT> {
-    let rsdp = unsafe { &*(ptr as *const RSDP) };
-    if !rsdp.is_valid() {
-        return None
+mod test {
+    #[test_case]
+    fn madt_entries_are_decoded() {
+        use super::{MadtEntries, MadtEntry};
+        let data: &[u8] = &[
+            // Local APIC: uid 0, id 1, enabled
+            0, 8, 0, 1, 1, 0, 0, 0,
+            // I/O APIC: id 2, 0xfec00000, gsi 0
+            1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0,
+            // ISA IRQ0 -> GSI 2, flags 0
+            2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+            // Local APIC NMI(未対応)
+            4, 6, 0xff, 0, 0, 1,
+            // 長さが壊れている
+            1, 40, 0,
+        ];
+        let mut entries = MadtEntries::new(data);
+        assert_eq!(entries.next(), Some(MadtEntry::LocalApic { processor_uid: 0, apic_id: 1, enabled: true }));
+        assert_eq!(entries.next(), Some(MadtEntry::IoApic { id: 2, address: 0xfec0_0000, gsi_base: 0 }));
+        assert_eq!(entries.next(), Some(MadtEntry::InterruptSourceOverride { bus: 0, source: 0, gsi: 2, flags: 0 }));
+        assert_eq!(entries.next(), Some(MadtEntry::Other { ty: 4 }));
+        assert_eq!(entries.next(), None);
     }
 
-    let Some(xsdt) = (unsafe { XSDT::from_raw(rsdp.xsdt_address as *mut u8) }) else {
-        return None
-    };
+    #[test_case]
+    fn rsdt_entries_are_32bit() {
+        use super::{DescriptionHeader, root_entries};
+        let mut data = [0u8; size_of::<DescriptionHeader>() + 8];
+        data[0..4].copy_from_slice(b"RSDT");
+        let len = data.len() as u32;
+        data[4..8].copy_from_slice(&len.to_le_bytes());
+        data[36..40].copy_from_slice(&0x7fe1_000u32.to_le_bytes());
+        data[40..44].copy_from_slice(&0x7fe2_000u32.to_le_bytes());
+        let header = unsafe { &*(data.as_ptr() as *const DescriptionHeader) };
+        assert_eq!(header.signature(), "RSDT");
+        let mut entries = root_entries(header, 4);
+        assert_eq!(entries.next(), Some(0x7fe1_000));
+        assert_eq!(entries.next(), Some(0x7fe2_000));
+        assert_eq!(entries.next(), None);
+    }
 
-    for i in 0..xsdt.header.length {
-        let entry = xsdt.entry(i as usize);
-        if let Some(fadt) = unsafe { FADT::from_raw(entry) } {
-            return Some(fadt)
-        }
+    #[test_case]
+    fn mcfg_entries_are_decoded() {
+        use super::{McfgEntry, mcfg_entries};
+        let data: &[u8] = &[
+            0x00, 0x00, 0x00, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0, 0,
+            // 16byteに満たない分は無視する
+            0, 0, 0,
+        ];
+        let mut entries = mcfg_entries(data);
+        assert_eq!(entries.next(), Some(McfgEntry { base_address: 0xb000_0000, segment: 0, start_bus: 0, end_bus: 0xff }));
+        assert_eq!(entries.next(), None);
     }
-    None
 }
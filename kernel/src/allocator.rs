@@ -201,6 +201,7 @@ unsafe impl GlobalAlloc for SimplestAllocator {
 
 pub struct MemoryCorruptionCheckAllocator {
     inner: Mutex<(*mut u8, *mut u8)>,
+    start: AtomicPtr<u8>,
 }
 
 unsafe impl Send for MemoryCorruptionCheckAllocator {}
@@ -211,7 +212,8 @@ impl MemoryCorruptionCheckAllocator {
     const BOTTOM_MARK: u8 = 0xcd;
     pub const fn empty() -> Self {
         MemoryCorruptionCheckAllocator {
-            inner: Mutex::new((null_mut(), null_mut()))
+            inner: Mutex::new((null_mut(), null_mut())),
+            start: AtomicPtr::new(null_mut()),
         }
     }
     pub unsafe fn init(&self, head: *mut u8, end: *mut u8) {
@@ -221,6 +223,16 @@ impl MemoryCorruptionCheckAllocator {
         }
         lck.0 = unsafe { head.byte_add(1) };
         lck.1 = end;
+        self.start.store(head, Ordering::Relaxed);
+    }
+    // パニック中にも呼ぶので、ロックが取れなければNone
+    pub fn usage(&self) -> Option<HeapUsage> {
+        let lck = self.inner.try_lock()?;
+        let start = self.start.load(Ordering::Relaxed);
+        Some(HeapUsage {
+            total: lck.1.addr() - start.addr(),
+            used: lck.0.addr() - start.addr(),
+        })
     }
 }
 
@@ -280,13 +292,23 @@ unsafe impl GlobalAlloc for MemoryCorruptionCheckAllocator {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct HeapUsage {
+    pub total: usize,
+    pub used: usize,
+}
+
+pub fn heap_usage() -> Option<HeapUsage> {
+    ALLOCATOR.usage()
+}
+
 #[global_allocator]
 static ALLOCATOR: MemoryCorruptionCheckAllocator = MemoryCorruptionCheckAllocator::empty();
 
 pub unsafe fn init_allocator() {
     let heap_frame = 64 * 512;
     let heap_start = crate::memory_manager::page_allocate(heap_frame).expect("cannot initialize heap allocate");
-    let start = heap_start.frame();
-    let end = start + heap_frame * BYTES_PER_FRAME;
-    unsafe { ALLOCATOR.init(start as *mut u8, end as *mut u8) };
+    let start = heap_start.as_ptr();
+    let end = unsafe { start.add(heap_frame * BYTES_PER_FRAME) };
+    unsafe { ALLOCATOR.init(start, end) };
 }
@@ -0,0 +1,117 @@
+// 名前空間のデバイスについて、よく使う値を取り出す
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::interpreter::{Aml, Error, Handler, Result};
+use super::name::Path;
+use super::object::Object;
+use super::resource::{Resource, parse_resources};
+
+// _STAがなければ、存在して有効で動作中とみなす
+pub const STATUS_PRESENT: u64 = 1 << 0;
+pub const STATUS_ENABLED: u64 = 1 << 1;
+pub const STATUS_DEFAULT: u64 = 0x0f;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IrqSource {
+    // I/O APICの入力番号
+    Gsi(u32),
+    // リンクデバイスの_CRSで決まる
+    Link { device: Path, index: u32 },
+}
+
+// _PRTの1エントリ。INTA#からINTD#がpin 0から3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciRoute {
+    pub device: u8,
+    pub pin: u8,
+    pub source: IrqSource,
+}
+
+// EISA IDを"PNP0A03"のような文字列にする
+// 圧縮された3文字の英字と、4桁の16進数がビッグエンディアンで並ぶ
+pub fn decode_eisa_id(id: u32) -> String {
+    let v = id.swap_bytes();
+    let c = |shift: u32| (((v >> shift) & 0x1f) as u8 + 0x40) as char;
+    format!("{}{}{}{:04X}", c(26), c(21), c(16), v & 0xffff)
+}
+
+impl<H: Handler> Aml<H> {
+    fn evaluate_optional(&mut self, path: Path) -> Result<Option<Object>> {
+        if self.get(&path).is_none() {
+            return Ok(None);
+        }
+        self.evaluate(&path, Vec::new()).map(Some)
+    }
+
+    pub fn status(&mut self, device: &Path) -> Result<u64> {
+        match self.evaluate_optional(device.join(*b"_STA"))? {
+            Some(Object::Integer(v)) => Ok(v),
+            Some(_) => Err(Error::TypeMismatch),
+            None => Ok(STATUS_DEFAULT),
+        }
+    }
+
+    pub fn hardware_id(&mut self, device: &Path) -> Result<Option<String>> {
+        match self.evaluate_optional(device.join(*b"_HID"))? {
+            Some(Object::Integer(id)) => Ok(Some(decode_eisa_id(id as u32))),
+            Some(Object::String(s)) => Ok(Some(s)),
+            Some(_) => Err(Error::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+
+    pub fn resources(&mut self, device: &Path) -> Result<Vec<Resource>> {
+        match self.evaluate(&device.join(*b"_CRS"), Vec::new())? {
+            Object::Buffer(b) => parse_resources(&b),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    // \_Sxの最初の2つの要素が、PM1a, PM1bのSLP_TYPに書く値
+    pub fn sleep_type(&mut self, state: u8) -> Result<(u8, u8)> {
+        let path = Path::root().join([b'_', b'S', b'0' + state, b'_']);
+        let value = self.evaluate(&path, Vec::new())?;
+        let package = value.as_package().ok_or(Error::TypeMismatch)?;
+        let a = package.first().and_then(Object::as_integer).ok_or(Error::TypeMismatch)?;
+        let b = package.get(1).and_then(Object::as_integer).unwrap_or(0);
+        Ok((a as u8, b as u8))
+    }
+
+    // PCIルートブリッジの_PRTを読む
+    pub fn pci_routing(&mut self, bridge: &Path) -> Result<Vec<PciRoute>> {
+        let table = self.evaluate(&bridge.join(*b"_PRT"), Vec::new())?;
+        let entries = table.as_package().ok_or(Error::TypeMismatch)?;
+        let mut routes = Vec::new();
+        for entry in entries {
+            let fields = entry.as_package().ok_or(Error::TypeMismatch)?;
+            let [address, pin, source, index] = fields else {
+                return Err(Error::TypeMismatch);
+            };
+            let integer = |o: &Object| o.as_integer().ok_or(Error::TypeMismatch);
+            let index = integer(index)? as u32;
+            let source = match source {
+                Object::Integer(0) => IrqSource::Gsi(index),
+                Object::Reference(path) => {
+                    let device = self.resolve_reference(path).ok_or_else(|| Error::UndefinedName(path.clone()))?;
+                    IrqSource::Link { device, index }
+                }
+                _ => return Err(Error::TypeMismatch),
+            };
+            routes.push(PciRoute { device: (integer(address)? >> 16) as u8, pin: integer(pin)? as u8, source });
+        }
+        Ok(routes)
+    }
+
+    // リンクデバイスが今使っている割り込み番号。indexは_CRSの何番目の記述子か
+    pub fn link_irq(&mut self, link: &Path, index: u32) -> Result<u32> {
+        let resources = self.resources(link)?;
+        match resources.get(index as usize) {
+            Some(Resource::Interrupt { irqs, .. }) => irqs.first().copied().ok_or(Error::TypeMismatch),
+            Some(_) => Err(Error::TypeMismatch),
+            None => Err(Error::IndexOutOfRange),
+        }
+    }
+}
@@ -0,0 +1,1444 @@
+use core::fmt;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::name::{self, NameSeg, NameString, Path};
+use super::object::*;
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6e;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const CONCAT_OP: u8 = 0x73;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7a;
+const AND_OP: u8 = 0x7b;
+const NAND_OP: u8 = 0x7c;
+const OR_OP: u8 = 0x7d;
+const NOR_OP: u8 = 0x7e;
+const XOR_OP: u8 = 0x7f;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8a;
+const CREATE_WORD_FIELD_OP: u8 = 0x8b;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8c;
+const CREATE_BIT_FIELD_OP: u8 = 0x8d;
+const OBJECT_TYPE_OP: u8 = 0x8e;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8f;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_BUFFER_OP: u8 = 0x96;
+const TO_DECIMAL_STRING_OP: u8 = 0x97;
+const TO_HEX_STRING_OP: u8 = 0x98;
+const TO_INTEGER_OP: u8 = 0x99;
+const TO_STRING_OP: u8 = 0x9c;
+const COPY_OBJECT_OP: u8 = 0x9d;
+const MID_OP: u8 = 0x9e;
+const CONTINUE_OP: u8 = 0x9f;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const WHILE_OP: u8 = 0xa2;
+const NOOP_OP: u8 = 0xa3;
+const RETURN_OP: u8 = 0xa4;
+const BREAK_OP: u8 = 0xa5;
+const BREAKPOINT_OP: u8 = 0xcc;
+const ONES_OP: u8 = 0xff;
+
+// EXT_OP_PREFIXに続くもの
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const COND_REF_OF_OP: u8 = 0x12;
+const CREATE_FIELD_OP: u8 = 0x13;
+const STALL_OP: u8 = 0x21;
+const SLEEP_OP: u8 = 0x22;
+const ACQUIRE_OP: u8 = 0x23;
+const SIGNAL_OP: u8 = 0x24;
+const WAIT_OP: u8 = 0x25;
+const RESET_OP: u8 = 0x26;
+const RELEASE_OP: u8 = 0x27;
+const FROM_BCD_OP: u8 = 0x28;
+const TO_BCD_OP: u8 = 0x29;
+const REVISION_OP: u8 = 0x30;
+const DEBUG_OP: u8 = 0x31;
+const FATAL_OP: u8 = 0x32;
+const TIMER_OP: u8 = 0x33;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+// FieldList
+const RESERVED_FIELD: u8 = 0x00;
+const ACCESS_FIELD: u8 = 0x01;
+const EXTENDED_ACCESS_FIELD: u8 = 0x03;
+
+// メソッド呼び出しの深さとWhileの回数の上限。ファームウェアのバグで止まらないようにする
+const MAX_DEPTH: usize = 32;
+const MAX_LOOP: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    UnexpectedEnd,
+    UnknownOpcode(u16),
+    InvalidName,
+    UndefinedName(Path),
+    TypeMismatch,
+    IndexOutOfRange,
+    DivideByZero,
+    NotSupported(&'static str),
+    TooDeep,
+    LoopTimeout,
+    Fatal,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownOpcode(op) => write!(f, "unknown opcode {:#x}", op),
+            Error::UndefinedName(path) => write!(f, "undefined name {}", path),
+            Error::NotSupported(what) => write!(f, "{} is not supported", what),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+// OperationRegionの読み書き。widthはバイト数
+pub trait Handler {
+    fn read_memory(&mut self, address: u64, width: u64) -> u64;
+    fn write_memory(&mut self, address: u64, width: u64, value: u64);
+    fn read_io(&mut self, port: u16, width: u64) -> u64;
+    fn write_io(&mut self, port: u16, width: u64, value: u64);
+    fn read_pci(&mut self, address: PciAddress, offset: u16, width: u64) -> u64;
+    fn write_pci(&mut self, address: PciAddress, offset: u16, width: u64, value: u64);
+    fn stall(&mut self, microseconds: u64);
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+    fn peek(&self) -> Result<u8> {
+        self.data.get(self.pos).copied().ok_or(Error::UnexpectedEnd)
+    }
+    fn peek_at(&self, n: usize) -> Option<u8> {
+        self.data.get(self.pos + n).copied()
+    }
+    fn byte(&mut self) -> Result<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Ok(b)
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(Error::UnexpectedEnd)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+    fn integer(&mut self, n: usize) -> Result<u64> {
+        let mut v = [0u8; 8];
+        v[..n].copy_from_slice(self.bytes(n)?);
+        Ok(u64::from_le_bytes(v))
+    }
+    // 先頭の上位2bitが後に続くバイト数。自分自身の長さも含む
+    fn pkg_length_value(&mut self) -> Result<usize> {
+        let lead = self.byte()?;
+        let follow = (lead >> 6) as usize;
+        if follow == 0 {
+            return Ok((lead & 0x3f) as usize);
+        }
+        let mut len = (lead & 0x0f) as usize;
+        for i in 0..follow {
+            len |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        Ok(len)
+    }
+    // PkgLengthの終わる位置
+    fn pkg_length(&mut self) -> Result<usize> {
+        let start = self.pos;
+        let end = start + self.pkg_length_value()?;
+        if end > self.data.len() || end < self.pos {
+            return Err(Error::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+    fn name_seg(&mut self) -> Result<NameSeg> {
+        let seg = self.bytes(4)?;
+        name::name_seg(seg).filter(|s| s == seg).ok_or(Error::InvalidName)
+    }
+    fn name_string(&mut self) -> Result<NameString> {
+        let mut name = NameString { absolute: false, parents: 0, segments: Vec::new() };
+        if self.peek()? == name::ROOT_CHAR {
+            self.pos += 1;
+            name.absolute = true;
+        } else {
+            while self.peek()? == name::PARENT_PREFIX {
+                self.pos += 1;
+                name.parents += 1;
+            }
+        }
+        let count = match self.peek()? {
+            name::DUAL_NAME_PREFIX => { self.pos += 1; 2 }
+            name::MULTI_NAME_PREFIX => { self.pos += 1; self.byte()? as usize }
+            name::NULL_NAME => { self.pos += 1; 0 }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+}
+
+enum Flow {
+    Normal,
+    Return(Object),
+    Break,
+    Continue,
+}
+
+struct Frame {
+    scope: Path,
+    args: Vec<Object>,
+    locals: Vec<Object>,
+    // メソッドの中で作った名前。抜けるときに消す。テーブルを読み込んでいるときはNone
+    created: Option<Vec<Path>>,
+}
+
+impl Frame {
+    fn new(scope: Path, args: Vec<Object>, in_method: bool) -> Self {
+        let mut args = args;
+        args.resize(7, Object::Uninitialized);
+        Frame { scope, args, locals: vec![Object::Uninitialized; 8], created: in_method.then(Vec::new) }
+    }
+    fn in_method(&self) -> bool {
+        self.created.is_some()
+    }
+}
+
+// 値を書き込む先
+enum Target {
+    None,
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Name(Path),
+    Index(alloc::boxed::Box<Target>, usize),
+}
+
+pub struct Aml<H> {
+    objects: BTreeMap<Path, Object>,
+    handler: H,
+    // DSDTのリビジョンが2未満なら整数は32bit
+    wide: bool,
+    depth: usize,
+}
+
+impl<H: Handler> Aml<H> {
+    pub fn new(handler: H) -> Self {
+        let mut objects = BTreeMap::new();
+        objects.insert(Path::root(), Object::Scope);
+        for seg in [*b"_GPE", *b"_PR_", *b"_SB_", *b"_SI_", *b"_TZ_"] {
+            objects.insert(Path::root().join(seg), Object::Scope);
+        }
+        objects.insert(Path::root().join(*b"_OS_"), Object::String(String::from("Microsoft Windows NT")));
+        objects.insert(Path::root().join(*b"_REV"), Object::Integer(2));
+        Aml { objects, handler, wide: true, depth: 0 }
+    }
+
+    // DSDTかSSDTのヘッダより後ろを読み込み、名前空間に加える
+    pub fn load_table(&mut self, aml: &[u8], revision: u8) -> Result<()> {
+        if revision < 2 {
+            self.wide = false;
+        }
+        let mut frame = Frame::new(Path::root(), Vec::new(), false);
+        let mut c = Cursor::new(aml);
+        self.term_list(&mut c, aml.len(), &mut frame).map(|_| ())
+    }
+
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Object> {
+        self.objects.get(path)
+    }
+
+    pub fn children<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = (&'a Path, &'a Object)> + 'a {
+        self.objects.range(path.clone()..).skip(1).take_while(|(p, _)| p.segments().starts_with(path.segments())).filter(|(p, _)| path.is_parent_of(p))
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Path> {
+        self.objects.iter().filter(|(_, o)| matches!(o, Object::Device)).map(|(p, _)| p)
+    }
+
+    // メソッドなら呼び出し、フィールドなら読んだ値を返す
+    pub fn evaluate(&mut self, path: &Path, args: Vec<Object>) -> Result<Object> {
+        let object = self.objects.get(path).cloned().ok_or_else(|| Error::UndefinedName(path.clone()))?;
+        match object {
+            Object::Method(method) => self.invoke(path, &method, args),
+            Object::Alias(target) => self.evaluate(&target, args),
+            _ => {
+                let mut frame = Frame::new(path.parent().unwrap_or_default(), Vec::new(), false);
+                self.read_name(path, &mut frame)
+            }
+        }
+    }
+
+    pub fn evaluate_integer(&mut self, path: &Path) -> Result<u64> {
+        let value = self.evaluate(path, Vec::new())?;
+        self.to_integer(&value)
+    }
+
+    // パッケージの要素の名前は、読み込んだ時点でまだ定義されていないことがあるので、使うときに探す
+    pub fn resolve_reference(&self, path: &Path) -> Option<Path> {
+        if self.objects.contains_key(path) {
+            return Some(path.clone());
+        }
+        let seg = *path.last()?;
+        let mut scope = path.parent()?.parent();
+        while let Some(s) = scope {
+            let candidate = s.join(seg);
+            if self.objects.contains_key(&candidate) {
+                return Some(candidate);
+            }
+            scope = s.parent();
+        }
+        None
+    }
+
+    fn invoke(&mut self, path: &Path, method: &Method, args: Vec<Object>) -> Result<Object> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        let mut frame = Frame::new(path.clone(), args, true);
+        let code = method.code.clone();
+        let mut c = Cursor::new(&code);
+        self.depth += 1;
+        let result = self.term_list(&mut c, code.len(), &mut frame);
+        self.depth -= 1;
+        for created in frame.created.take().unwrap_or_default() {
+            self.objects.remove(&created);
+        }
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Object::Uninitialized),
+        }
+    }
+
+    fn mask(&self, v: u64) -> u64 {
+        if self.wide { v } else { v & 0xffff_ffff }
+    }
+
+    fn lookup(&self, name: &NameString, scope: &Path) -> Option<Path> {
+        if name.uses_search_rules() {
+            let mut s = Some(scope.clone());
+            while let Some(sc) = s {
+                let path = sc.join(name.segments[0]);
+                if self.objects.contains_key(&path) {
+                    return Some(path);
+                }
+                s = sc.parent();
+            }
+            None
+        } else {
+            name.resolve(scope).filter(|p| self.objects.contains_key(p))
+        }
+    }
+
+    fn lookup_or_error(&self, name: &NameString, scope: &Path) -> Result<Path> {
+        self.lookup(name, scope).ok_or_else(|| Error::UndefinedName(name.resolve(scope).unwrap_or_default()))
+    }
+
+    fn add(&mut self, name: &NameString, object: Object, frame: &mut Frame) -> Result<Path> {
+        let path = name.resolve(&frame.scope).ok_or(Error::InvalidName)?;
+        if matches!(object, Object::Scope) && self.objects.contains_key(&path) {
+            return Ok(path);
+        }
+        self.objects.insert(path.clone(), object);
+        if let Some(created) = frame.created.as_mut() {
+            created.push(path.clone());
+        }
+        Ok(path)
+    }
+
+    fn term_list(&mut self, c: &mut Cursor, end: usize, frame: &mut Frame) -> Result<Flow> {
+        while c.pos < end {
+            match self.term(c, end, frame)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    // ScopeやDeviceの中身。テーブルの読み込み中に分からないものがあれば、その中の残りだけ飛ばす
+    fn nested(&mut self, c: &mut Cursor, end: usize, path: Path, frame: &mut Frame) -> Result<Flow> {
+        let saved = core::mem::replace(&mut frame.scope, path);
+        let result = self.term_list(c, end, frame);
+        let path = core::mem::replace(&mut frame.scope, saved);
+        match result {
+            Ok(Flow::Normal) => {}
+            Ok(flow) => return Ok(flow),
+            Err(e) if !frame.in_method() => log::warn!("AML: skipped the rest of {}: {}", path, e),
+            Err(e) => return Err(e),
+        }
+        c.pos = end;
+        Ok(Flow::Normal)
+    }
+
+    fn term(&mut self, c: &mut Cursor, end: usize, frame: &mut Frame) -> Result<Flow> {
+        let op = c.peek()?;
+        match op {
+            NAME_OP => {
+                c.pos += 1;
+                let name = c.name_string()?;
+                let value = self.term_arg(c, frame)?;
+                self.add(&name, value, frame)?;
+            }
+            SCOPE_OP => {
+                c.pos += 1;
+                let scope_end = c.pkg_length()?;
+                let name = c.name_string()?;
+                let path = self.add(&name, Object::Scope, frame)?;
+                return self.nested(c, scope_end, path, frame);
+            }
+            METHOD_OP => {
+                c.pos += 1;
+                let method_end = c.pkg_length()?;
+                let name = c.name_string()?;
+                let flags = c.byte()?;
+                let code = Arc::from(&c.data[c.pos..method_end]);
+                c.pos = method_end;
+                self.add(&name, Object::Method(Method { code, arg_count: flags & 0x07 }), frame)?;
+            }
+            EXTERNAL_OP => {
+                c.pos += 1;
+                c.name_string()?;
+                c.bytes(2)?;
+            }
+            ALIAS_OP => {
+                c.pos += 1;
+                let source = c.name_string()?;
+                let alias = c.name_string()?;
+                let source = self.lookup_or_error(&source, &frame.scope)?;
+                self.add(&alias, Object::Alias(source), frame)?;
+            }
+            IF_OP => {
+                c.pos += 1;
+                let if_end = c.pkg_length()?;
+                let predicate = self.term_arg(c, frame)?;
+                if self.to_integer(&predicate)? != 0 {
+                    let flow = self.term_list(c, if_end, frame)?;
+                    if !matches!(flow, Flow::Normal) {
+                        return Ok(flow);
+                    }
+                    if c.pos < end && c.peek()? == ELSE_OP {
+                        c.pos += 1;
+                        c.pos = c.pkg_length()?;
+                    }
+                } else {
+                    c.pos = if_end;
+                    if c.pos < end && c.peek()? == ELSE_OP {
+                        c.pos += 1;
+                        let else_end = c.pkg_length()?;
+                        return self.term_list(c, else_end, frame);
+                    }
+                }
+            }
+            ELSE_OP => {
+                c.pos += 1;
+                c.pos = c.pkg_length()?;
+            }
+            WHILE_OP => {
+                c.pos += 1;
+                let while_end = c.pkg_length()?;
+                let predicate_start = c.pos;
+                let mut count = 0;
+                loop {
+                    c.pos = predicate_start;
+                    let predicate = self.term_arg(c, frame)?;
+                    if self.to_integer(&predicate)? == 0 {
+                        break;
+                    }
+                    match self.term_list(c, while_end, frame)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                    count += 1;
+                    if count >= MAX_LOOP {
+                        return Err(Error::LoopTimeout);
+                    }
+                }
+                c.pos = while_end;
+            }
+            RETURN_OP => {
+                c.pos += 1;
+                let value = self.term_arg(c, frame)?;
+                return Ok(Flow::Return(value));
+            }
+            BREAK_OP => {
+                c.pos += 1;
+                return Ok(Flow::Break);
+            }
+            CONTINUE_OP => {
+                c.pos += 1;
+                return Ok(Flow::Continue);
+            }
+            NOOP_OP | BREAKPOINT_OP => c.pos += 1,
+            NOTIFY_OP => {
+                c.pos += 1;
+                let target = self.target(c, frame)?;
+                let value = self.term_arg(c, frame)?;
+                if let Target::Name(path) = target {
+                    log::trace!("AML: Notify({}, {})", path, value);
+                }
+            }
+            CREATE_DWORD_FIELD_OP | CREATE_WORD_FIELD_OP | CREATE_BYTE_FIELD_OP | CREATE_BIT_FIELD_OP | CREATE_QWORD_FIELD_OP => {
+                c.pos += 1;
+                let source = self.field_source(c, frame)?;
+                let index = self.integer_arg(c, frame)?;
+                let (bit_offset, bit_length) = match op {
+                    CREATE_BIT_FIELD_OP => (index, 1),
+                    CREATE_BYTE_FIELD_OP => (index * 8, 8),
+                    CREATE_WORD_FIELD_OP => (index * 8, 16),
+                    CREATE_DWORD_FIELD_OP => (index * 8, 32),
+                    _ => (index * 8, 64),
+                };
+                let name = c.name_string()?;
+                self.add(&name, Object::BufferField(BufferField { source, bit_offset, bit_length }), frame)?;
+            }
+            EXT_OP_PREFIX => return self.ext_term(c, frame),
+            _ => {
+                self.term_arg(c, frame)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn ext_term(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<Flow> {
+        let op = c.peek_at(1).ok_or(Error::UnexpectedEnd)?;
+        match op {
+            MUTEX_OP => {
+                c.pos += 2;
+                let name = c.name_string()?;
+                c.byte()?;
+                self.add(&name, Object::Mutex, frame)?;
+            }
+            EVENT_OP => {
+                c.pos += 2;
+                let name = c.name_string()?;
+                self.add(&name, Object::Event, frame)?;
+            }
+            OP_REGION_OP => {
+                c.pos += 2;
+                let name = c.name_string()?;
+                let space = c.byte()?;
+                let offset = self.integer_arg(c, frame)?;
+                let length = self.integer_arg(c, frame)?;
+                let device = frame.scope.clone();
+                self.add(&name, Object::OperationRegion(Region { space, offset, length, device }), frame)?;
+            }
+            FIELD_OP => {
+                c.pos += 2;
+                let field_end = c.pkg_length()?;
+                let region = c.name_string()?;
+                let region = self.lookup_or_error(&region, &frame.scope)?;
+                self.field_list(c, field_end, FieldKind::Normal { region }, frame)?;
+            }
+            INDEX_FIELD_OP => {
+                c.pos += 2;
+                let field_end = c.pkg_length()?;
+                let index = c.name_string()?;
+                let data = c.name_string()?;
+                let index = self.lookup_or_error(&index, &frame.scope)?;
+                let data = self.lookup_or_error(&data, &frame.scope)?;
+                self.field_list(c, field_end, FieldKind::Index { index, data }, frame)?;
+            }
+            BANK_FIELD_OP => {
+                c.pos += 2;
+                let field_end = c.pkg_length()?;
+                let region = c.name_string()?;
+                let bank = c.name_string()?;
+                let region = self.lookup_or_error(&region, &frame.scope)?;
+                let bank = self.lookup_or_error(&bank, &frame.scope)?;
+                let value = self.integer_arg(c, frame)?;
+                self.field_list(c, field_end, FieldKind::Bank { region, bank, value }, frame)?;
+            }
+            DEVICE_OP | THERMAL_ZONE_OP => {
+                c.pos += 2;
+                let device_end = c.pkg_length()?;
+                let name = c.name_string()?;
+                let object = if op == DEVICE_OP { Object::Device } else { Object::ThermalZone };
+                let path = self.add(&name, object, frame)?;
+                return self.nested(c, device_end, path, frame);
+            }
+            PROCESSOR_OP => {
+                c.pos += 2;
+                let processor_end = c.pkg_length()?;
+                let name = c.name_string()?;
+                let id = c.byte()?;
+                // PblkAddr, PblkLen
+                c.bytes(5)?;
+                let path = self.add(&name, Object::Processor { id }, frame)?;
+                return self.nested(c, processor_end, path, frame);
+            }
+            POWER_RES_OP => {
+                c.pos += 2;
+                let power_end = c.pkg_length()?;
+                let name = c.name_string()?;
+                // SystemLevel, ResourceOrder
+                c.bytes(3)?;
+                let path = self.add(&name, Object::PowerResource, frame)?;
+                return self.nested(c, power_end, path, frame);
+            }
+            CREATE_FIELD_OP => {
+                c.pos += 2;
+                let source = self.field_source(c, frame)?;
+                let bit_offset = self.integer_arg(c, frame)?;
+                let bit_length = self.integer_arg(c, frame)?;
+                let name = c.name_string()?;
+                self.add(&name, Object::BufferField(BufferField { source, bit_offset, bit_length }), frame)?;
+            }
+            SLEEP_OP | STALL_OP => {
+                c.pos += 2;
+                let time = self.integer_arg(c, frame)?;
+                self.handler.stall(if op == SLEEP_OP { time.saturating_mul(1000) } else { time });
+            }
+            RELEASE_OP | SIGNAL_OP | RESET_OP => {
+                c.pos += 2;
+                self.target(c, frame)?;
+            }
+            FATAL_OP => {
+                c.pos += 2;
+                let ty = c.byte()?;
+                let code = c.integer(4)?;
+                let arg = self.integer_arg(c, frame)?;
+                log::error!("AML: Fatal({:#x}, {:#x}, {:#x})", ty, code, arg);
+                return Err(Error::Fatal);
+            }
+            _ => {
+                self.term_arg(c, frame)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn field_list(&mut self, c: &mut Cursor, end: usize, kind: FieldKind, frame: &mut Frame) -> Result<()> {
+        let flags = c.byte()?;
+        let mut access_width = access_bytes(flags & 0x0f);
+        let update_rule = (flags >> 5) & 0x03;
+        let mut bit_offset = 0;
+        while c.pos < end {
+            match c.peek()? {
+                RESERVED_FIELD => {
+                    c.pos += 1;
+                    bit_offset += c.pkg_length_value()? as u64;
+                }
+                ACCESS_FIELD => {
+                    c.pos += 1;
+                    access_width = access_bytes(c.byte()? & 0x0f);
+                    c.byte()?;
+                }
+                EXTENDED_ACCESS_FIELD => {
+                    c.pos += 1;
+                    access_width = access_bytes(c.byte()? & 0x0f);
+                    c.bytes(2)?;
+                }
+                _ => {
+                    let seg = c.name_seg()?;
+                    let bit_length = c.pkg_length_value()? as u64;
+                    let name = NameString { absolute: false, parents: 0, segments: vec![seg] };
+                    let field = FieldUnit { kind: kind.clone(), bit_offset, bit_length, access_width, update_rule };
+                    self.add(&name, Object::FieldUnit(field), frame)?;
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn field_source(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<FieldSource> {
+        match c.peek()? {
+            op @ LOCAL0_OP..=LOCAL7_OP => {
+                c.pos += 1;
+                Ok(FieldSource::Local((op - LOCAL0_OP) as usize))
+            }
+            op @ ARG0_OP..=ARG6_OP => {
+                c.pos += 1;
+                Ok(FieldSource::Arg((op - ARG0_OP) as usize))
+            }
+            op if name::is_name_string_start(op) => {
+                let name = c.name_string()?;
+                Ok(FieldSource::Name(self.lookup_or_error(&name, &frame.scope)?))
+            }
+            _ => Err(Error::NotSupported("buffer field on a temporary buffer")),
+        }
+    }
+
+    fn integer_arg(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<u64> {
+        let value = self.term_arg(c, frame)?;
+        self.to_integer(&value)
+    }
+
+    fn term_arg(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<Object> {
+        let op = c.peek()?;
+        if name::is_name_string_start(op) {
+            let name = c.name_string()?;
+            let path = self.lookup_or_error(&name, &frame.scope)?;
+            if let Some(Object::Method(method)) = self.objects.get(&path) {
+                let method = method.clone();
+                let mut args = Vec::new();
+                for _ in 0..method.arg_count {
+                    args.push(self.term_arg(c, frame)?);
+                }
+                return self.invoke(&path, &method, args);
+            }
+            return self.read_name(&path, frame);
+        }
+        c.pos += 1;
+        let value = match op {
+            ZERO_OP => Object::Integer(0),
+            ONE_OP => Object::Integer(1),
+            ONES_OP => Object::Integer(self.mask(u64::MAX)),
+            BYTE_PREFIX => Object::Integer(c.integer(1)?),
+            WORD_PREFIX => Object::Integer(c.integer(2)?),
+            DWORD_PREFIX => Object::Integer(c.integer(4)?),
+            QWORD_PREFIX => Object::Integer(self.mask(c.integer(8)?)),
+            STRING_PREFIX => {
+                let len = c.data[c.pos..].iter().position(|&b| b == 0).ok_or(Error::UnexpectedEnd)?;
+                let s = core::str::from_utf8(c.bytes(len)?).map_err(|_| Error::TypeMismatch)?;
+                c.pos += 1;
+                Object::String(String::from(s))
+            }
+            BUFFER_OP => {
+                let buffer_end = c.pkg_length()?;
+                let size = self.integer_arg(c, frame)? as usize;
+                let init = &c.data[c.pos.min(buffer_end)..buffer_end];
+                c.pos = buffer_end;
+                let mut buffer = init.to_vec();
+                buffer.resize(size.max(init.len()), 0);
+                Object::Buffer(buffer)
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                let package_end = c.pkg_length()?;
+                let count = if op == PACKAGE_OP { c.byte()? as usize } else { self.integer_arg(c, frame)? as usize };
+                let mut elements = Vec::new();
+                while c.pos < package_end {
+                    let element = if name::is_name_string_start(c.peek()?) {
+                        let name = c.name_string()?;
+                        let path = self.lookup(&name, &frame.scope).or_else(|| name.resolve(&frame.scope)).ok_or(Error::InvalidName)?;
+                        Object::Reference(path)
+                    } else {
+                        self.term_arg(c, frame)?
+                    };
+                    elements.push(element);
+                }
+                if elements.len() < count {
+                    elements.resize(count, Object::Uninitialized);
+                }
+                Object::Package(elements)
+            }
+            LOCAL0_OP..=LOCAL7_OP => frame.locals[(op - LOCAL0_OP) as usize].clone(),
+            ARG0_OP..=ARG6_OP => frame.args[(op - ARG0_OP) as usize].clone(),
+            STORE_OP | COPY_OBJECT_OP => {
+                let value = self.term_arg(c, frame)?;
+                let target = self.target(c, frame)?;
+                if op == STORE_OP {
+                    self.store(&target, value.clone(), frame)?;
+                } else {
+                    self.store_raw(&target, value.clone(), frame)?;
+                }
+                value
+            }
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+                let a = self.integer_arg(c, frame)?;
+                let b = self.integer_arg(c, frame)?;
+                let result = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => a.checked_shl(b as u32).unwrap_or(0),
+                    SHIFT_RIGHT_OP => a.checked_shr(b as u32).unwrap_or(0),
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => a.checked_rem(b).ok_or(Error::DivideByZero)?,
+                };
+                self.store_result(c, frame, Object::Integer(self.mask(result)))?
+            }
+            NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP | FROM_BCD_OP | TO_BCD_OP => {
+                let a = self.integer_arg(c, frame)?;
+                let result = match op {
+                    NOT_OP => !a,
+                    FIND_SET_LEFT_BIT_OP => 64 - a.leading_zeros() as u64,
+                    FIND_SET_RIGHT_BIT_OP => if a == 0 { 0 } else { a.trailing_zeros() as u64 + 1 },
+                    FROM_BCD_OP => from_bcd(a),
+                    _ => to_bcd(a),
+                };
+                self.store_result(c, frame, Object::Integer(self.mask(result)))?
+            }
+            DIVIDE_OP => {
+                let a = self.integer_arg(c, frame)?;
+                let b = self.integer_arg(c, frame)?;
+                if b == 0 {
+                    return Err(Error::DivideByZero);
+                }
+                let remainder = self.target(c, frame)?;
+                self.store(&remainder, Object::Integer(a % b), frame)?;
+                self.store_result(c, frame, Object::Integer(a / b))?
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.target(c, frame)?;
+                let value = self.read_target(&target, frame)?;
+                let value = self.to_integer(&value)?;
+                let value = if op == INCREMENT_OP { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                let value = Object::Integer(self.mask(value));
+                self.store(&target, value.clone(), frame)?;
+                value
+            }
+            LAND_OP | LOR_OP => {
+                let a = self.integer_arg(c, frame)? != 0;
+                let b = self.integer_arg(c, frame)? != 0;
+                self.boolean(if op == LAND_OP { a && b } else { a || b })
+            }
+            LNOT_OP => {
+                let a = self.integer_arg(c, frame)?;
+                self.boolean(a == 0)
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.term_arg(c, frame)?;
+                let b = self.term_arg(c, frame)?;
+                let ordering = self.compare(&a, &b)?;
+                self.boolean(match op {
+                    LEQUAL_OP => ordering.is_eq(),
+                    LGREATER_OP => ordering.is_gt(),
+                    _ => ordering.is_lt(),
+                })
+            }
+            CONCAT_OP => {
+                let a = self.term_arg(c, frame)?;
+                let b = self.term_arg(c, frame)?;
+                let result = match a {
+                    Object::String(mut s) => {
+                        s.push_str(&self.to_string(&b)?);
+                        Object::String(s)
+                    }
+                    a => {
+                        let mut buffer = self.to_buffer(&a)?;
+                        buffer.extend_from_slice(&self.to_buffer(&b)?);
+                        Object::Buffer(buffer)
+                    }
+                };
+                self.store_result(c, frame, result)?
+            }
+            SIZE_OF_OP => {
+                let target = self.target(c, frame)?;
+                let value = self.read_target(&target, frame)?;
+                Object::Integer(match value {
+                    Object::String(s) => s.len(),
+                    Object::Buffer(b) => b.len(),
+                    Object::Package(p) => p.len(),
+                    _ => return Err(Error::TypeMismatch),
+                } as u64)
+            }
+            INDEX_OP => {
+                let source = self.term_arg(c, frame)?;
+                let index = self.integer_arg(c, frame)? as usize;
+                let element = index_of(&source, index)?;
+                self.store_result(c, frame, element)?
+            }
+            DEREF_OF_OP => match self.term_arg(c, frame)? {
+                Object::Reference(path) => self.read_name(&path, frame)?,
+                other => other,
+            },
+            REF_OF_OP => match self.target(c, frame)? {
+                Target::Name(path) => Object::Reference(path),
+                _ => return Err(Error::NotSupported("RefOf on a local object")),
+            },
+            OBJECT_TYPE_OP => {
+                let target = self.target(c, frame)?;
+                let value = match &target {
+                    Target::Name(path) => self.objects.get(path).cloned().unwrap_or(Object::Uninitialized),
+                    target => self.read_target(target, frame)?,
+                };
+                Object::Integer(value.type_code())
+            }
+            TO_INTEGER_OP => {
+                let value = match self.term_arg(c, frame)? {
+                    Object::String(s) => Object::Integer(parse_integer(&s).ok_or(Error::TypeMismatch)?),
+                    other => Object::Integer(self.to_integer(&other)?),
+                };
+                self.store_result(c, frame, value)?
+            }
+            TO_BUFFER_OP => {
+                let value = self.term_arg(c, frame)?;
+                let value = Object::Buffer(self.to_buffer(&value)?);
+                self.store_result(c, frame, value)?
+            }
+            TO_HEX_STRING_OP | TO_DECIMAL_STRING_OP => {
+                let value = self.term_arg(c, frame)?;
+                let hex = op == TO_HEX_STRING_OP;
+                let s = match value {
+                    Object::Integer(v) if hex => format!("0x{:X}", v),
+                    Object::Integer(v) => format!("{}", v),
+                    Object::Buffer(b) => {
+                        let items: Vec<String> = b.iter().map(|x| if hex { format!("0x{:02X}", x) } else { format!("{}", x) }).collect();
+                        items.join(",")
+                    }
+                    Object::String(s) => s,
+                    _ => return Err(Error::TypeMismatch),
+                };
+                self.store_result(c, frame, Object::String(s))?
+            }
+            TO_STRING_OP => {
+                let value = self.term_arg(c, frame)?;
+                let limit = self.integer_arg(c, frame)?;
+                let Object::Buffer(b) = value else {
+                    return Err(Error::TypeMismatch);
+                };
+                let len = b.iter().position(|&x| x == 0).unwrap_or(b.len()).min(limit as usize);
+                let s = String::from_utf8(b[..len].to_vec()).map_err(|_| Error::TypeMismatch)?;
+                self.store_result(c, frame, Object::String(s))?
+            }
+            MID_OP => {
+                let value = self.term_arg(c, frame)?;
+                let index = self.integer_arg(c, frame)? as usize;
+                let len = self.integer_arg(c, frame)? as usize;
+                let result = match value {
+                    Object::String(s) => {
+                        let start = index.min(s.len());
+                        Object::String(String::from(s.get(start..(start + len).min(s.len())).ok_or(Error::TypeMismatch)?))
+                    }
+                    Object::Buffer(b) => {
+                        let start = index.min(b.len());
+                        Object::Buffer(b[start..(start + len).min(b.len())].to_vec())
+                    }
+                    _ => return Err(Error::TypeMismatch),
+                };
+                self.store_result(c, frame, result)?
+            }
+            EXT_OP_PREFIX => {
+                let ext = c.byte()?;
+                match ext {
+                    COND_REF_OF_OP => {
+                        let exists = match c.peek()? {
+                            LOCAL0_OP..=LOCAL7_OP | ARG0_OP..=ARG6_OP => {
+                                c.pos += 1;
+                                None
+                            }
+                            _ => {
+                                let name = c.name_string()?;
+                                self.lookup(&name, &frame.scope)
+                            }
+                        };
+                        let target = self.target(c, frame)?;
+                        match exists {
+                            Some(path) => {
+                                self.store(&target, Object::Reference(path), frame)?;
+                                self.boolean(true)
+                            }
+                            None => Object::Integer(0),
+                        }
+                    }
+                    ACQUIRE_OP => {
+                        self.target(c, frame)?;
+                        c.bytes(2)?;
+                        // ひとつのスレッドでしか動かさないので、必ず取れる
+                        Object::Integer(0)
+                    }
+                    WAIT_OP => {
+                        self.target(c, frame)?;
+                        self.term_arg(c, frame)?;
+                        Object::Integer(0)
+                    }
+                    REVISION_OP => Object::Integer(2),
+                    TIMER_OP => Object::Integer(0),
+                    _ => return Err(Error::UnknownOpcode((EXT_OP_PREFIX as u16) << 8 | ext as u16)),
+                }
+            }
+            _ => return Err(Error::UnknownOpcode(op as u16)),
+        };
+        Ok(value)
+    }
+
+    fn boolean(&self, b: bool) -> Object {
+        Object::Integer(if b { self.mask(u64::MAX) } else { 0 })
+    }
+
+    // 演算の最後のTargetに書き、値を返す
+    fn store_result(&mut self, c: &mut Cursor, frame: &mut Frame, value: Object) -> Result<Object> {
+        let target = self.target(c, frame)?;
+        self.store(&target, value.clone(), frame)?;
+        Ok(value)
+    }
+
+    fn target(&mut self, c: &mut Cursor, frame: &mut Frame) -> Result<Target> {
+        let op = c.peek()?;
+        match op {
+            name::NULL_NAME => {
+                c.pos += 1;
+                Ok(Target::None)
+            }
+            LOCAL0_OP..=LOCAL7_OP => {
+                c.pos += 1;
+                Ok(Target::Local((op - LOCAL0_OP) as usize))
+            }
+            ARG0_OP..=ARG6_OP => {
+                c.pos += 1;
+                Ok(Target::Arg((op - ARG0_OP) as usize))
+            }
+            EXT_OP_PREFIX if c.peek_at(1) == Some(DEBUG_OP) => {
+                c.pos += 2;
+                Ok(Target::Debug)
+            }
+            INDEX_OP => {
+                c.pos += 1;
+                let source = self.target(c, frame)?;
+                let index = self.integer_arg(c, frame)? as usize;
+                self.target(c, frame)?;
+                Ok(Target::Index(alloc::boxed::Box::new(source), index))
+            }
+            DEREF_OF_OP => {
+                c.pos += 1;
+                match self.term_arg(c, frame)? {
+                    Object::Reference(path) => Ok(Target::Name(path)),
+                    _ => Err(Error::TypeMismatch),
+                }
+            }
+            op if name::is_name_string_start(op) => {
+                let name = c.name_string()?;
+                Ok(Target::Name(self.lookup_or_error(&name, &frame.scope)?))
+            }
+            _ => Err(Error::UnknownOpcode(op as u16)),
+        }
+    }
+
+    fn read_target(&mut self, target: &Target, frame: &mut Frame) -> Result<Object> {
+        match target {
+            Target::None | Target::Debug => Ok(Object::Uninitialized),
+            Target::Local(i) => Ok(frame.locals[*i].clone()),
+            Target::Arg(i) => Ok(frame.args[*i].clone()),
+            Target::Name(path) => self.read_name(path, frame),
+            Target::Index(source, index) => {
+                let source = self.read_target(source, frame)?;
+                index_of(&source, *index)
+            }
+        }
+    }
+
+    // 名前の付いたものを読む。フィールドならハードウェアから読む
+    fn read_name(&mut self, path: &Path, frame: &mut Frame) -> Result<Object> {
+        let object = self.objects.get(path).cloned().ok_or_else(|| Error::UndefinedName(path.clone()))?;
+        match object {
+            Object::FieldUnit(field) => self.read_field(&field, frame),
+            Object::BufferField(field) => {
+                let buffer = self.field_buffer(&field.source, frame)?;
+                let bytes = extract_bits(buffer, field.bit_offset, field.bit_length);
+                Ok(bits_to_object(bytes, field.bit_length))
+            }
+            Object::Alias(target) => self.read_name(&target, frame),
+            Object::Device | Object::Scope | Object::Processor { .. } | Object::PowerResource | Object::ThermalZone
+                | Object::Mutex | Object::Event | Object::OperationRegion(_) => Ok(Object::Reference(path.clone())),
+            other => Ok(other),
+        }
+    }
+
+    // 書き込む先の型に合わせて変換してから書く
+    fn store(&mut self, target: &Target, value: Object, frame: &mut Frame) -> Result<()> {
+        let Target::Name(path) = target else {
+            return self.store_raw(target, value, frame);
+        };
+        let current = self.objects.get(path).cloned().ok_or_else(|| Error::UndefinedName(path.clone()))?;
+        match current {
+            Object::FieldUnit(field) => self.write_field(&field, &value, frame),
+            Object::BufferField(field) => {
+                let bits = self.to_bits(&value)?;
+                let buffer = self.field_buffer_mut(&field.source, frame)?;
+                insert_bits(buffer, field.bit_offset, field.bit_length, &bits);
+                Ok(())
+            }
+            Object::Alias(path) => self.store(&Target::Name(path), value, frame),
+            Object::Integer(_) => {
+                let v = self.to_integer(&value)?;
+                self.objects.insert(path.clone(), Object::Integer(v));
+                Ok(())
+            }
+            Object::Buffer(old) => {
+                let mut buffer = self.to_buffer(&value)?;
+                buffer.resize(old.len(), 0);
+                self.objects.insert(path.clone(), Object::Buffer(buffer));
+                Ok(())
+            }
+            Object::String(_) => {
+                let s = self.to_string(&value)?;
+                self.objects.insert(path.clone(), Object::String(s));
+                Ok(())
+            }
+            Object::Method(_) => Err(Error::TypeMismatch),
+            _ => {
+                self.objects.insert(path.clone(), value);
+                Ok(())
+            }
+        }
+    }
+
+    fn store_raw(&mut self, target: &Target, value: Object, frame: &mut Frame) -> Result<()> {
+        match target {
+            Target::None => {}
+            Target::Debug => log::debug!("AML: Debug = {}", value),
+            Target::Local(i) => frame.locals[*i] = value,
+            Target::Arg(i) => frame.args[*i] = value,
+            Target::Name(path) => {
+                self.objects.insert(path.clone(), value);
+            }
+            Target::Index(source, index) => {
+                let mut container = self.read_target(source, frame)?;
+                match &mut container {
+                    Object::Package(p) => *p.get_mut(*index).ok_or(Error::IndexOutOfRange)? = value,
+                    Object::Buffer(b) => *b.get_mut(*index).ok_or(Error::IndexOutOfRange)? = self.to_integer(&value)? as u8,
+                    _ => return Err(Error::TypeMismatch),
+                }
+                self.store_raw(source, container, frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn field_buffer<'a>(&'a self, source: &FieldSource, frame: &'a Frame) -> Result<&'a [u8]> {
+        let object = match source {
+            FieldSource::Name(path) => self.objects.get(path).ok_or_else(|| Error::UndefinedName(path.clone()))?,
+            FieldSource::Local(i) => &frame.locals[*i],
+            FieldSource::Arg(i) => &frame.args[*i],
+        };
+        match object {
+            Object::Buffer(b) => Ok(b),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn field_buffer_mut<'a>(&'a mut self, source: &FieldSource, frame: &'a mut Frame) -> Result<&'a mut Vec<u8>> {
+        let object = match source {
+            FieldSource::Name(path) => self.objects.get_mut(path).ok_or_else(|| Error::UndefinedName(path.clone()))?,
+            FieldSource::Local(i) => &mut frame.locals[*i],
+            FieldSource::Arg(i) => &mut frame.args[*i],
+        };
+        match object {
+            Object::Buffer(b) => Ok(b),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn read_field(&mut self, field: &FieldUnit, frame: &mut Frame) -> Result<Object> {
+        let mut bytes = vec![0u8; field.bit_length.div_ceil(8) as usize];
+        let unit_bits = field.access_width * 8;
+        let end = field.bit_offset + field.bit_length;
+        for unit in field.bit_offset / unit_bits..end.div_ceil(unit_bits) {
+            let value = self.read_unit(field, unit * field.access_width, frame)?;
+            let unit_start = unit * unit_bits;
+            for bit in field.bit_offset.max(unit_start)..end.min(unit_start + unit_bits) {
+                if value >> (bit - unit_start) & 1 != 0 {
+                    let dst = bit - field.bit_offset;
+                    bytes[(dst / 8) as usize] |= 1 << (dst % 8);
+                }
+            }
+        }
+        Ok(bits_to_object(bytes, field.bit_length))
+    }
+
+    fn write_field(&mut self, field: &FieldUnit, value: &Object, frame: &mut Frame) -> Result<()> {
+        let bits = self.to_bits(value)?;
+        let unit_bits = field.access_width * 8;
+        let end = field.bit_offset + field.bit_length;
+        for unit in field.bit_offset / unit_bits..end.div_ceil(unit_bits) {
+            let unit_start = unit * unit_bits;
+            let (lo, hi) = (field.bit_offset.max(unit_start), end.min(unit_start + unit_bits));
+            let mut v = if lo == unit_start && hi == unit_start + unit_bits {
+                0
+            } else {
+                match field.update_rule {
+                    UPDATE_PRESERVE => self.read_unit(field, unit * field.access_width, frame)?,
+                    UPDATE_WRITE_AS_ONES => u64::MAX,
+                    _ => 0,
+                }
+            };
+            for bit in lo..hi {
+                let src = bit - field.bit_offset;
+                let set = bits.get((src / 8) as usize).is_some_and(|b| b >> (src % 8) & 1 != 0);
+                let mask = 1 << (bit - unit_start);
+                if set { v |= mask } else { v &= !mask }
+            }
+            self.write_unit(field, unit * field.access_width, v, frame)?;
+        }
+        Ok(())
+    }
+
+    fn read_unit(&mut self, field: &FieldUnit, offset: u64, frame: &mut Frame) -> Result<u64> {
+        match &field.kind {
+            FieldKind::Normal { region } => self.access_region(region, offset, field.access_width, None),
+            FieldKind::Bank { region, bank, value } => {
+                self.store(&Target::Name(bank.clone()), Object::Integer(*value), frame)?;
+                self.access_region(region, offset, field.access_width, None)
+            }
+            FieldKind::Index { index, data } => {
+                self.store(&Target::Name(index.clone()), Object::Integer(offset), frame)?;
+                let value = self.read_name(data, frame)?;
+                self.to_integer(&value)
+            }
+        }
+    }
+
+    fn write_unit(&mut self, field: &FieldUnit, offset: u64, value: u64, frame: &mut Frame) -> Result<()> {
+        match &field.kind {
+            FieldKind::Normal { region } => self.access_region(region, offset, field.access_width, Some(value)).map(|_| ()),
+            FieldKind::Bank { region, bank, value: bank_value } => {
+                self.store(&Target::Name(bank.clone()), Object::Integer(*bank_value), frame)?;
+                self.access_region(region, offset, field.access_width, Some(value)).map(|_| ())
+            }
+            FieldKind::Index { index, data } => {
+                self.store(&Target::Name(index.clone()), Object::Integer(offset), frame)?;
+                self.store(&Target::Name(data.clone()), Object::Integer(value), frame)
+            }
+        }
+    }
+
+    // valueがあれば書き、なければ読む
+    fn access_region(&mut self, path: &Path, offset: u64, width: u64, value: Option<u64>) -> Result<u64> {
+        let Some(Object::OperationRegion(region)) = self.objects.get(path).cloned() else {
+            return Err(Error::TypeMismatch);
+        };
+        let address = region.offset + offset;
+        let mask = if width >= 8 { u64::MAX } else { (1 << (width * 8)) - 1 };
+        Ok(match (region.space, value) {
+            (REGION_SYSTEM_MEMORY, None) => self.handler.read_memory(address, width) & mask,
+            (REGION_SYSTEM_MEMORY, Some(v)) => { self.handler.write_memory(address, width, v & mask); 0 }
+            (REGION_SYSTEM_IO, None) => self.handler.read_io(address as u16, width) & mask,
+            (REGION_SYSTEM_IO, Some(v)) => { self.handler.write_io(address as u16, width, v & mask); 0 }
+            (REGION_PCI_CONFIG, value) => {
+                let pci = self.pci_address(&region.device)?;
+                match value {
+                    None => self.handler.read_pci(pci, address as u16, width) & mask,
+                    Some(v) => { self.handler.write_pci(pci, address as u16, width, v & mask); 0 }
+                }
+            }
+            _ => return Err(Error::NotSupported("operation region space")),
+        })
+    }
+
+    // デバイスの_ADRと、ルートブリッジの_SEG, _BBNから決める
+    // PCI-PCIブリッジの先は考えていない
+    fn pci_address(&mut self, device: &Path) -> Result<PciAddress> {
+        let adr = match device.join(*b"_ADR") {
+            p if self.objects.contains_key(&p) => self.evaluate_integer(&p)?,
+            _ => 0,
+        };
+        let mut address = PciAddress { segment: 0, bus: 0, device: (adr >> 16) as u8, function: adr as u8 };
+        let mut scope = Some(device.clone());
+        while let Some(s) = scope {
+            let bbn = s.join(*b"_BBN");
+            if self.objects.contains_key(&bbn) {
+                address.bus = self.evaluate_integer(&bbn)? as u8;
+                let seg = s.join(*b"_SEG");
+                if self.objects.contains_key(&seg) {
+                    address.segment = self.evaluate_integer(&seg)? as u16;
+                }
+                break;
+            }
+            scope = s.parent();
+        }
+        Ok(address)
+    }
+
+    fn to_integer(&self, value: &Object) -> Result<u64> {
+        match value {
+            Object::Integer(v) => Ok(*v),
+            Object::Buffer(b) => {
+                let mut bytes = [0u8; 8];
+                let n = b.len().min(if self.wide { 8 } else { 4 });
+                bytes[..n].copy_from_slice(&b[..n]);
+                Ok(u64::from_le_bytes(bytes))
+            }
+            Object::String(s) => Ok(self.mask(u64::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"), 16).map_err(|_| Error::TypeMismatch)?)),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn to_buffer(&self, value: &Object) -> Result<Vec<u8>> {
+        match value {
+            Object::Integer(v) => Ok(v.to_le_bytes()[..if self.wide { 8 } else { 4 }].to_vec()),
+            Object::Buffer(b) => Ok(b.clone()),
+            Object::String(s) => {
+                let mut b = s.as_bytes().to_vec();
+                b.push(0);
+                Ok(b)
+            }
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn to_string(&self, value: &Object) -> Result<String> {
+        match value {
+            Object::String(s) => Ok(s.clone()),
+            Object::Integer(v) => Ok(format!("{:X}", v)),
+            Object::Buffer(b) => Ok(b.iter().map(|x| format!("{:02X}", x)).collect::<Vec<_>>().join(" ")),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn to_bits(&self, value: &Object) -> Result<Vec<u8>> {
+        match value {
+            Object::Integer(v) => Ok(v.to_le_bytes().to_vec()),
+            Object::Buffer(b) => Ok(b.clone()),
+            Object::String(s) => Ok(s.as_bytes().to_vec()),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+
+    fn compare(&self, a: &Object, b: &Object) -> Result<core::cmp::Ordering> {
+        match a {
+            Object::Integer(a) => Ok(a.cmp(&self.to_integer(b)?)),
+            Object::String(a) => Ok(a.as_str().cmp(self.to_string(b)?.as_str())),
+            Object::Buffer(a) => Ok(a.as_slice().cmp(self.to_buffer(b)?.as_slice())),
+            _ => Err(Error::TypeMismatch),
+        }
+    }
+}
+
+fn access_bytes(access_type: u8) -> u64 {
+    match access_type {
+        2 => 2,
+        3 => 4,
+        4 => 8,
+        // AnyAcc, ByteAcc, BufferAcc
+        _ => 1,
+    }
+}
+
+fn index_of(source: &Object, index: usize) -> Result<Object> {
+    match source {
+        Object::Package(p) => p.get(index).cloned().ok_or(Error::IndexOutOfRange),
+        Object::Buffer(b) => b.get(index).map(|&x| Object::Integer(x as u64)).ok_or(Error::IndexOutOfRange),
+        Object::String(s) => s.as_bytes().get(index).map(|&x| Object::Integer(x as u64)).ok_or(Error::IndexOutOfRange),
+        _ => Err(Error::TypeMismatch),
+    }
+}
+
+fn extract_bits(buffer: &[u8], bit_offset: u64, bit_length: u64) -> Vec<u8> {
+    let mut bytes = vec![0u8; bit_length.div_ceil(8) as usize];
+    for i in 0..bit_length {
+        let src = bit_offset + i;
+        if buffer.get((src / 8) as usize).is_some_and(|b| b >> (src % 8) & 1 != 0) {
+            bytes[(i / 8) as usize] |= 1 << (i % 8);
+        }
+    }
+    bytes
+}
+
+fn insert_bits(buffer: &mut [u8], bit_offset: u64, bit_length: u64, bits: &[u8]) {
+    for i in 0..bit_length {
+        let dst = bit_offset + i;
+        let Some(byte) = buffer.get_mut((dst / 8) as usize) else {
+            break;
+        };
+        let set = bits.get((i / 8) as usize).is_some_and(|b| b >> (i % 8) & 1 != 0);
+        if set { *byte |= 1 << (dst % 8) } else { *byte &= !(1 << (dst % 8)) }
+    }
+}
+
+// 64bitまでなら整数、それより長ければバッファ
+fn bits_to_object(bytes: Vec<u8>, bit_length: u64) -> Object {
+    if bit_length > 64 {
+        return Object::Buffer(bytes);
+    }
+    let mut v = [0u8; 8];
+    v[..bytes.len()].copy_from_slice(&bytes);
+    Object::Integer(u64::from_le_bytes(v))
+}
+
+// ToIntegerは0xで始まれば16進、そうでなければ10進
+fn parse_integer(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn from_bcd(v: u64) -> u64 {
+    let mut result = 0;
+    for i in (0..16).rev() {
+        result = result * 10 + (v >> (i * 4) & 0xf);
+    }
+    result
+}
+
+fn to_bcd(mut v: u64) -> u64 {
+    let mut result = 0;
+    for i in 0..16 {
+        result |= (v % 10) << (i * 4);
+        v /= 10;
+    }
+    result
+}
@@ -0,0 +1,292 @@
+// ACPI Machine Language
+// DSDTとSSDTを読み込んで名前空間を作り、メソッドを実行する
+// 電源断の\_S5、PCIの割り込み配線の_PRT、デバイスの_HID, _STA, _CRSなどを読むのに使う
+
+mod name;
+mod object;
+mod interpreter;
+mod resource;
+mod device;
+
+use core::fmt::{self, Write};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::io_port::{inb, ind, inw, outb, outd, outw};
+use crate::paging::phys_to_virt;
+
+pub use name::Path;
+pub use object::Object;
+pub use interpreter::{Aml, Error, Handler, PciAddress, Result};
+pub use resource::{AddressKind, Resource, parse_resources};
+pub use device::{IrqSource, PciRoute, STATUS_DEFAULT, STATUS_ENABLED, STATUS_PRESENT, decode_eisa_id};
+
+// OperationRegionを実際のハードウェアに向ける
+pub struct KernelHandler;
+
+impl Handler for KernelHandler {
+    fn read_memory(&mut self, address: u64, width: u64) -> u64 {
+        let ptr = phys_to_virt(address);
+        unsafe {
+            match width {
+                1 => (ptr as *const u8).read_volatile() as u64,
+                2 => (ptr as *const u16).read_volatile() as u64,
+                4 => (ptr as *const u32).read_volatile() as u64,
+                _ => (ptr as *const u64).read_volatile(),
+            }
+        }
+    }
+
+    fn write_memory(&mut self, address: u64, width: u64, value: u64) {
+        let ptr = phys_to_virt(address);
+        unsafe {
+            match width {
+                1 => (ptr as *mut u8).write_volatile(value as u8),
+                2 => (ptr as *mut u16).write_volatile(value as u16),
+                4 => (ptr as *mut u32).write_volatile(value as u32),
+                _ => (ptr as *mut u64).write_volatile(value),
+            }
+        }
+    }
+
+    fn read_io(&mut self, port: u16, width: u64) -> u64 {
+        unsafe {
+            match width {
+                1 => inb(port) as u64,
+                2 => inw(port) as u64,
+                _ => ind(port) as u64,
+            }
+        }
+    }
+
+    fn write_io(&mut self, port: u16, width: u64, value: u64) {
+        unsafe {
+            match width {
+                1 => outb(port, value as u8),
+                2 => outw(port, value as u16),
+                _ => outd(port, value as u32),
+            }
+        }
+    }
+
+    // I/Oポート経由なのでセグメント0の先頭256バイトしか読めない
+    fn read_pci(&mut self, address: PciAddress, offset: u16, width: u64) -> u64 {
+        if address.segment != 0 || offset > 0xff {
+            log::warn!("AML: PCI config {:?} offset {:#x} is not reachable", address, offset);
+            return u64::MAX;
+        }
+        unsafe { crate::pci::read_config(address.bus, address.device, address.function, offset as u8, width as u8) as u64 }
+    }
+
+    fn write_pci(&mut self, address: PciAddress, offset: u16, width: u64, value: u64) {
+        if address.segment != 0 || offset > 0xff {
+            log::warn!("AML: PCI config {:?} offset {:#x} is not reachable", address, offset);
+            return;
+        }
+        unsafe { crate::pci::write_config(address.bus, address.device, address.function, offset as u8, width as u8, value as u32) }
+    }
+
+    fn stall(&mut self, microseconds: u64) {
+        let Some(fadt) = crate::acpi::fadt() else {
+            for _ in 0..microseconds.saturating_mul(100) { core::hint::spin_loop() }
+            return;
+        };
+        // wait_millisecondsはu32で数えるうえ、24bitのPMタイマは約4.7秒で一周するので、1秒ずつ待つ
+        let mut ms = microseconds.div_ceil(1000);
+        while ms > 0 {
+            let chunk = ms.min(1000);
+            fadt.wait_milliseconds(chunk as u32);
+            ms -= chunk;
+        }
+    }
+}
+
+static AML: Mutex<Option<Aml<KernelHandler>>> = Mutex::new(None);
+
+// init_acpiの後に呼ぶ。DSDTに続けてSSDTを全部読み込む
+pub fn init_aml() -> bool {
+    let Some(tables) = crate::acpi::tables() else {
+        return false;
+    };
+    let Some(dsdt) = crate::acpi::dsdt() else {
+        log::warn!("DSDT is not found");
+        return false;
+    };
+    let mut aml = Aml::new(KernelHandler);
+    for table in core::iter::once(dsdt).chain(tables.find_by_signature("SSDT")) {
+        if let Err(e) = aml.load_table(table.data(), dsdt.revision()) {
+            log::warn!("AML: failed to load {} {}: {}", table.signature(), table.oem_table_id(), e);
+        }
+    }
+    log::info!("AML: {} objects in the namespace", aml.object_count());
+    *AML.lock() = Some(aml);
+    true
+}
+
+pub fn with<T>(f: impl FnOnce(&mut Aml<KernelHandler>) -> T) -> Option<T> {
+    AML.lock().as_mut().map(f)
+}
+
+// 引数がなければデバイスの一覧、あればその名前を評価した値を出す
+pub fn dump(path: Option<&str>, out: &mut dyn Write) -> fmt::Result {
+    let mut guard = AML.lock();
+    let Some(aml) = guard.as_mut() else {
+        return writeln!(out, "AML is not loaded");
+    };
+    let Some(path) = path else {
+        let devices: Vec<Path> = aml.devices().cloned().collect();
+        for device in devices.iter() {
+            let status = aml.status(device).unwrap_or(0);
+            if status & STATUS_PRESENT == 0 {
+                continue;
+            }
+            match aml.hardware_id(device) {
+                Ok(Some(hid)) => writeln!(out, "{:<24} {:<10} status {:#x}", device, hid, status)?,
+                _ => writeln!(out, "{:<24} {:<10} status {:#x}", device, "", status)?,
+            }
+        }
+        return Ok(());
+    };
+    let Some(path) = Path::parse(path) else {
+        return writeln!(out, "invalid path: {} (e.g. \\_SB.PCI0._CRS)", path);
+    };
+    match aml.evaluate(&path, Vec::new()) {
+        Ok(Object::Buffer(b)) if path.last() == Some(b"_CRS") => match parse_resources(&b) {
+            Ok(resources) => resources.iter().try_for_each(|r| writeln!(out, "{:x?}", r)),
+            Err(e) => writeln!(out, "{}: {}", path, e),
+        },
+        Ok(value) => writeln!(out, "{}", value),
+        Err(e) => writeln!(out, "{}: {}", path, e),
+    }
+}
+
+mod test {
+    use alloc::collections::BTreeMap;
+
+    use super::{Handler, PciAddress};
+
+    // 読み書きを覚えておくだけのハードウェア。テストのときしか使わない
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct Memory {
+        bytes: BTreeMap<(u8, u64), u8>,
+    }
+
+    #[allow(dead_code)]
+    impl Memory {
+        fn read(&self, space: u8, address: u64, width: u64) -> u64 {
+            (0..width).map(|i| (*self.bytes.get(&(space, address + i)).unwrap_or(&0) as u64) << (8 * i)).sum()
+        }
+        fn write(&mut self, space: u8, address: u64, width: u64, value: u64) {
+            for i in 0..width {
+                self.bytes.insert((space, address + i), (value >> (8 * i)) as u8);
+            }
+        }
+        fn pci(address: PciAddress, offset: u16) -> u64 {
+            (address.bus as u64) << 20 | (address.device as u64) << 15 | (address.function as u64) << 12 | offset as u64
+        }
+    }
+
+    impl Handler for Memory {
+        fn read_memory(&mut self, address: u64, width: u64) -> u64 { self.read(0, address, width) }
+        fn write_memory(&mut self, address: u64, width: u64, value: u64) { self.write(0, address, width, value) }
+        fn read_io(&mut self, port: u16, width: u64) -> u64 { self.read(1, port as u64, width) }
+        fn write_io(&mut self, port: u16, width: u64, value: u64) { self.write(1, port as u64, width, value) }
+        fn read_pci(&mut self, address: PciAddress, offset: u16, width: u64) -> u64 { self.read(2, Self::pci(address, offset), width) }
+        fn write_pci(&mut self, address: PciAddress, offset: u16, width: u64, value: u64) { self.write(2, Self::pci(address, offset), width, value) }
+        fn stall(&mut self, _microseconds: u64) {}
+    }
+
+    // testdata/test.amlはtest.dslを手で組み立てたもの
+    #[allow(dead_code)]
+    fn load(table: &[u8]) -> super::Aml<Memory> {
+        let mut aml = super::Aml::new(Memory::default());
+        aml.load_table(&table[36..], table[8]).unwrap();
+        aml
+    }
+
+    #[test_case]
+    fn methods_are_evaluated() {
+        use alloc::vec;
+        use super::{Object, Path};
+        let mut aml = load(include_bytes!("testdata/test.aml"));
+        let path = |s| Path::parse(s).unwrap();
+        assert_eq!(aml.sleep_type(5), Ok((5, 5)));
+        assert_eq!(aml.evaluate(&path("\\FIB"), vec![Object::Integer(10)]), Ok(Object::Integer(55)));
+        assert_eq!(aml.evaluate(&path("\\LOOP"), vec![Object::Integer(10)]), Ok(Object::Integer(18)));
+        assert_eq!(aml.evaluate(&path("\\STR"), vec![]), Ok(Object::String("AB0x1F".into())));
+        assert_eq!(aml.evaluate(&path("\\PKG"), vec![Object::Integer(1)]), Ok(Object::Integer(7)));
+        assert_eq!(aml.evaluate(&path("\\COND"), vec![]), Ok(Object::Integer(3)));
+        // メソッドの中で作った名前は抜けると消える
+        assert_eq!(aml.evaluate(&path("\\BUFM"), vec![]), Ok(Object::Buffer(vec![1, 0xbb, 0xaa, 4])));
+        assert_eq!(aml.get(&path("\\BUFM.B")), None);
+    }
+
+    #[test_case]
+    fn fields_access_hardware() {
+        use alloc::vec;
+        use super::{Object, Path};
+        let mut aml = load(include_bytes!("testdata/test.aml"));
+        let path = |s| Path::parse(s).unwrap();
+        aml.handler().write(0, 0xfed00004, 1, 0xa5);
+        aml.handler().write(0, 0xfed0000a, 1, 0x77);
+        assert_eq!(aml.evaluate_integer(&path("\\FLG")), Ok(0xa));
+        // FLGは4bit、WIDEはDWordAccの2単位にまたがる。まわりのビットはPreserveで残る
+        aml.evaluate(&path("\\SETF"), vec![Object::Integer(3), Object::Integer(0x12_3456_789a)]).unwrap();
+        assert_eq!(aml.handler().read(0, 0xfed00004, 8), 0x0077_1234_5678_9a35);
+        assert_eq!(aml.evaluate_integer(&path("\\WIDE")), Ok(0x12_3456_789a));
+        // IndexFieldはCIDXにオフセットを書いてからCDATを読む
+        aml.handler().write(1, 0x71, 1, 0x59);
+        assert_eq!(aml.evaluate_integer(&path("\\SEC2")), Ok(0x59));
+        assert_eq!(aml.handler().read(1, 0x70, 1), 2);
+    }
+
+    #[test_case]
+    fn pci_routing_follows_link_devices() {
+        use alloc::vec;
+        use super::{IrqSource, Path, PciAddress, PciRoute, Resource};
+        let mut aml = load(include_bytes!("testdata/test.aml"));
+        let path = |s| Path::parse(s).unwrap();
+        assert_eq!(aml.hardware_id(&path("\\_SB.PCI0")), Ok(Some("PNP0A08".into())));
+        assert_eq!(aml.status(&path("\\_SB.HPET")), Ok(0));
+        let routes = aml.pci_routing(&path("\\_SB.PCI0")).unwrap();
+        assert_eq!(routes[0], PciRoute { device: 1, pin: 0, source: IrqSource::Link { device: path("\\_SB.LNKA"), index: 0 } });
+        assert_eq!(routes[2].source, IrqSource::Gsi(0x10));
+        // LNKAの割り込み番号は00:00.0の0x60から読む
+        let host = PciAddress { segment: 0, bus: 0, device: 0, function: 0 };
+        aml.handler().write_pci(host, 0x60, 1, 0x0b);
+        assert_eq!(aml.link_irq(&path("\\_SB.LNKA"), 0), Ok(11));
+        assert_eq!(aml.resources(&path("\\_SB.LNKB")), Ok(vec![Resource::Interrupt { irqs: vec![11], edge_triggered: false, active_low: true, shared: true }]));
+        // ISAの_ADRは0x001F0000
+        let isa = PciAddress { device: 0x1f, ..host };
+        aml.handler().write_pci(isa, 0x81, 1, 0x42);
+        assert_eq!(aml.evaluate_integer(&path("\\_SB.PCI0.ISA.COMB")), Ok(0x42));
+    }
+
+    #[test_case]
+    fn firecracker_dsdt_is_loaded() {
+        use alloc::vec;
+        use super::{AddressKind, Error, IrqSource, Path, Resource};
+        // Firecrackerのゲストの/sys/firmware/acpi/tables/DSDT
+        let mut aml = load(include_bytes!("testdata/firecracker.aml"));
+        let path = |s| Path::parse(s).unwrap();
+        assert_eq!(aml.hardware_id(&path("\\_SB.PC00")), Ok(Some("PNP0A08".into())));
+        assert_eq!(aml.hardware_id(&path("\\_SB.COM1")), Ok(Some("PNP0501".into())));
+        let resources = aml.resources(&path("\\_SB.COM1")).unwrap();
+        assert_eq!(resources[1], Resource::Io { min: 0x3f8, max: 0x3f8, alignment: 1, length: 8 });
+        let resources = aml.resources(&path("\\_SB.PC00")).unwrap();
+        assert!(matches!(resources[0], Resource::Address { kind: AddressKind::BusNumber, min: 0, max: 0, .. }));
+        let routes = aml.pci_routing(&path("\\_SB.PC00")).unwrap();
+        assert_eq!(routes.len(), 32);
+        assert!(routes.iter().all(|r| matches!(r.source, IrqSource::Gsi(_))));
+        // VCLKの_STAはメソッド
+        assert_eq!(aml.status(&path("\\_SB.VCLK")), Ok(0xf));
+        assert_eq!(aml.resources(&path("\\_SB.GED")), Ok(vec![
+            Resource::Interrupt { irqs: vec![5], edge_triggered: true, active_low: false, shared: false },
+            Resource::Interrupt { irqs: vec![6], edge_triggered: true, active_low: false, shared: false },
+        ]));
+        // Firecrackerは\_S5を持たない
+        assert_eq!(aml.sleep_type(5), Err(Error::UndefinedName(path("\\_S5"))));
+    }
+}
@@ -0,0 +1,110 @@
+use core::fmt;
+
+use alloc::vec::Vec;
+
+pub type NameSeg = [u8; 4];
+
+// 名前空間の絶対パス。ルートは空
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Path(Vec<NameSeg>);
+
+impl Path {
+    pub fn root() -> Self {
+        Path(Vec::new())
+    }
+    // "\_SB.PCI0._PRT"のような表記から作る。4文字に満たないNameSegは'_'で埋める
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.strip_prefix('\\')?;
+        let mut path = Path::root();
+        if s.is_empty() {
+            return Some(path);
+        }
+        for seg in s.split('.') {
+            path.0.push(name_seg(seg.as_bytes())?);
+        }
+        Some(path)
+    }
+    pub fn segments(&self) -> &[NameSeg] {
+        &self.0
+    }
+    pub fn last(&self) -> Option<&NameSeg> {
+        self.0.last()
+    }
+    pub fn join(&self, seg: NameSeg) -> Self {
+        let mut path = self.clone();
+        path.0.push(seg);
+        path
+    }
+    pub fn parent(&self) -> Option<Self> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Path(parent.to_vec()))
+    }
+    pub fn is_parent_of(&self, other: &Path) -> bool {
+        other.0.len() == self.0.len() + 1 && other.0.starts_with(&self.0)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\\")?;
+        for (i, seg) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(core::str::from_utf8(seg).unwrap_or("????"))?;
+        }
+        Ok(())
+    }
+}
+
+fn is_lead_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c == b'_'
+}
+
+fn is_name_char(c: u8) -> bool {
+    is_lead_name_char(c) || c.is_ascii_digit()
+}
+
+pub fn name_seg(s: &[u8]) -> Option<NameSeg> {
+    if s.is_empty() || s.len() > 4 || !is_lead_name_char(s[0]) || !s.iter().all(|&c| is_name_char(c)) {
+        return None;
+    }
+    let mut seg = *b"____";
+    seg[..s.len()].copy_from_slice(s);
+    Some(seg)
+}
+
+// AMLに書かれている名前。ルートからか、何階層か上がったところから
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameString {
+    pub absolute: bool,
+    pub parents: usize,
+    pub segments: Vec<NameSeg>,
+}
+
+impl NameString {
+    // 1つのNameSegだけなら、見つかるまで親のスコープを探す
+    pub fn uses_search_rules(&self) -> bool {
+        !self.absolute && self.parents == 0 && self.segments.len() == 1
+    }
+    // scopeから見たパス。ルートより上に行こうとすればNone
+    pub fn resolve(&self, scope: &Path) -> Option<Path> {
+        let mut path = if self.absolute { Path::root() } else { scope.clone() };
+        for _ in 0..self.parents {
+            path = path.parent()?;
+        }
+        path.0.extend_from_slice(&self.segments);
+        Some(path)
+    }
+}
+
+pub const ROOT_CHAR: u8 = b'\\';
+pub const PARENT_PREFIX: u8 = b'^';
+pub const DUAL_NAME_PREFIX: u8 = 0x2e;
+pub const MULTI_NAME_PREFIX: u8 = 0x2f;
+pub const NULL_NAME: u8 = 0x00;
+
+// NameStringが始まるバイトか
+pub fn is_name_string_start(c: u8) -> bool {
+    is_lead_name_char(c) || matches!(c, ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX)
+}
@@ -0,0 +1,178 @@
+use core::fmt;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::name::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<Object>),
+    // パッケージの要素に書かれた名前(_PRTのリンクデバイスなど)やRefOfの結果
+    Reference(Path),
+    Method(Method),
+    OperationRegion(Region),
+    FieldUnit(FieldUnit),
+    BufferField(BufferField),
+    Alias(Path),
+    Device,
+    Scope,
+    Processor { id: u8 },
+    PowerResource,
+    ThermalZone,
+    Mutex,
+    Event,
+}
+
+impl Object {
+    pub fn as_integer(&self) -> Option<u64> {
+        match self {
+            Object::Integer(v) => Some(*v),
+            _ => None,
+        }
+    }
+    pub fn as_package(&self) -> Option<&[Object]> {
+        match self {
+            Object::Package(p) => Some(p),
+            _ => None,
+        }
+    }
+    // ObjectTypeで返す値
+    pub fn type_code(&self) -> u64 {
+        match self {
+            Object::Uninitialized => 0,
+            Object::Integer(_) => 1,
+            Object::String(_) => 2,
+            Object::Buffer(_) => 3,
+            Object::Package(_) => 4,
+            Object::FieldUnit(_) => 5,
+            Object::Device => 6,
+            Object::Event => 7,
+            Object::Method(_) => 8,
+            Object::Mutex => 9,
+            Object::OperationRegion(_) => 10,
+            Object::PowerResource => 11,
+            Object::Processor { .. } => 12,
+            Object::ThermalZone => 13,
+            Object::BufferField(_) => 14,
+            Object::Reference(_) | Object::Alias(_) | Object::Scope => 0,
+        }
+    }
+    fn type_name(&self) -> &'static str {
+        match self {
+            Object::Uninitialized => "Uninitialized",
+            Object::Integer(_) => "Integer",
+            Object::String(_) => "String",
+            Object::Buffer(_) => "Buffer",
+            Object::Package(_) => "Package",
+            Object::Reference(_) => "Reference",
+            Object::Method(_) => "Method",
+            Object::OperationRegion(_) => "OperationRegion",
+            Object::FieldUnit(_) => "FieldUnit",
+            Object::BufferField(_) => "BufferField",
+            Object::Alias(_) => "Alias",
+            Object::Device => "Device",
+            Object::Scope => "Scope",
+            Object::Processor { .. } => "Processor",
+            Object::PowerResource => "PowerResource",
+            Object::ThermalZone => "ThermalZone",
+            Object::Mutex => "Mutex",
+            Object::Event => "Event",
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::Integer(v) => write!(f, "{:#x}", v),
+            Object::String(s) => write!(f, "\"{}\"", s),
+            Object::Buffer(b) => {
+                f.write_str("Buffer {")?;
+                for (i, byte) in b.iter().enumerate() {
+                    write!(f, "{}{:02x}", if i == 0 { "" } else { " " }, byte)?;
+                }
+                f.write_str("}")
+            }
+            Object::Package(p) => {
+                f.write_str("Package {")?;
+                for (i, e) in p.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, e)?;
+                }
+                f.write_str("}")
+            }
+            Object::Reference(path) | Object::Alias(path) => write!(f, "{}", path),
+            Object::Method(m) => write!(f, "Method({} args)", m.arg_count),
+            other => f.write_str(other.type_name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub code: Arc<[u8]>,
+    pub arg_count: u8,
+}
+
+// 中身までは比べない
+impl PartialEq for Method {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.code, &other.code)
+    }
+}
+
+pub const REGION_SYSTEM_MEMORY: u8 = 0;
+pub const REGION_SYSTEM_IO: u8 = 1;
+pub const REGION_PCI_CONFIG: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub space: u8,
+    pub offset: u64,
+    pub length: u64,
+    // PCI_Configのときにアドレスを決めるデバイス
+    pub device: Path,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldKind {
+    Normal { region: Path },
+    // indexにバイトオフセットを書いてからdataを読み書きする
+    Index { index: Path, data: Path },
+    // bankにvalueを書いてからregionを読み書きする
+    Bank { region: Path, bank: Path, value: u64 },
+}
+
+pub const UPDATE_PRESERVE: u8 = 0;
+pub const UPDATE_WRITE_AS_ONES: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    // 1回に読み書きするバイト数
+    pub access_width: u64,
+    pub update_rule: u8,
+}
+
+// CreateDWordFieldなどの元になるバッファ
+// 引数やローカル変数なら、フィールドを作ったメソッドのものを指す
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldSource {
+    Name(Path),
+    Local(usize),
+    Arg(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferField {
+    pub source: FieldSource,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+}
@@ -0,0 +1,147 @@
+// _CRSなどが返すリソーステンプレート(バッファ)を読む
+
+use alloc::vec::Vec;
+
+use super::interpreter::{Error, Result};
+
+// 小さいリソースデータ
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_DMA: u8 = 0x05;
+const SMALL_IO: u8 = 0x08;
+const SMALL_FIXED_IO: u8 = 0x09;
+const SMALL_END_TAG: u8 = 0x0f;
+
+// 大きいリソースデータ
+const LARGE_MEMORY32: u8 = 0x05;
+const LARGE_MEMORY32_FIXED: u8 = 0x06;
+const LARGE_DWORD_ADDRESS: u8 = 0x07;
+const LARGE_WORD_ADDRESS: u8 = 0x08;
+const LARGE_EXTENDED_IRQ: u8 = 0x09;
+const LARGE_QWORD_ADDRESS: u8 = 0x0a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+    Memory,
+    Io,
+    BusNumber,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Interrupt { irqs: Vec<u32>, edge_triggered: bool, active_low: bool, shared: bool },
+    // チャネルのビットマスク
+    Dma { channels: u8 },
+    Io { min: u16, max: u16, alignment: u8, length: u8 },
+    FixedIo { base: u16, length: u8 },
+    // Memory32Fixedはmin == max
+    Memory { min: u64, max: u64, alignment: u64, length: u64, writable: bool },
+    Address { kind: AddressKind, min: u64, max: u64, translation: u64, length: u64 },
+    Other(u8),
+}
+
+fn le(bytes: &[u8]) -> u64 {
+    let mut v = [0u8; 8];
+    v[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(v)
+}
+
+// Granularity, Min, Max, Translation, Lengthがsizeバイトずつ並ぶ
+fn address(body: &[u8], size: usize) -> Result<Resource> {
+    if body.len() < 3 + size * 5 {
+        return Err(Error::UnexpectedEnd);
+    }
+    let kind = match body[0] {
+        0 => AddressKind::Memory,
+        1 => AddressKind::Io,
+        2 => AddressKind::BusNumber,
+        other => AddressKind::Other(other),
+    };
+    let field = |i: usize| le(&body[3 + size * i..3 + size * (i + 1)]);
+    Ok(Resource::Address { kind, min: field(1), max: field(2), translation: field(3), length: field(4) })
+}
+
+fn small(kind: u8, body: &[u8]) -> Result<Resource> {
+    let need = |n: usize| if body.len() < n { Err(Error::UnexpectedEnd) } else { Ok(()) };
+    Ok(match kind {
+        SMALL_IRQ => {
+            need(2)?;
+            let mask = le(&body[..2]) as u32;
+            let irqs = (0..16).filter(|i| mask & (1 << i) != 0).collect();
+            // フラグがなければエッジトリガ、アクティブハイ
+            let flags = body.get(2).copied().unwrap_or(0x01);
+            Resource::Interrupt { irqs, edge_triggered: flags & 0x01 != 0, active_low: flags & 0x08 != 0, shared: flags & 0x10 != 0 }
+        }
+        SMALL_DMA => {
+            need(1)?;
+            Resource::Dma { channels: body[0] }
+        }
+        SMALL_IO => {
+            need(7)?;
+            Resource::Io { min: le(&body[1..3]) as u16, max: le(&body[3..5]) as u16, alignment: body[5], length: body[6] }
+        }
+        SMALL_FIXED_IO => {
+            need(3)?;
+            Resource::FixedIo { base: le(&body[..2]) as u16, length: body[2] }
+        }
+        other => Resource::Other(other),
+    })
+}
+
+fn large(kind: u8, body: &[u8]) -> Result<Resource> {
+    let need = |n: usize| if body.len() < n { Err(Error::UnexpectedEnd) } else { Ok(()) };
+    Ok(match kind {
+        LARGE_MEMORY32 => {
+            need(17)?;
+            Resource::Memory {
+                min: le(&body[1..5]),
+                max: le(&body[5..9]),
+                alignment: le(&body[9..13]),
+                length: le(&body[13..17]),
+                writable: body[0] & 0x01 != 0,
+            }
+        }
+        LARGE_MEMORY32_FIXED => {
+            need(9)?;
+            let base = le(&body[1..5]);
+            Resource::Memory { min: base, max: base, alignment: 1, length: le(&body[5..9]), writable: body[0] & 0x01 != 0 }
+        }
+        LARGE_WORD_ADDRESS => address(body, 2)?,
+        LARGE_DWORD_ADDRESS => address(body, 4)?,
+        LARGE_QWORD_ADDRESS => address(body, 8)?,
+        LARGE_EXTENDED_IRQ => {
+            need(2)?;
+            let flags = body[0];
+            let count = body[1] as usize;
+            need(2 + count * 4)?;
+            let irqs = body[2..2 + count * 4].chunks_exact(4).map(|c| le(c) as u32).collect();
+            Resource::Interrupt { irqs, edge_triggered: flags & 0x02 != 0, active_low: flags & 0x04 != 0, shared: flags & 0x08 != 0 }
+        }
+        other => Resource::Other(0x80 | other),
+    })
+}
+
+pub fn parse_resources(buffer: &[u8]) -> Result<Vec<Resource>> {
+    let mut resources = Vec::new();
+    let mut pos = 0;
+    while pos < buffer.len() {
+        let tag = buffer[pos];
+        let (kind, body_start, len) = if tag & 0x80 == 0 {
+            ((tag >> 3) & 0x0f, pos + 1, (tag & 0x07) as usize)
+        } else {
+            let len = buffer.get(pos + 1..pos + 3).ok_or(Error::UnexpectedEnd)?;
+            (tag & 0x7f, pos + 3, le(len) as usize)
+        };
+        let body = buffer.get(body_start..body_start + len).ok_or(Error::UnexpectedEnd)?;
+        if tag & 0x80 == 0 {
+            if kind == SMALL_END_TAG {
+                break;
+            }
+            resources.push(small(kind, body)?);
+        } else {
+            resources.push(large(kind, body)?);
+        }
+        pos = body_start + len;
+    }
+    Ok(resources)
+}
@@ -1,5 +1,8 @@
-use core::{ffi::{CStr, c_void}, ptr::null, sync::atomic::{AtomicBool, AtomicPtr}};
+use core::{ffi::{CStr, c_void}, fmt::{self, Display}, ptr::null, sync::atomic::{AtomicBool, AtomicPtr}};
 
+use common::symbols::{SYMBOL_TABLE_CAPACITY, SymbolTable};
+
+use crate::dwarf::{LineInfo, find_line};
 use crate::serial_println;
 
 static LOCK: AtomicBool = AtomicBool::new(false);
@@ -8,6 +11,38 @@ static mut SYMTAB_PTR: *const Elf64Sym = null();
 static mut SYMTAB_NUM: usize = 0;
 static mut STRTAB_PTR: *const i8 = null();
 static mut BASE: usize = 0;
+static mut DEBUG_LINE: &[u8] = &[];
+
+// build.rsが作ったシンボルテーブル(common/src/symbols.rs)
+// ローダに依存せず、ローダのメモリを解放した後でも引ける
+#[repr(C, align(8))]
+struct EmbeddedSymbols([u8; SYMBOL_TABLE_CAPACITY]);
+
+#[used]
+#[unsafe(link_section = ".kernel_symbols")]
+static KERNEL_SYMBOLS: EmbeddedSymbols = EmbeddedSymbols(*include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin")));
+
+// {:#}でハッシュを落として出す
+struct Demangled<'a>(rustc_demangle::Demangle<'a>);
+
+impl Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+// テーブルと、そこから求めたカーネルのベースアドレス
+// テスト用のバイナリなどでレイアウトが合わなければNone
+fn embedded_symbols() -> Option<(SymbolTable<'static>, usize)> {
+    let table = SymbolTable::from_bytes(&KERNEL_SYMBOLS.0)?;
+    let base = (&raw const KERNEL_SYMBOLS).addr().wrapping_sub(table.header.self_offset as usize);
+    // 自分自身が正しく引けるかで確かめる
+    let (name, offset) = table.lookup((print_backtrace as fn() as usize).wrapping_sub(base) as u64)?;
+    if offset != 0 || !name.ends_with("backtrace::print_backtrace") {
+        return None;
+    }
+    Some((table, base))
+}
 
 #[derive(Debug)]
 #[repr(C)]
@@ -21,9 +56,10 @@ struct Elf64Sym {
 }
 
 // パラメータがすべて正当であること
-pub unsafe fn init_backtrace(base: usize, symtab_ptr: *const c_void, symtab_num: usize, strtab_ptr: *const c_void) {
+// symtabとdebug_lineはなければnull
+pub unsafe fn init_backtrace(base: usize, symtab_ptr: *const c_void, symtab_num: usize, strtab_ptr: *const c_void, debug_line: *const u8, debug_line_len: usize) {
     // 最低限のassertをしておく
-    if base == 0 || symtab_ptr.is_null() || symtab_num == 0 || strtab_ptr.is_null() {
+    if base == 0 {
         return;
     }
 
@@ -31,19 +67,71 @@ pub unsafe fn init_backtrace(base: usize, symtab_ptr: *const c_void, symtab_num:
     if LOCK.compare_exchange_weak(false, true, core::sync::atomic::Ordering::SeqCst, core::sync::atomic::Ordering::SeqCst).is_ok() {
         unsafe {
             BASE = base;
-            SYMTAB_PTR = symtab_ptr as *const Elf64Sym;
-            SYMTAB_NUM = symtab_num;
-            STRTAB_PTR = strtab_ptr as *const i8;
+            if !symtab_ptr.is_null() && symtab_num != 0 && !strtab_ptr.is_null() {
+                SYMTAB_PTR = symtab_ptr as *const Elf64Sym;
+                SYMTAB_NUM = symtab_num;
+                STRTAB_PTR = strtab_ptr as *const i8;
+            }
+            if !debug_line.is_null() {
+                DEBUG_LINE = core::slice::from_raw_parts(debug_line, debug_line_len);
+            }
         }
         INITALIZED.store(true, core::sync::atomic::Ordering::Release);
     }
 }
 
-fn print_fn_name(rip: u64) {
-    unsafe {
-        if !INITALIZED.load(core::sync::atomic::Ordering::Acquire) {
-            return;
+// offsetはカーネルイメージ先頭からのオフセット
+fn line_info(offset: usize) -> Option<LineInfo<'static>> {
+    if !INITALIZED.load(core::sync::atomic::Ordering::Acquire) {
+        return None;
+    }
+    let debug_line = unsafe { DEBUG_LINE };
+    // 行番号0はコンパイラが作ったコードなど、対応する行がないもの
+    find_line(debug_line, offset as u64).filter(|info| info.line != 0)
+}
+
+pub struct Frame<'a> {
+    pub return_address: u64,
+    // カーネルイメージ先頭からのオフセット
+    pub offset: usize,
+    // シンボルが見つからなければNone
+    pub name: Option<&'a dyn Display>,
+    pub line: Option<LineInfo<'static>>,
+}
+
+impl<'a> Frame<'a> {
+    fn new(return_address: u64, base: usize, name: Option<&'a dyn Display>) -> Self {
+        let offset = (return_address as usize).wrapping_sub(base);
+        // 戻りアドレスはcallの次の命令を指すので、一つ戻してから引く
+        let line = name.and_then(|_| line_info(offset.wrapping_sub(1)));
+        Frame { return_address, offset, name, line }
+    }
+}
+
+// KASLRで毎回変わるので、ELF上のアドレスに戻すのに使う
+pub fn kernel_base() -> Option<usize> {
+    if let Some((_, base)) = embedded_symbols() {
+        Some(base)
+    } else if INITALIZED.load(core::sync::atomic::Ordering::Acquire) {
+        Some(unsafe { BASE })
+    } else {
+        None
+    }
+}
+
+fn resolve(rip: u64, f: &mut dyn FnMut(&Frame)) {
+    if let Some((table, base)) = embedded_symbols() {
+        let offset = (rip as usize).wrapping_sub(base);
+        match table.lookup(offset as u64) {
+            Some((name, _)) => f(&Frame::new(rip, base, Some(&name))),
+            None => f(&Frame::new(rip, base, None)),
         }
+        return;
+    }
+
+    // 埋め込みのテーブルが使えなければブートローダが渡したsymtabを線形探索する
+    unsafe {
+        let base = if INITALIZED.load(core::sync::atomic::Ordering::Acquire) { BASE } else { 0 };
 
         // 別にパフォーマンスは気にしないのでループを回す
         for i in 0..SYMTAB_NUM {
@@ -51,26 +139,43 @@ fn print_fn_name(rip: u64) {
             let value = sym.value as usize;
             let size = sym.size as usize;
             let rip = rip as usize;
-            if BASE + value <= rip && rip < BASE + value + size {
-                serial_println!("Function address: {:x}", rip - BASE);
-                let str = CStr::from_ptr(STRTAB_PTR.add(sym.name as usize));
-                // マングリングはあきらめる
-                serial_println!("         name   : {:?}", str);
-                break;
+            if base + value <= rip && rip < base + value + size {
+                let name = Demangled(rustc_demangle::demangle(CStr::from_ptr(STRTAB_PTR.add(sym.name as usize)).to_str().unwrap_or("?")));
+                f(&Frame::new(rip as u64, base, Some(&name)));
+                return;
             }
         }
+        f(&Frame::new(rip, base, None));
     }
 }
 
-pub fn print_backtrace() {
+// rbpをたどって、呼び出し元から順にfを呼ぶ
+#[inline(never)]
+pub fn walk_stack(mut f: impl FnMut(&Frame)) {
     unsafe {
         let mut rbp: *const u64;
         core::arch::asm!("mov {}, rbp", out(reg) rbp);
-
-        while *rbp != 0 {
+        while !rbp.is_null() && *rbp != 0 {
             let ret_addr = *rbp.offset(1);
-            print_fn_name(ret_addr);
+            resolve(ret_addr, &mut f);
             rbp = *rbp as *const u64;
         }
     }
 }
+
+pub fn print_backtrace() {
+    if let Some(base) = kernel_base() {
+        serial_println!("Kernel base: {:x}", base);
+    }
+    walk_stack(|frame| {
+        let Some(name) = frame.name else {
+            return;
+        };
+        serial_println!("Function address: {:x}", frame.offset);
+        // kernel::usb::trb::TransferEventTRB::on_event (trb.rs:201) のように出す
+        match frame.line {
+            Some(info) => serial_println!("         name   : {} ({}:{})", name, info.file, info.line),
+            None => serial_println!("         name   : {}", name),
+        }
+    });
+}
@@ -0,0 +1,56 @@
+use common::boot_config::BootConfig;
+use conquer_once::spin::OnceCell;
+use log::LevelFilter;
+
+use crate::logger::LogFilter;
+
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Warn;
+
+static BOOT_CONFIG: OnceCell<BootConfig<'static>> = OnceCell::uninit();
+
+// safety: ptrがnullか、len byteの領域を指していて以降書き換えられないこと
+pub unsafe fn init_boot_config(ptr: *const u8, len: usize) {
+    let text = if ptr.is_null() {
+        ""
+    } else {
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    };
+    if BOOT_CONFIG.try_init_once(|| BootConfig::parse(text)).is_err() {
+        log::warn!("boot config is already initialized");
+    }
+}
+
+// 初期化前や設定ファイルがない場合は既定値を返す
+pub fn boot_config() -> BootConfig<'static> {
+    BOOT_CONFIG.get().copied().unwrap_or_default()
+}
+
+// log=warn,kernel::usb=trace のようにモジュールごとにも指定できる
+pub fn log_filter() -> LogFilter {
+    let Some(value) = boot_config().log else {
+        return LogFilter::new(DEFAULT_LOG_LEVEL);
+    };
+    LogFilter::parse(value).unwrap_or_else(|item| {
+        log::warn!("invalid log filter: {}", item);
+        LogFilter::new(DEFAULT_LOG_LEVEL)
+    })
+}
+
+// test=<name>が指定されていれば、名前にそれを含むテストだけを実行する
+pub fn test_filter() -> Option<&'static str> {
+    boot_config().test
+}
+
+// gdb=com1またはcom2で、そのシリアルポートでGDBのスタブを動かす
+pub fn gdb_port() -> Option<u16> {
+    match boot_config().gdb? {
+        "com1" => Some(0x3f8),
+        "com2" => Some(0x2f8),
+        "off" => None,
+        value => {
+            log::warn!("unknown gdb port: {}", value);
+            None
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// パニック時のクラッシュダンプ
+// シリアルには次のような枠で囲んで出し、tools/crash_dump.pyで整形して読む
+//
+//   ===== BEGIN CRASH DUMP v1 =====
+//   @panic
+//   message=...
+//   @backtrace
+//   ffffffff80012345	12345	kernel::main	main.rs:120
+//   ===== END CRASH DUMP length=1234 crc32=89abcdef =====
+//
+// "@名前"の行から次の"@"までが一つのセクション
+// panic, registers, memoryは"key=value"、backtrace, log, tasksはタブ区切りの行の並び
+// lengthとcrc32はBEGINの次の行からENDの直前までについて計算する
+// 値の中の'\\', '\n', '\t'は"\\\\", "\\n", "\\t"に置き換えるので、枠や区切りは崩れない
+//
+// パニック中に使うのでアロケートしない
+
+use core::arch::asm;
+use core::fmt::{self, Display, Write};
+use core::panic::PanicInfo;
+
+use conquer_once::spin::OnceCell;
+
+pub const CRASH_DUMP_VERSION: u32 = 1;
+const DUMP_BUFFER_SIZE: usize = 64 * 1024;
+
+// ストレージのドライバが登録すれば、ダンプをファイルにも書き出す
+pub type DumpStorage = fn(name: &str, data: &[u8]) -> Result<(), crate::error::Error>;
+
+static STORAGE: OnceCell<DumpStorage> = OnceCell::uninit();
+
+pub fn register_storage(storage: DumpStorage) {
+    if STORAGE.try_init_once(|| storage).is_err() {
+        log::warn!("crash dump storage is already registered");
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    // 呼び出した場所での値。パニックハンドラの先頭で呼ぶ
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut r = Registers::default();
+        unsafe {
+            // ポインタを入れたレジスタの値はそのポインタになる
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                in(reg) &raw mut r,
+                options(nostack, preserves_flags),
+            );
+            asm!("lea {}, [rip]", out(reg) r.rip, options(nomem, nostack, preserves_flags));
+            asm!("pushfq", "pop {}", out(reg) r.rflags, options(nomem, preserves_flags));
+            asm!("mov {:x}, cs", out(reg) r.cs, options(nomem, nostack, preserves_flags));
+            asm!("mov {:x}, ss", out(reg) r.ss, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr0", out(reg) r.cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr2", out(reg) r.cr2, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr3", out(reg) r.cr3, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) r.cr4, options(nomem, nostack, preserves_flags));
+        }
+        r.cs &= 0xffff;
+        r.ss &= 0xffff;
+        r
+    }
+
+    fn fields(&self) -> [(&'static str, u64); 24] {
+        [
+            ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx), ("rdx", self.rdx),
+            ("rsi", self.rsi), ("rdi", self.rdi), ("rbp", self.rbp), ("rsp", self.rsp),
+            ("r8", self.r8), ("r9", self.r9), ("r10", self.r10), ("r11", self.r11),
+            ("r12", self.r12), ("r13", self.r13), ("r14", self.r14), ("r15", self.r15),
+            ("rip", self.rip), ("rflags", self.rflags), ("cs", self.cs), ("ss", self.ss),
+            ("cr0", self.cr0), ("cr2", self.cr2), ("cr3", self.cr3), ("cr4", self.cr4),
+        ]
+    }
+}
+
+// ENDの行の分は空けておく
+const FOOTER_RESERVED: usize = 128;
+
+// limitを越えた分は捨てる
+struct DumpBuffer {
+    buf: [u8; DUMP_BUFFER_SIZE],
+    len: usize,
+    limit: usize,
+    truncated: bool,
+}
+
+impl Write for DumpBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.limit - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.truncated |= n < s.len();
+        Ok(())
+    }
+}
+
+static mut DUMP_BUFFER: DumpBuffer = DumpBuffer { buf: [0; DUMP_BUFFER_SIZE], len: 0, limit: 0, truncated: false };
+
+// 区切りに使う文字を逃がして書く
+struct Escaped<T>(T);
+
+impl<T: Display> Display for Escaped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct EscapeWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
+        impl Write for EscapeWriter<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '\\' => self.0.write_str("\\\\")?,
+                        '\n' => self.0.write_str("\\n")?,
+                        '\t' => self.0.write_str("\\t")?,
+                        c => self.0.write_char(c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+        write!(EscapeWriter(f), "{}", self.0)
+    }
+}
+
+fn write_sections(w: &mut impl Write, info: &PanicInfo, regs: &Registers) -> fmt::Result {
+    writeln!(w, "@panic")?;
+    writeln!(w, "message={}", Escaped(info.message()))?;
+    if let Some(location) = info.location() {
+        writeln!(w, "location={}:{}:{}", Escaped(location.file()), location.line(), location.column())?;
+    }
+    writeln!(w, "tick={}", crate::timer::get_tick())?;
+    writeln!(w, "time={}", crate::time::Instant::now())?;
+    if let Some(dt) = crate::rtc::now() {
+        writeln!(w, "date={}", dt)?;
+    }
+    if let Some(base) = crate::backtrace::kernel_base() {
+        writeln!(w, "kernel_base={:x}", base)?;
+    }
+
+    writeln!(w, "@registers")?;
+    for (name, value) in regs.fields() {
+        writeln!(w, "{}={:016x}", name, value)?;
+    }
+
+    // 戻りアドレス, オフセット, 関数名, ファイル:行
+    writeln!(w, "@backtrace")?;
+    let mut result = Ok(());
+    crate::backtrace::walk_stack(|frame| {
+        if result.is_err() {
+            return;
+        }
+        result = (|| {
+            write!(w, "{:016x}\t{:x}\t", frame.return_address, frame.offset)?;
+            match frame.name {
+                Some(name) => write!(w, "{}", Escaped(name))?,
+                None => w.write_str("?")?,
+            }
+            w.write_char('\t')?;
+            if let Some(line) = frame.line {
+                write!(w, "{}:{}", Escaped(line.file), line.line)?;
+            }
+            w.write_char('\n')
+        })();
+    });
+    result?;
+
+    // 時刻(秒), レベル, モジュール, メッセージ
+    writeln!(w, "@log")?;
+    crate::logger::try_for_each_recent(crate::panic::PANIC_LOG_ENTRIES, |entry| {
+        if result.is_ok() {
+            result = writeln!(w, "{}\t{}\t{}\t{}", entry.time, entry.level, Escaped(&entry.module), Escaped(&entry.message));
+        }
+    });
+    result?;
+
+    // ID, poll回数, 名前
+    writeln!(w, "@tasks")?;
+    crate::task::try_for_each_task(|task| {
+        if result.is_ok() {
+            result = writeln!(w, "{}\t{}\t{}", task.id, task.polls, Escaped(task.name));
+        }
+    });
+    result?;
+
+    writeln!(w, "@memory")?;
+    if let Some(manager) = crate::memory_manager::MANAGER.try_lock() {
+        let stats = manager.stats();
+        writeln!(w, "frames_total={}", stats.total)?;
+        writeln!(w, "frames_allocated={}", stats.allocated)?;
+        writeln!(w, "bytes_per_frame={}", crate::memory_manager::BYTES_PER_FRAME)?;
+    }
+    if let Some(heap) = crate::allocator::heap_usage() {
+        writeln!(w, "heap_total={}", heap.total)?;
+        writeln!(w, "heap_used={}", heap.used)?;
+    }
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// safety: 割り込みを止めたパニック処理の中から一度だけ呼ぶこと
+pub unsafe fn write_crash_dump(info: &PanicInfo, regs: &Registers) {
+    let dump = unsafe { &mut *(&raw mut DUMP_BUFFER) };
+    dump.len = 0;
+    dump.limit = DUMP_BUFFER_SIZE - FOOTER_RESERVED;
+    dump.truncated = false;
+
+    let _ = writeln!(dump, "===== BEGIN CRASH DUMP v{} =====", CRASH_DUMP_VERSION);
+    let body_start = dump.len;
+    let _ = write_sections(dump, info, regs);
+    if dump.truncated {
+        // 途中で切れた行を閉じておく
+        dump.limit = DUMP_BUFFER_SIZE - FOOTER_RESERVED / 2;
+        let _ = writeln!(dump, "\n@truncated");
+    }
+    let body = &dump.buf[body_start..dump.len];
+    let (length, crc) = (body.len(), crc32(body));
+    dump.limit = DUMP_BUFFER_SIZE;
+    let _ = writeln!(dump, "===== END CRASH DUMP length={} crc32={:08x} =====", length, crc);
+
+    let data = &dump.buf[..dump.len];
+    unsafe { crate::serial::write_bytes_unlocked(data); }
+
+    if let Some(storage) = STORAGE.get() {
+        let mut name: heapless::String<32> = heapless::String::new();
+        let _ = match crate::rtc::now() {
+            Some(dt) => write!(name, "crash-{:04}{:02}{:02}-{:02}{:02}{:02}.txt", dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second),
+            None => write!(name, "crash-{}.txt", crate::timer::get_tick()),
+        };
+        // ファイルにも枠ごと書いて、ツールでそのまま読めるようにする
+        if let Err(e) = storage(&name, data) {
+            crate::serial_println!("failed to write crash dump: {}", e);
+        }
+    }
+}
+
+mod test {
+    #[test_case]
+    fn crc32_matches_reference() {
+        assert_eq!(super::crc32(b""), 0);
+        assert_eq!(super::crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test_case]
+    fn escaped_keeps_frame_intact() {
+        use core::fmt::Write;
+        let mut s: heapless::String<32> = heapless::String::new();
+        write!(s, "{}", super::Escaped("a\tb\nc\\d")).unwrap();
+        assert_eq!(s.as_str(), "a\\tb\\nc\\\\d");
+    }
+}
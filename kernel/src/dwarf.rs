@@ -0,0 +1,353 @@
+// .debug_lineからアドレスに対応するファイル名と行番号を引く(DWARF 2〜5)
+// バックトレースでしか使わないので、毎回先頭から行番号プログラムを実行する
+// v5でファイル名が.debug_line_strにある場合は、そのセクションを持っていないので名前はわからない
+
+#[derive(Debug, Clone, Copy)]
+pub struct LineInfo<'a> {
+    // ディレクトリを除いたファイル名
+    pub file: &'a str,
+    pub line: u64,
+}
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+const DW_LNCT_PATH: u64 = 1;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+#[derive(Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let s = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(s)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+    fn offset(&mut self, offset_size: usize) -> Option<u64> {
+        if offset_size == 8 { self.u64() } else { self.u32().map(|v| v as u64) }
+    }
+    fn uleb(&mut self) -> Option<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+    fn sleb(&mut self) -> Option<i64> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let s = self.bytes(len)?;
+        self.pos += 1;
+        Some(s)
+    }
+}
+
+struct LineProgramHeader<'a> {
+    version: u16,
+    offset_size: usize,
+    min_inst_length: u8,
+    line_base: i8,
+    line_range: u8,
+    opcode_base: u8,
+    standard_opcode_lengths: &'a [u8],
+    // v4まではファイル名の並び、v5はエントリの形式から始まる並び
+    file_names: Reader<'a>,
+}
+
+impl<'a> LineProgramHeader<'a> {
+    // 行番号プログラムの本体を返す
+    fn parse(unit: &mut Reader<'a>, offset_size: usize) -> Option<(Self, Reader<'a>)> {
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            // address_size, segment_selector_size
+            unit.bytes(2)?;
+        }
+        let header_length = unit.offset(offset_size)? as usize;
+        let program = Reader::new(unit.data.get(unit.pos.checked_add(header_length)?..)?);
+        let min_inst_length = unit.u8()?;
+        if version >= 4 {
+            // maximum_operations_per_instruction(VLIWでなければ1)
+            unit.u8()?;
+        }
+        let _default_is_stmt = unit.u8()?;
+        let line_base = unit.u8()? as i8;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return None;
+        }
+        let standard_opcode_lengths = unit.bytes(opcode_base as usize - 1)?;
+        if version < 5 {
+            // include_directories
+            while !unit.cstr()?.is_empty() {}
+        } else {
+            // directoryの表は使わないので読み飛ばす
+            let formats = unit.clone();
+            let format_count = unit.u8()?;
+            for _ in 0..format_count as u32 * 2 {
+                unit.uleb()?;
+            }
+            let count = unit.uleb()?;
+            for _ in 0..count {
+                read_entry(unit, formats.clone(), offset_size)?;
+            }
+        }
+        let header = LineProgramHeader {
+            version,
+            offset_size,
+            min_inst_length,
+            line_base,
+            line_range,
+            opcode_base,
+            standard_opcode_lengths,
+            file_names: unit.clone(),
+        };
+        Some((header, program))
+    }
+
+    fn file_name(&self, index: u64) -> Option<&'a str> {
+        let mut r = self.file_names.clone();
+        let name = if self.version < 5 {
+            // 1始まり
+            let mut name = None;
+            for _ in 0..index {
+                let n = r.cstr()?;
+                if n.is_empty() {
+                    return None;
+                }
+                r.uleb()?;
+                r.uleb()?;
+                r.uleb()?;
+                name = Some(n);
+            }
+            name?
+        } else {
+            // 0始まり
+            let formats = r.clone();
+            let format_count = r.u8()?;
+            for _ in 0..format_count as u32 * 2 {
+                r.uleb()?;
+            }
+            let count = r.uleb()?;
+            if index >= count {
+                return None;
+            }
+            for _ in 0..index {
+                read_entry(&mut r, formats.clone(), self.offset_size)?;
+            }
+            read_entry(&mut r, formats, self.offset_size)??
+        };
+        let name = core::str::from_utf8(name).ok()?;
+        Some(name.rsplit('/').next().unwrap_or(name))
+    }
+}
+
+// v5のエントリを一つ読み、DW_LNCT_pathがその場にある文字列ならそれを返す
+fn read_entry<'a>(r: &mut Reader<'a>, mut formats: Reader<'a>, offset_size: usize) -> Option<Option<&'a [u8]>> {
+    let format_count = formats.u8()?;
+    let mut path = None;
+    for _ in 0..format_count {
+        let content_type = formats.uleb()?;
+        let form = formats.uleb()?;
+        match form {
+            DW_FORM_STRING => {
+                let s = r.cstr()?;
+                if content_type == DW_LNCT_PATH {
+                    path = Some(s);
+                }
+            }
+            DW_FORM_STRP | DW_FORM_LINE_STRP => {
+                r.offset(offset_size)?;
+            }
+            DW_FORM_DATA1 => {
+                r.bytes(1)?;
+            }
+            DW_FORM_DATA2 => {
+                r.bytes(2)?;
+            }
+            DW_FORM_DATA4 => {
+                r.bytes(4)?;
+            }
+            DW_FORM_DATA8 => {
+                r.bytes(8)?;
+            }
+            DW_FORM_DATA16 => {
+                r.bytes(16)?;
+            }
+            DW_FORM_UDATA => {
+                r.uleb()?;
+            }
+            DW_FORM_BLOCK => {
+                let len = r.uleb()? as usize;
+                r.bytes(len)?;
+            }
+            DW_FORM_BLOCK1 => {
+                let len = r.u8()? as usize;
+                r.bytes(len)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+#[derive(Clone, Copy)]
+struct Row {
+    address: u64,
+    file: u64,
+    line: u64,
+}
+
+// 見つかればSome(Some(..))、このユニットになければSome(None)、壊れていればNone
+fn find_in_program(header: &LineProgramHeader, mut program: Reader, addr: u64) -> Option<Option<(u64, u64)>> {
+    let initial = Row { address: 0, file: 1, line: 1 };
+    let mut row = initial;
+    let mut prev: Option<Row> = None;
+    // 行が一つ増えるたびに、直前の行がaddrを含んでいたかを確かめる
+    let emit = |row: Row, prev: &mut Option<Row>| {
+        let found = prev.filter(|p| p.address <= addr && addr < row.address).map(|p| (p.file, p.line));
+        *prev = Some(row);
+        found
+    };
+
+    while !program.is_empty() {
+        let opcode = program.u8()?;
+        if opcode >= header.opcode_base {
+            let adjusted = opcode - header.opcode_base;
+            row.address = row.address.wrapping_add((adjusted / header.line_range) as u64 * header.min_inst_length as u64);
+            row.line = row.line.wrapping_add_signed(header.line_base as i64 + (adjusted % header.line_range) as i64);
+            if let Some(found) = emit(row, &mut prev) {
+                return Some(Some(found));
+            }
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = program.uleb()? as usize;
+                let mut ext = Reader::new(program.bytes(len)?);
+                match ext.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        if let Some(found) = emit(row, &mut prev) {
+                            return Some(Some(found));
+                        }
+                        row = initial;
+                        prev = None;
+                    }
+                    DW_LNE_SET_ADDRESS => {
+                        row.address = match len - 1 {
+                            8 => ext.u64()?,
+                            4 => ext.u32()? as u64,
+                            _ => return None,
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            DW_LNS_COPY => {
+                if let Some(found) = emit(row, &mut prev) {
+                    return Some(Some(found));
+                }
+            }
+            DW_LNS_ADVANCE_PC => row.address = row.address.wrapping_add(program.uleb()?.wrapping_mul(header.min_inst_length as u64)),
+            DW_LNS_ADVANCE_LINE => row.line = row.line.wrapping_add_signed(program.sleb()?),
+            DW_LNS_SET_FILE => row.file = program.uleb()?,
+            DW_LNS_CONST_ADD_PC => {
+                row.address = row.address.wrapping_add(((255 - header.opcode_base) / header.line_range) as u64 * header.min_inst_length as u64);
+            }
+            DW_LNS_FIXED_ADVANCE_PC => row.address = row.address.wrapping_add(program.u16()? as u64),
+            _ => {
+                // 引数の数だけLEB128を読み飛ばす
+                for _ in 0..*header.standard_opcode_lengths.get(opcode as usize - 1)? {
+                    program.uleb()?;
+                }
+            }
+        }
+    }
+    Some(None)
+}
+
+// addrはリンク時のアドレス(カーネルイメージ先頭からのオフセット)
+pub fn find_line(debug_line: &[u8], addr: u64) -> Option<LineInfo<'_>> {
+    let mut r = Reader::new(debug_line);
+    while !r.is_empty() {
+        let (unit_length, offset_size) = match r.u32()? {
+            0xffff_ffff => (r.u64()? as usize, 8),
+            len => (len as usize, 4),
+        };
+        let mut unit = Reader::new(r.bytes(unit_length)?);
+        let Some((header, program)) = LineProgramHeader::parse(&mut unit, offset_size) else {
+            continue;
+        };
+        if let Some(Some((file, line))) = find_in_program(&header, program, addr) {
+            return Some(LineInfo { file: header.file_name(file).unwrap_or("?"), line });
+        }
+    }
+    None
+}
@@ -22,12 +22,22 @@ macro_rules! entry {
             static STACK: SyncUnsafeCell<MaybeUninit<Stack>> = SyncUnsafeCell::new(MaybeUninit::uninit());
 
             naked_asm!(
+                // UEFIのローダからは64bitモードで、GRUB(Multiboot2)からは32bitモードでここに来る
+                // 0x41は32bitではinc ecx、64bitではclcに付くREXプレフィックスとして無視されるので、
+                // ecxが0のままなら64bitモード
+                "xor ecx, ecx",
+                ".byte 0x41",
+                "clc",
+                "jrcxz 3f",
+                "jmp {}",
+                "3:",
                 "mov rbp, 0",  // rbp == 0ならスタックフレームの終了
                 "lea rsp, {}[{} + rip]",
                 "call {}",
                 "2: cli",
                 "hlt",
                 "jmp 2b",
+                sym $crate::multiboot2::multiboot2_entry32,
                 sym STACK,
                 const STACK_SIZE,
                 sym $p,
@@ -35,3 +45,18 @@ macro_rules! entry {
         }
     };
 }
+
+// ブートローダから渡された情報が解釈できるものかを確かめる
+// 合わない場合は、可能ならパニック画面に出してから止まる
+pub unsafe fn check_config(config: *const common::Config) -> &'static common::Config {
+    match unsafe { common::Config::validate(config) } {
+        Ok(()) => unsafe { &*config },
+        Err(e) => {
+            crate::serial::init_serial();
+            if e.frame_buffer_usable() {
+                unsafe { crate::panic::init_default_panic_print(&raw const (*config).frame_buffer_config); }
+            }
+            panic!("{}", e);
+        }
+    }
+}
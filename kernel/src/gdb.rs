@@ -0,0 +1,673 @@
+// GDBのリモートシリアルプロトコル(RSP)のスタブ
+// boot.cfgでgdb=com2(かcom1)を指定すると有効になり、起動時にブレークポイントで止まって接続を待つ
+//
+//   qemu ... -serial stdio -serial tcp::1234,server,nowait
+//   (gdb) file target/x86_64-unknown-none/debug/kernel
+//   (gdb) target remote :1234
+//
+// int3(ブレークポイント例外)と、TFによるシングルステップ(デバッグ例外)で止まる
+// パニックしたときも止まるが、そこからは再開できない
+// 止まっている間は割り込みを止めたまま、シリアルをポーリングして読み書きする
+// カーネルの配置はランダムなので、qOffsetsでずらす量を伝える
+
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::interrupt::{ExceptionVector, InterruptFrame};
+use crate::io_port::{inb, outb};
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const RFLAGS_TF: u64 = 1 << 8;
+const CR0_WP: u64 = 1 << 16;
+
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+// 例外の入口で積むレジスタ。InterruptFrameの直前にpushする
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub frame: InterruptFrame,
+}
+
+macro_rules! trap_entry {
+    ($name:ident, $vector:expr) => {
+        // 例外の時点でrspは16byte境界から5つ積んだところにあり、15個積むと境界に戻る
+        #[unsafe(naked)]
+        pub unsafe extern "sysv64" fn $name() {
+            naked_asm!(
+                "push r15",
+                "push r14",
+                "push r13",
+                "push r12",
+                "push r11",
+                "push r10",
+                "push r9",
+                "push r8",
+                "push rbp",
+                "push rdi",
+                "push rsi",
+                "push rdx",
+                "push rcx",
+                "push rbx",
+                "push rax",
+                "mov rdi, rsp",
+                "mov esi, {vector}",
+                "cld",
+                "call {handler}",
+                "pop rax",
+                "pop rbx",
+                "pop rcx",
+                "pop rdx",
+                "pop rsi",
+                "pop rdi",
+                "pop rbp",
+                "pop r8",
+                "pop r9",
+                "pop r10",
+                "pop r11",
+                "pop r12",
+                "pop r13",
+                "pop r14",
+                "pop r15",
+                "iretq",
+                vector = const $vector as u32,
+                handler = sym handle_trap,
+            )
+        }
+    };
+}
+
+trap_entry!(int_handler_debug, ExceptionVector::Debug);
+trap_entry!(int_handler_breakpoint, ExceptionVector::Breakpoint);
+
+static PORT: AtomicU16 = AtomicU16::new(0);
+// 一度でもパケットを受け取っていれば、GDBは止まった理由が来るのを待っている
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+// 入れたブレークポイントのアドレスと元の1byte
+static mut BREAKPOINTS: [Option<(u64, u8)>; MAX_BREAKPOINTS] = [None; MAX_BREAKPOINTS];
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut REPLY: Reply = Reply { buf: [0; PACKET_SIZE], len: 0 };
+
+pub fn init(port: u16) -> bool {
+    unsafe {
+        outb(port + 1, 0x00);
+        outb(port + 3, 0x80);
+        // 115200bps
+        outb(port + 0, 0x01);
+        outb(port + 1, 0x00);
+        outb(port + 3, 0x03);
+        outb(port + 2, 0xC7);
+        outb(port + 4, 0x1E);
+        outb(port + 0, 0xAE);
+        if inb(port + 0) != 0xAE {
+            return false;
+        }
+        // 割り込みは使わない
+        outb(port + 4, 0x03);
+    }
+    PORT.store(port, Ordering::Relaxed);
+    true
+}
+
+pub fn enabled() -> bool {
+    PORT.load(Ordering::Relaxed) != 0
+}
+
+// ここで止まってGDBを待つ
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("int3") }
+}
+
+fn getc(port: u16) -> u8 {
+    unsafe {
+        while inb(port + 5) & 1 == 0 {
+            core::hint::spin_loop();
+        }
+        inb(port)
+    }
+}
+
+fn putc(port: u16, c: u8) {
+    unsafe {
+        while inb(port + 5) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        outb(port, c);
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+// $...#xx を一つ受け取る。チェックサムが合わなければ'-'を返して待ち直す
+fn read_packet(port: u16, buf: &mut [u8; PACKET_SIZE]) -> &[u8] {
+    'retry: loop {
+        while getc(port) != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            let c = getc(port);
+            match c {
+                b'#' => break,
+                b'$' => continue 'retry,
+                _ => {
+                    if len == PACKET_SIZE {
+                        putc(port, b'-');
+                        continue 'retry;
+                    }
+                    buf[len] = c;
+                    len += 1;
+                    sum = sum.wrapping_add(c);
+                }
+            }
+        }
+        let expected = hex_digit(getc(port)).zip(hex_digit(getc(port))).map(|(h, l)| h << 4 | l);
+        if expected != Some(sum) {
+            putc(port, b'-');
+            continue;
+        }
+        putc(port, b'+');
+        return &buf[..len];
+    }
+}
+
+fn send_packet(port: u16, data: &[u8]) {
+    loop {
+        putc(port, b'$');
+        let mut sum = 0u8;
+        for &c in data {
+            putc(port, c);
+            sum = sum.wrapping_add(c);
+        }
+        putc(port, b'#');
+        putc(port, HEX[(sum >> 4) as usize]);
+        putc(port, HEX[(sum & 0xf) as usize]);
+        // '-'なら送り直す。'+'以外の余計な文字は読み捨てる
+        loop {
+            match getc(port) {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+    fn push(&mut self, s: &[u8]) {
+        let n = s.len().min(PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+        self.len += n;
+    }
+    fn push_byte(&mut self, b: u8) {
+        self.push(&[HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+    }
+    // リトルエンディアンでsize byte
+    fn push_le(&mut self, value: u64, size: usize) {
+        for b in &value.to_le_bytes()[..size] {
+            self.push_byte(*b);
+        }
+    }
+    // 数値としての16進(先頭の0は省く)
+    fn push_num(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros() as usize).div_ceil(4).max(1);
+        for i in (0..digits).rev() {
+            self.push(&[HEX[((value >> (i * 4)) & 0xf) as usize]]);
+        }
+    }
+    fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+// 先頭から16進の数値を読む
+fn parse_num(s: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    let mut n = 0;
+    while let Some(d) = s.first().and_then(|&c| hex_digit(c)) {
+        value = value.checked_mul(16)? | d as u64;
+        *s = &s[1..];
+        n += 1;
+    }
+    (n > 0).then_some(value)
+}
+
+fn expect(s: &mut &[u8], c: u8) -> Option<()> {
+    let (&first, rest) = s.split_first()?;
+    (first == c).then(|| *s = rest)
+}
+
+fn parse_le(s: &mut &[u8], size: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    for b in bytes[..size].iter_mut() {
+        *b = hex_digit(*s.first()?)? << 4 | hex_digit(*s.get(1)?)?;
+        *s = &s[2..];
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn segment(n: usize) -> u64 {
+    let value: u16;
+    unsafe {
+        match n {
+            20 => asm!("mov {:x}, ds", out(reg) value, options(nomem, nostack, preserves_flags)),
+            21 => asm!("mov {:x}, es", out(reg) value, options(nomem, nostack, preserves_flags)),
+            22 => asm!("mov {:x}, fs", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov {:x}, gs", out(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    value as u64
+}
+
+// GDBのamd64のレジスタ番号
+// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8〜r15, rip, eflags, cs, ss, ds, es, fs, gs
+const REGISTER_COUNT: usize = 24;
+
+fn register_size(n: usize) -> usize {
+    if n < 17 { 8 } else { 4 }
+}
+
+impl TrapFrame {
+    fn register(&self, n: usize) -> u64 {
+        match n {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.frame.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.frame.rip,
+            17 => self.frame.rflags,
+            18 => self.frame.cs,
+            19 => self.frame.ss,
+            _ => segment(n),
+        }
+    }
+
+    // セグメントは書き換えると戻れなくなるので無視する
+    fn set_register(&mut self, n: usize, value: u64) {
+        let r = match n {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.frame.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.frame.rip,
+            17 => &mut self.frame.rflags,
+            _ => return,
+        };
+        *r = value;
+    }
+}
+
+fn mapped(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    // ページごとに確かめる
+    let mut page = addr & !0xfff;
+    while page < end {
+        if crate::paging::translate(page as usize).is_none() {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+// カーネルのコードは書き込み禁止でマップされているので、CR0.WPを一時的に落として書く
+unsafe fn write_byte(addr: u64, value: u8) {
+    unsafe {
+        let cr0: u64;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov cr0, {}", in(reg) cr0 & !CR0_WP, options(nostack, preserves_flags));
+        (addr as *mut u8).write_volatile(value);
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+    }
+}
+
+fn breakpoints() -> &'static mut [Option<(u64, u8)>; MAX_BREAKPOINTS] {
+    // 止まっている間にしか触らない
+    unsafe { &mut *(&raw mut BREAKPOINTS) }
+}
+
+fn insert_breakpoint(addr: u64) -> bool {
+    let bps = breakpoints();
+    if bps.iter().flatten().any(|(a, _)| *a == addr) {
+        return true;
+    }
+    let Some(slot) = bps.iter_mut().find(|b| b.is_none()) else {
+        return false;
+    };
+    if !mapped(addr, 1) {
+        return false;
+    }
+    let original = unsafe { (addr as *const u8).read_volatile() };
+    unsafe { write_byte(addr, INT3) };
+    *slot = Some((addr, original));
+    true
+}
+
+fn remove_breakpoint(addr: u64) {
+    for slot in breakpoints().iter_mut() {
+        if let Some((a, original)) = *slot {
+            if a == addr {
+                unsafe { write_byte(a, original) };
+                *slot = None;
+            }
+        }
+    }
+}
+
+fn remove_all_breakpoints() {
+    for slot in breakpoints().iter_mut() {
+        if let Some((a, original)) = slot.take() {
+            unsafe { write_byte(a, original) };
+        }
+    }
+}
+
+enum Action {
+    Reply,
+    Resume,
+}
+
+fn stop_reply(reply: &mut Reply, signal: u8) {
+    reply.clear();
+    reply.push(b"S");
+    reply.push_byte(signal);
+}
+
+fn handle_packet(packet: &[u8], regs: &mut TrapFrame, signal: u8, reply: &mut Reply) -> Option<Action> {
+    reply.clear();
+    let (&command, mut args) = packet.split_first()?;
+    match command {
+        b'?' => stop_reply(reply, signal),
+        b'g' => {
+            for n in 0..REGISTER_COUNT {
+                reply.push_le(regs.register(n), register_size(n));
+            }
+        }
+        b'G' => {
+            for n in 0..REGISTER_COUNT {
+                let Some(value) = parse_le(&mut args, register_size(n)) else {
+                    break;
+                };
+                regs.set_register(n, value);
+            }
+            reply.push(b"OK");
+        }
+        b'p' => {
+            let n = parse_num(&mut args)? as usize;
+            if n < REGISTER_COUNT {
+                reply.push_le(regs.register(n), register_size(n));
+            } else {
+                reply.push(b"E00");
+            }
+        }
+        b'P' => {
+            let n = parse_num(&mut args)? as usize;
+            expect(&mut args, b'=')?;
+            if n < REGISTER_COUNT {
+                regs.set_register(n, parse_le(&mut args, register_size(n))?);
+            }
+            reply.push(b"OK");
+        }
+        b'm' => {
+            let addr = parse_num(&mut args)?;
+            expect(&mut args, b',')?;
+            let len = parse_num(&mut args)?.min(PACKET_SIZE as u64 / 2);
+            // 読めるところまで返す
+            for i in 0..len {
+                let Some(a) = addr.checked_add(i).filter(|&a| mapped(a, 1)) else {
+                    break;
+                };
+                reply.push_byte(unsafe { (a as *const u8).read_volatile() });
+            }
+            if reply.len == 0 {
+                reply.push(b"E14");
+            }
+        }
+        b'M' => {
+            let addr = parse_num(&mut args)?;
+            expect(&mut args, b',')?;
+            let len = parse_num(&mut args)?;
+            expect(&mut args, b':')?;
+            if len.checked_mul(2) != Some(args.len() as u64) || !mapped(addr, len) {
+                reply.push(b"E14");
+            } else {
+                for i in 0..len {
+                    let value = parse_le(&mut args, 1)? as u8;
+                    unsafe { write_byte(addr + i, value) };
+                }
+                reply.push(b"OK");
+            }
+        }
+        b'Z' | b'z' => {
+            // ソフトウェアブレークポイントだけ
+            if args.first() != Some(&b'0') {
+                return Some(Action::Reply);
+            }
+            args = &args[1..];
+            expect(&mut args, b',')?;
+            let addr = parse_num(&mut args)?;
+            if command == b'z' {
+                remove_breakpoint(addr);
+                reply.push(b"OK");
+            } else if insert_breakpoint(addr) {
+                reply.push(b"OK");
+            } else {
+                reply.push(b"E0e");
+            }
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_num(&mut args) {
+                regs.frame.rip = addr;
+            }
+            if command == b's' {
+                regs.frame.rflags |= RFLAGS_TF;
+            }
+            return Some(Action::Resume);
+        }
+        b'D' => {
+            remove_all_breakpoints();
+            CONNECTED.store(false, Ordering::Relaxed);
+            reply.push(b"OK");
+            send_packet(PORT.load(Ordering::Relaxed), reply.data());
+            return Some(Action::Resume);
+        }
+        b'k' => {
+            remove_all_breakpoints();
+            CONNECTED.store(false, Ordering::Relaxed);
+            return Some(Action::Resume);
+        }
+        b'H' | b'T' => reply.push(b"OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                reply.push(b"PacketSize=");
+                reply.push_num(PACKET_SIZE as u64);
+            } else if args.starts_with(b"Attached") {
+                reply.push(b"1");
+            } else if args.starts_with(b"fThreadInfo") {
+                reply.push(b"m1");
+            } else if args.starts_with(b"sThreadInfo") {
+                reply.push(b"l");
+            } else if args == b"C" {
+                reply.push(b"QC1");
+            } else if args == b"Offsets" {
+                // シンボルはカーネルイメージ先頭からのオフセットでリンクされている
+                let base = crate::backtrace::kernel_base().unwrap_or(0) as u64;
+                for name in [&b"Text="[..], b";Data=", b";Bss="] {
+                    reply.push(name);
+                    reply.push_num(base);
+                }
+            }
+        }
+        // 知らないものには空で返す
+        _ => {}
+    }
+    Some(Action::Reply)
+}
+
+// resumableでなければ、GDBが再開しようとしても止まったままにする
+fn session(regs: &mut TrapFrame, signal: u8, resumable: bool) {
+    let port = PORT.load(Ordering::Relaxed);
+    let packet = unsafe { &mut *(&raw mut PACKET) };
+    let reply = unsafe { &mut *(&raw mut REPLY) };
+    if CONNECTED.load(Ordering::Relaxed) {
+        stop_reply(reply, signal);
+        send_packet(port, reply.data());
+    }
+    loop {
+        let p = read_packet(port, packet);
+        CONNECTED.store(true, Ordering::Relaxed);
+        let action = handle_packet(p, regs, signal, reply);
+        match action {
+            Some(Action::Resume) if resumable => return,
+            Some(Action::Resume) => {
+                regs.frame.rflags &= !RFLAGS_TF;
+                if !CONNECTED.load(Ordering::Relaxed) {
+                    continue;
+                }
+                // コンソール出力(O)で知らせて、止まったままであることを返す
+                reply.clear();
+                reply.push(b"O");
+                for &b in b"cannot resume after panic\n" {
+                    reply.push_byte(b);
+                }
+                send_packet(port, reply.data());
+                stop_reply(reply, signal);
+                send_packet(port, reply.data());
+            }
+            Some(Action::Reply) => send_packet(port, reply.data()),
+            // 壊れたパケット
+            None => send_packet(port, b"E01"),
+        }
+    }
+}
+
+extern "sysv64" fn handle_trap(regs: &mut TrapFrame, vector: u32) {
+    let is_breakpoint = vector == ExceptionVector::Breakpoint as u32;
+    if !enabled() {
+        panic!("unexpected {} at {:#x}", if is_breakpoint { "breakpoint" } else { "debug exception" }, regs.frame.rip);
+    }
+    if is_breakpoint {
+        // 入れたブレークポイントなら、その命令から実行し直せるように戻す
+        let addr = regs.frame.rip.wrapping_sub(1);
+        if breakpoints().iter().flatten().any(|(a, _)| *a == addr) {
+            regs.frame.rip = addr;
+        }
+    } else {
+        // DR6は自分では消えない
+        unsafe { asm!("mov {}, dr6", "mov dr6, {}", out(reg) _, in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+    }
+    regs.frame.rflags &= !RFLAGS_TF;
+    session(regs, SIGTRAP, true);
+}
+
+// パニックしたところで止まる。有効でなければ何もしない
+pub fn enter_from_panic(regs: &crate::crash_dump::Registers) {
+    if !enabled() {
+        return;
+    }
+    let mut frame = TrapFrame {
+        rax: regs.rax,
+        rbx: regs.rbx,
+        rcx: regs.rcx,
+        rdx: regs.rdx,
+        rsi: regs.rsi,
+        rdi: regs.rdi,
+        rbp: regs.rbp,
+        r8: regs.r8,
+        r9: regs.r9,
+        r10: regs.r10,
+        r11: regs.r11,
+        r12: regs.r12,
+        r13: regs.r13,
+        r14: regs.r14,
+        r15: regs.r15,
+        frame: InterruptFrame { rip: regs.rip, cs: regs.cs, rflags: regs.rflags, rsp: regs.rsp, ss: regs.ss },
+    };
+    session(&mut frame, SIGABRT, false);
+}
+
+mod test {
+    #[test_case]
+    fn parse_packet_arguments() {
+        let mut s: &[u8] = b"ffff8000,10:";
+        assert_eq!(super::parse_num(&mut s), Some(0xffff_8000));
+        assert_eq!(super::expect(&mut s, b','), Some(()));
+        assert_eq!(super::parse_num(&mut s), Some(0x10));
+        assert_eq!(s, b":");
+        let mut s: &[u8] = b"3412";
+        assert_eq!(super::parse_le(&mut s, 2), Some(0x1234));
+    }
+
+    #[test_case]
+    fn reply_encodes_numbers() {
+        let mut reply = super::Reply { buf: [0; super::PACKET_SIZE], len: 0 };
+        reply.push_num(0);
+        reply.push(b",");
+        reply.push_num(0x1000);
+        reply.push(b",");
+        reply.push_le(0x1234, 4);
+        assert_eq!(reply.data(), b"0,1000,34120000");
+    }
+}
@@ -3,9 +3,12 @@ use alloc::boxed::Box;
 use alloc::vec as m_vec;
 
 use common::writer_config::{
+    PixelBitmask,
     PixelFormat,
     FrameBufferConfig,
+    VideoModeList,
 };
+use conquer_once::spin::OnceCell;
 
 use crate::{math::{Rectangle, Vector2D}, serial_println};
 
@@ -29,20 +32,60 @@ impl PixelColor {
     }
 }
 
-fn write_rgb_(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor) {
+fn write_rgb_(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor, _fmt: &PixelFormat) {
     let idx = 4 * (ppsl * y + x);
     buffer[idx] = c.r;
     buffer[idx + 1] = c.g;
     buffer[idx + 2] = c.b;
 }
 
-fn write_bgr_(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor) {
+fn write_bgr_(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor, _fmt: &PixelFormat) {
     let idx = 4 * (ppsl * y + x);
     buffer[idx] = c.b;
     buffer[idx + 1] = c.g;
     buffer[idx + 2] = c.r;
 }
 
+fn write_bitmask_(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor, fmt: &PixelFormat) {
+    let PixelFormat::Bitmask(mask) = fmt else {
+        unreachable!()
+    };
+    let bytes_per_pixel = mask.bits_per_pixel() / 8;
+    let idx = bytes_per_pixel * (ppsl * y + x);
+    let value = pack_bitmask(mask, c).to_le_bytes();
+    buffer[idx..idx + bytes_per_pixel].copy_from_slice(&value[..bytes_per_pixel]);
+}
+
+// 8bitの色をmaskの幅に合わせて詰める
+// maskのビットは連続していることを仮定する
+fn pack_channel(mask: u32, v: u8) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let width = (mask >> shift).count_ones();
+    let v = if width >= 8 {
+        (v as u32) << (width - 8)
+    } else {
+        (v as u32) >> (8 - width)
+    };
+    (v << shift) & mask
+}
+
+fn pack_bitmask(mask: &PixelBitmask, c: PixelColor) -> u32 {
+    pack_channel(mask.red, c.r) | pack_channel(mask.green, c.g) | pack_channel(mask.blue, c.b)
+}
+
+// 1pixelをlittle endianで読んだときの値を返す
+pub fn pack_pixel(format: PixelFormat, c: PixelColor) -> Result<u32, ()> {
+    match format {
+        PixelFormat::Rgb => Ok(u32::from_le_bytes([c.r, c.g, c.b, 0])),
+        PixelFormat::Bgr => Ok(u32::from_le_bytes([c.b, c.g, c.r, 0])),
+        PixelFormat::Bitmask(mask) => Ok(pack_bitmask(&mask, c)),
+        PixelFormat::BltOnly => Err(())
+    }
+}
+
 pub struct FrameBuffer {
     frame_buffer: &'static mut [u8],
     from_vec: bool,
@@ -50,7 +93,7 @@ pub struct FrameBuffer {
     horizontal_resolution: usize,
     vertical_resolution: usize,
     pixel_format: PixelFormat,
-    write_: fn(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor),
+    write_: fn(buffer: &mut [u8], ppsl: usize, x: usize, y: usize, c: PixelColor, fmt: &PixelFormat),
 }
 
 impl FrameBuffer {
@@ -68,7 +111,8 @@ impl FrameBuffer {
             write_: match config.pixel_format {
                 PixelFormat::Rgb => write_rgb_,
                 PixelFormat::Bgr => write_bgr_,
-                _ => panic!()
+                PixelFormat::Bitmask(_) => write_bitmask_,
+                PixelFormat::BltOnly => panic!("BltOnly frame buffer is not supported")
             }
         }
     }
@@ -90,7 +134,8 @@ impl FrameBuffer {
             write_: match config.pixel_format {
                 PixelFormat::Rgb => write_rgb_,
                 PixelFormat::Bgr => write_bgr_,
-                _ => panic!()
+                PixelFormat::Bitmask(_) => write_bitmask_,
+                PixelFormat::BltOnly => panic!("BltOnly frame buffer is not supported")
             }
         }
     }
@@ -171,7 +216,7 @@ impl FrameBuffer {
         if x >= self.horizontal_resolution || y >= self.vertical_resolution {
             return;
         }
-        (self.write_)(self.frame_buffer, self.pixels_per_scan_line, x, y, c);
+        (self.write_)(self.frame_buffer, self.pixels_per_scan_line, x, y, c, &self.pixel_format);
     }
     pub fn move_up(&mut self, value: usize, fill: u8) {
         let per_pixel = bits_per_pixel(self.pixel_format).unwrap();
@@ -206,6 +251,19 @@ pub fn bits_per_pixel(format: PixelFormat) -> Result<usize, ()> {
     match format {
         PixelFormat::Rgb => Ok(32),
         PixelFormat::Bgr => Ok(32),
-        _ => Err(())
+        PixelFormat::Bitmask(mask) => Ok(mask.bits_per_pixel()),
+        PixelFormat::BltOnly => Err(())
     }
 }
+
+static VIDEO_MODES: OnceCell<VideoModeList> = OnceCell::uninit();
+
+// ブートローダから受け取ったモード一覧を保存する
+pub fn init_video_modes(modes: &VideoModeList) {
+    VIDEO_MODES.try_init_once(|| *modes).expect("already init");
+}
+
+// 利用可能なモードと現在のモード
+pub fn video_modes() -> Option<&'static VideoModeList> {
+    VIDEO_MODES.get()
+}
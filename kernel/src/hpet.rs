@@ -0,0 +1,276 @@
+// High Precision Event Timer
+// ACPIのHPETテーブルから場所を知り、メインカウンタを単調増加のナノ秒の時計として使う
+// LAPICタイマの較正にも使い、コンパレータ0でワンショットの割り込みを起こせる
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+
+use crate::acpi::HPET;
+use crate::interrupt::{InterruptFrame, InterruptVector};
+use crate::paging::phys_to_virt;
+
+// レジスタのオフセット
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+const fn timer_configuration(n: u8) -> usize {
+    0x100 + 0x20 * n as usize
+}
+
+const fn timer_comparator(n: u8) -> usize {
+    0x108 + 0x20 * n as usize
+}
+
+// GENERAL_CAPABILITIES
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+// GENERAL_CONFIGURATION
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+// タイマごとの設定。TYPE_CNFが1なら周期モード
+const TN_INT_TYPE_CNF: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+const TN_INT_ROUTE_MASK: u64 = 0x1f << TN_INT_ROUTE_SHIFT;
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+// 仕様で決まっているカウンタの周期の上限(100ns)
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const ONESHOT_COMPARATOR: u8 = 0;
+// GSI 16以上はPCIのINTxだが、デバイスはMSIを使っているので空いている
+const FIRST_PCI_GSI: u32 = 16;
+
+pub struct Hpet {
+    base: usize,
+    period_fs: u64,
+    comparator_count: u8,
+    counter_64: bool,
+    // ワンショットの割り込みが届くGSI。I/O APICにつなげられなければNone
+    oneshot_gsi: Option<u32>,
+}
+
+impl Hpet {
+    unsafe fn read(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+    unsafe fn write(&self, offset: usize, value: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+    pub fn frequency(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period_fs
+    }
+    pub fn comparator_count(&self) -> u8 {
+        self.comparator_count
+    }
+    pub fn oneshot_gsi(&self) -> Option<u32> {
+        self.oneshot_gsi
+    }
+    // 32bitのカウンタは前に読んだ値から桁上がりを補う
+    // 半周(14.318MHzで約2.5分)する前に一度は読まれている必要がある
+    pub fn counter(&self) -> u64 {
+        if self.counter_64 {
+            return unsafe { self.read(MAIN_COUNTER) };
+        }
+        // 先にlastを読む。カウンタを読んだ後だと、その間に割り込みの中で読まれた新しい値がlastになり、一周したように見える
+        let last = LAST_COUNTER.load(Ordering::Relaxed);
+        let now = unsafe { self.read(MAIN_COUNTER) };
+        let value = extend_counter(last, now as u32);
+        LAST_COUNTER.fetch_max(value, Ordering::Relaxed).max(value)
+    }
+    pub fn nanoseconds(&self) -> u64 {
+        ticks_to_ns(self.counter(), self.period_fs)
+    }
+    pub fn wait_nanoseconds(&self, ns: u64) {
+        let end = self.nanoseconds().saturating_add(ns);
+        while self.nanoseconds() < end {
+            core::hint::spin_loop();
+        }
+    }
+    pub fn wait_milliseconds(&self, ms: u32) {
+        self.wait_nanoseconds(ms as u64 * 1_000_000);
+    }
+}
+
+static HPET_DEVICE: OnceCell<Hpet> = OnceCell::uninit();
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+static ONESHOT_FIRED: AtomicU64 = AtomicU64::new(0);
+static ONESHOT_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn ticks_to_ns(ticks: u64, period_fs: u64) -> u64 {
+    (ticks as u128 * period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND) as u64
+}
+
+fn ns_to_ticks(ns: u64, period_fs: u64) -> u64 {
+    (ns as u128 * FEMTOSECONDS_PER_NANOSECOND).div_ceil(period_fs as u128) as u64
+}
+
+// 前の値lastから下位32bitの差だけ進める。差が半周以上なら、進んだのではなく他で読まれたlastより少し前の値なので、lastのままにする
+fn extend_counter(last: u64, now: u32) -> u64 {
+    let delta = now.wrapping_sub(last as u32);
+    if delta < 1 << 31 { last + delta as u64 } else { last }
+}
+
+// コンパレータ0の割り込みをI/O APICにつなぐ
+unsafe fn route_oneshot(hpet: &Hpet) -> Option<u32> {
+    let configuration = unsafe { hpet.read(timer_configuration(ONESHOT_COMPARATOR)) };
+    let capability = (configuration >> 32) as u32;
+    let gsi = (FIRST_PCI_GSI..32).chain(0..FIRST_PCI_GSI)
+        .find(|&gsi| capability & (1 << gsi) != 0 && crate::ioapic::has_gsi(gsi))?;
+    let configuration = configuration & !(TN_INT_ROUTE_MASK | TN_INT_ENB_CNF | TN_INT_TYPE_CNF) | (gsi as u64) << TN_INT_ROUTE_SHIFT;
+    unsafe { hpet.write(timer_configuration(ONESHOT_COMPARATOR), configuration) };
+    crate::ioapic::route_gsi(gsi, InterruptVector::HPET as u8, crate::interrupt::local_apic_id()).then_some(gsi)
+}
+
+// safety: init_ioapicの後、割り込みを止めた状態で一度だけ呼ぶ
+pub unsafe fn init_hpet(table: Option<&'static HPET>) -> bool {
+    let Some(table) = table else {
+        log::info!("HPET is not found");
+        return false;
+    };
+    let mut hpet = Hpet { base: phys_to_virt(table.base_address()), period_fs: 0, comparator_count: 0, counter_64: false, oneshot_gsi: None };
+    let capabilities = unsafe { hpet.read(GENERAL_CAPABILITIES) };
+    hpet.period_fs = capabilities >> 32;
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        log::warn!("HPET at {:#x} has an invalid period {} fs", table.base_address(), hpet.period_fs);
+        return false;
+    }
+    hpet.comparator_count = (capabilities >> 8 & 0x1f) as u8 + 1;
+    hpet.counter_64 = capabilities & COUNT_SIZE_CAP != 0;
+
+    unsafe {
+        // 全部のコンパレータを止めてから、レガシー置き換えなしでカウンタを動かす
+        for n in 0..hpet.comparator_count {
+            let configuration = hpet.read(timer_configuration(n));
+            hpet.write(timer_configuration(n), configuration & !(TN_INT_ENB_CNF | TN_TYPE_CNF | TN_FSB_EN_CNF));
+        }
+        let configuration = hpet.read(GENERAL_CONFIGURATION);
+        hpet.write(GENERAL_CONFIGURATION, configuration & !LEG_RT_CNF | ENABLE_CNF);
+        // 32bitのカウンタは最初に読んだ値から数え始める
+        if !hpet.counter_64 {
+            LAST_COUNTER.store(hpet.read(MAIN_COUNTER) & 0xffff_ffff, Ordering::Relaxed);
+        }
+        hpet.oneshot_gsi = route_oneshot(&hpet);
+    }
+
+    log::info!("HPET: {} Hz, {} comparators, {}-bit counter, one-shot GSI {:?}",
+        hpet.frequency(), hpet.comparator_count, if hpet.counter_64 { 64 } else { 32 }, hpet.oneshot_gsi);
+    if HPET_DEVICE.try_init_once(|| hpet).is_err() {
+        log::warn!("HPET is already initialized");
+    }
+    true
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET_DEVICE.get()
+}
+
+// 起動してからのナノ秒。HPETがなければNone
+pub fn nanoseconds() -> Option<u64> {
+    hpet().map(Hpet::nanoseconds)
+}
+
+// HPETのnanoseconds()がdeadlineになったら割り込みを起こす
+// コンパレータは一つなので、後から設定したものが前のものを上書きする
+// 設定できないか、もう過ぎていればfalse
+pub fn set_oneshot(deadline: u64) -> bool {
+    let Some(hpet) = hpet().filter(|h| h.oneshot_gsi.is_some()) else {
+        return false;
+    };
+    let n = ONESHOT_COMPARATOR;
+    unsafe {
+        let configuration = hpet.read(timer_configuration(n));
+        hpet.write(timer_configuration(n), configuration & !TN_INT_ENB_CNF);
+        hpet.write(timer_comparator(n), ns_to_ticks(deadline, hpet.period_fs));
+        hpet.write(timer_configuration(n), configuration & !TN_TYPE_CNF | TN_INT_ENB_CNF);
+    }
+    // 書いている間に過ぎてしまうと、カウンタが一周するまで一致しない
+    hpet.nanoseconds() < deadline
+}
+
+pub fn cancel_oneshot() {
+    let Some(hpet) = hpet() else {
+        return;
+    };
+    unsafe {
+        let configuration = hpet.read(timer_configuration(ONESHOT_COMPARATOR));
+        hpet.write(timer_configuration(ONESHOT_COMPARATOR), configuration & !TN_INT_ENB_CNF);
+    }
+}
+
+// ワンショットの割り込みが来た回数
+pub fn oneshot_count() -> u64 {
+    ONESHOT_FIRED.load(Ordering::Relaxed)
+}
+
+// deadlineまで待つFuture。割り込みで起こされる
+pub struct OneShot {
+    deadline: u64,
+}
+
+pub fn oneshot(deadline: u64) -> OneShot {
+    OneShot { deadline }
+}
+
+impl Future for OneShot {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(now) = nanoseconds() else {
+            return Poll::Ready(());
+        };
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        ONESHOT_WAKER.register(cx.waker());
+        if !set_oneshot(self.deadline) {
+            ONESHOT_WAKER.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+pub extern "x86-interrupt" fn int_handler_hpet(_frame: InterruptFrame) {
+    // エッジトリガなので状態レジスタは触らなくてよい
+    ONESHOT_FIRED.fetch_add(1, Ordering::Relaxed);
+    ONESHOT_WAKER.wake();
+    crate::interrupt::notify_end_of_interrupt();
+}
+
+mod test {
+    #[test_case]
+    fn ticks_are_converted_to_nanoseconds() {
+        use super::{ns_to_ticks, ticks_to_ns};
+        // QEMUのHPETは10MHz(100ns)、実機は14.318MHzが多い
+        assert_eq!(ticks_to_ns(10_000_000, 100_000_000), 1_000_000_000);
+        assert_eq!(ticks_to_ns(14_318_180, 69_841_279), 1_000_000_004);
+        // 1年分でもあふれない
+        assert_eq!(ticks_to_ns(315_360_000_000_000, 100_000_000), 31_536_000_000_000_000);
+        // 期限に届かないことがないよう切り上げる
+        assert_eq!(ns_to_ticks(150, 100_000_000), 2);
+        assert_eq!(ns_to_ticks(200, 100_000_000), 2);
+    }
+
+    #[test_case]
+    fn counter_32bit_wraps() {
+        use super::extend_counter;
+        assert_eq!(extend_counter(0, 5), 5);
+        assert_eq!(extend_counter(0xffff_fff0, 0x10), 0x1_0000_0010);
+        assert_eq!(extend_counter(0x1_0000_0010, 0x20), 0x1_0000_0020);
+        assert_eq!(extend_counter(0x1_0000_0010, 0x10), 0x1_0000_0010);
+        // 割り込みの中で先に新しい値が読まれていても、一周したことにはしない
+        assert_eq!(extend_counter(0x1_0000_0010, 0x08), 0x1_0000_0010);
+        assert_eq!(extend_counter(0x2, 0xffff_fffe), 0x2);
+    }
+}
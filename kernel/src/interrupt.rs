@@ -1,13 +1,15 @@
 use spin::Mutex;
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct InterruptFrame {
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
 static IDT: Mutex<[InterruptDescriptor; 256]> = Mutex::new([
@@ -24,6 +26,13 @@ static IDT: Mutex<[InterruptDescriptor; 256]> = Mutex::new([
 pub enum InterruptVector {
     XHCI = 0x40,
     LAPICTimer = 0x41,
+    COM1 = 0x42,
+    HPET = 0x43,
+}
+
+pub enum ExceptionVector {
+    Debug = 1,
+    Breakpoint = 3,
 }
 
 pub enum DescriptorType {
@@ -109,12 +118,31 @@ pub fn load_idt() {
     }
 }
 
+// MADTで別の場所が示されていればそちらに変える
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0xfee0_0000);
+
+const LOCAL_APIC_ID: usize = 0x20;
+const LOCAL_APIC_EOI: usize = 0xb0;
+
+pub fn set_local_apic_base(phys: u64) {
+    LOCAL_APIC_BASE.store(phys, Ordering::Relaxed);
+}
+
+// Local APICのレジスタ
+pub fn local_apic(offset: usize) -> *mut u32 {
+    (crate::paging::phys_to_virt(LOCAL_APIC_BASE.load(Ordering::Relaxed)) + offset) as *mut u32
+}
+
 pub fn notify_end_of_interrupt() {
     unsafe {
-        (0xfee000b0 as *mut u32).write_volatile(0);
+        local_apic(LOCAL_APIC_EOI).write_volatile(0);
     }
 }
 
+pub fn local_apic_id() -> u8 {
+    unsafe { (local_apic(LOCAL_APIC_ID).read_volatile() >> 24) as u8 }
+}
+
 pub unsafe fn enable_interrupt() {
     unsafe {
         asm!("sti");
@@ -127,6 +155,22 @@ pub unsafe fn disable_interrupt() {
     }
 }
 
+// 割り込みを止めてfを実行し、元の状態に戻す
+// 割り込みハンドラと共有するロックを取るときに使う
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)); }
+    let enabled = rflags & (1 << 9) != 0;
+    if enabled {
+        unsafe { disable_interrupt(); }
+    }
+    let ret = f();
+    if enabled {
+        unsafe { enable_interrupt(); }
+    }
+    ret
+}
+
 pub unsafe fn enable_and_halt_interrupt() {
     unsafe {
         asm!("sti", "hlt");
@@ -137,5 +181,9 @@ pub unsafe fn init_interrupt() {
     let cs = get_cs();
     set_idt_entry(InterruptVector::XHCI as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::usb::controller::int_handler_xhci as *const fn() as u64, cs);
     set_idt_entry(InterruptVector::LAPICTimer as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::timer::int_handler_lapic_timer as *const fn() as u64, cs);
+    set_idt_entry(ExceptionVector::Debug as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::gdb::int_handler_debug as *const fn() as u64, cs);
+    set_idt_entry(ExceptionVector::Breakpoint as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::gdb::int_handler_breakpoint as *const fn() as u64, cs);
+    set_idt_entry(InterruptVector::COM1 as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::serial::int_handler_com1 as *const fn() as u64, cs);
+    set_idt_entry(InterruptVector::HPET as usize, InterruptDescriptorAttr::new(DescriptorType::InterruptGate, 0, true, 0), crate::hpet::int_handler_hpet as *const fn() as u64, cs);
     load_idt();
 }
@@ -22,6 +22,28 @@ pub(crate) unsafe fn inb(port: u16) -> u8 {
     res
 }
 
+pub(crate) unsafe fn outw(port: u16, value: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") port,
+            in("ax") value,
+        )
+    }
+}
+
+pub(crate) unsafe fn inw(port: u16) -> u16 {
+    let res: u16;
+    unsafe {
+        asm!(
+            "in ax, dx",
+            in("dx") port,
+            out("ax") res,
+        );
+    }
+    res
+}
+
 pub(crate) unsafe fn outd(port: u16, value: u32) {
     unsafe {
         asm!(
@@ -0,0 +1,222 @@
+// I/O APIC
+// ISAの割り込み(PIT, PS/2, シリアル, RTC)はMSIを使えないので、I/O APICでLocal APICへ振り分ける
+// MADTからI/O APICと割り込みの振り替え(Interrupt Source Override)を読み、
+// MADTがなければ標準の場所に一つだけあるものとして扱う
+// 8259は使わないので全部マスクする
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+
+use crate::acpi::{MADT, MadtEntry};
+use crate::io_port::outb;
+use crate::paging::phys_to_virt;
+
+const DEFAULT_IO_APIC_BASE: u64 = 0xfec0_0000;
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// Interrupt Source Overrideのflags
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LegacyIrq {
+    Pit = 0,
+    Keyboard = 1,
+    Com2 = 3,
+    Com1 = 4,
+    Rtc = 8,
+    Mouse = 12,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+    pub redirection_count: u32,
+}
+
+impl IoApic {
+    fn new(id: u8, address: u64, gsi_base: u32) -> Self {
+        let mut io_apic = IoApic { id, address, gsi_base, redirection_count: 0 };
+        io_apic.redirection_count = (unsafe { io_apic.read(IOAPICVER) } >> 16 & 0xff) + 1;
+        io_apic
+    }
+    unsafe fn read(&self, reg: u32) -> u32 {
+        let base = phys_to_virt(self.address);
+        unsafe {
+            ((base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+    unsafe fn write(&self, reg: u32, value: u32) {
+        let base = phys_to_virt(self.address);
+        unsafe {
+            ((base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+    fn contains(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_count).contains(&gsi)
+    }
+    unsafe fn set_redirection(&self, gsi: u32, low: u32, apic_id: u8) {
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+        unsafe {
+            // 書き換えている間に中途半端な設定で届かないよう、先にマスクしておく
+            self.write(reg, REDIRECTION_MASKED);
+            self.write(reg + 1, (apic_id as u32) << 24);
+            self.write(reg, low);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+pub struct InterruptControllers {
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<SourceOverride>,
+}
+
+static CONTROLLERS: OnceCell<InterruptControllers> = OnceCell::uninit();
+
+// 8259を初期化して0x20から始まるベクタに付け替え、全部マスクする
+// マスクしていても届いてしまう偽の割り込みが、例外と区別できるようにするため
+unsafe fn disable_pic() {
+    unsafe {
+        outb(0x20, 0x11);
+        outb(0xa0, 0x11);
+        outb(0x21, 0x20);
+        outb(0xa1, 0x28);
+        outb(0x21, 0x04);
+        outb(0xa1, 0x02);
+        outb(0x21, 0x01);
+        outb(0xa1, 0x01);
+        outb(0x21, 0xff);
+        outb(0xa1, 0xff);
+    }
+}
+
+// safety: メモリアロケータの初期化後、割り込みを止めた状態で一度だけ呼ぶ
+pub unsafe fn init_ioapic(madt: Option<&'static MADT>) {
+    unsafe { disable_pic(); }
+
+    let mut local_apics = Vec::new();
+    let mut io_apics = Vec::new();
+    let mut overrides = Vec::new();
+    if let Some(madt) = madt {
+        crate::interrupt::set_local_apic_base(madt.local_apic_address());
+        for entry in madt.entries() {
+            match entry {
+                MadtEntry::LocalApic { processor_uid, apic_id, enabled } => local_apics.push(LocalApic { processor_uid, apic_id, enabled }),
+                MadtEntry::IoApic { id, address, gsi_base } => io_apics.push(IoApic::new(id, address as u64, gsi_base)),
+                // ISA以外のバスは今のところない
+                MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } => overrides.push(SourceOverride { irq: source, gsi, flags }),
+                _ => {}
+            }
+        }
+    } else {
+        log::warn!("MADT is not found. assume an I/O APIC at {:#x}", DEFAULT_IO_APIC_BASE);
+    }
+    if io_apics.is_empty() {
+        io_apics.push(IoApic::new(0, DEFAULT_IO_APIC_BASE, 0));
+    }
+
+    for cpu in local_apics.iter() {
+        log::info!("local APIC: uid {} id {}{}", cpu.processor_uid, cpu.apic_id, if cpu.enabled { "" } else { " (disabled)" });
+    }
+    for io_apic in io_apics.iter() {
+        log::info!("I/O APIC: id {} at {:#x} GSI {}-{}", io_apic.id, io_apic.address, io_apic.gsi_base, io_apic.gsi_base + io_apic.redirection_count - 1);
+        for i in 0..io_apic.redirection_count {
+            unsafe { io_apic.write(IOREDTBL + i * 2, REDIRECTION_MASKED) };
+        }
+    }
+    for o in overrides.iter() {
+        log::info!("IRQ {} -> GSI {} (flags {:#x})", o.irq, o.gsi, o.flags);
+    }
+
+    if CONTROLLERS.try_init_once(|| InterruptControllers { local_apics, io_apics, overrides }).is_err() {
+        log::warn!("I/O APIC is already initialized");
+    }
+}
+
+pub fn controllers() -> Option<&'static InterruptControllers> {
+    CONTROLLERS.get()
+}
+
+// ISAのIRQのGSIとリダイレクションの設定。振り替えがなければGSIは同じ番号で、High active, エッジトリガ
+fn resolve(controllers: &InterruptControllers, irq: u8) -> (u32, u32) {
+    let Some(o) = controllers.overrides.iter().find(|o| o.irq == irq) else {
+        return (irq as u32, 0);
+    };
+    let mut low = 0;
+    if o.flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if o.flags & TRIGGER_MASK == TRIGGER_LEVEL {
+        low |= REDIRECTION_LEVEL;
+    }
+    (o.gsi, low)
+}
+
+// ISAの割り込みirqを、apic_idのCPUのvectorに届ける。担当するI/O APICがなければfalse
+pub fn route_irq(irq: LegacyIrq, vector: u8, apic_id: u8) -> bool {
+    let Some(controllers) = CONTROLLERS.get() else {
+        log::warn!("I/O APIC is not initialized");
+        return false;
+    };
+    let (gsi, low) = resolve(controllers, irq as u8);
+    let Some(io_apic) = controllers.io_apics.iter().find(|a| a.contains(gsi)) else {
+        log::warn!("no I/O APIC for IRQ {} (GSI {})", irq as u8, gsi);
+        return false;
+    };
+    unsafe { io_apic.set_redirection(gsi, low | vector as u32, apic_id) };
+    true
+}
+
+// ISA以外(HPETのコンパレータなど)のGSIを、High active, エッジトリガでvectorに届ける
+pub fn route_gsi(gsi: u32, vector: u8, apic_id: u8) -> bool {
+    let Some(io_apic) = CONTROLLERS.get().and_then(|c| c.io_apics.iter().find(|a| a.contains(gsi))) else {
+        log::warn!("no I/O APIC for GSI {}", gsi);
+        return false;
+    };
+    unsafe { io_apic.set_redirection(gsi, vector as u32, apic_id) };
+    true
+}
+
+// gsiを受け持つI/O APICがあるか
+pub fn has_gsi(gsi: u32) -> bool {
+    CONTROLLERS.get().is_some_and(|c| c.io_apics.iter().any(|a| a.contains(gsi)))
+}
+
+pub fn mask_irq(irq: LegacyIrq) {
+    let Some(controllers) = CONTROLLERS.get() else {
+        return;
+    };
+    let (gsi, _) = resolve(controllers, irq as u8);
+    if let Some(io_apic) = controllers.io_apics.iter().find(|a| a.contains(gsi)) {
+        unsafe { io_apic.write(IOREDTBL + (gsi - io_apic.gsi_base) * 2, REDIRECTION_MASKED) };
+    }
+}
@@ -61,6 +61,8 @@ fn keyboard_handler_internal(modifire: u8, pressing: [u8; 6]) -> Option<()> {
             };
             if *v != 0 {
                 print!("{}", (*v) as char);
+            } else {
+                crate::log_viewer::handle_key(*last);
             }
             Some(())
         } else {
@@ -75,6 +77,9 @@ fn keyboard_handler_internal(modifire: u8, pressing: [u8; 6]) -> Option<()> {
                 };
                 if *v != 0 {
                     print!("{}", (*v) as char);
+                } else {
+                    // 文字にならないキーはログビューアの操作に使う
+                    crate::log_viewer::handle_key(*last);
                 }
             }
             MODSTATE = modifire;
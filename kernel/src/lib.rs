@@ -13,9 +13,14 @@ pub mod console;
 pub mod pci;
 pub mod error;
 pub mod logger;
+pub mod log_viewer;
 pub mod usb;
 pub mod mouse;
 pub mod interrupt;
+pub mod ioapic;
+pub mod hpet;
+pub mod time;
+pub mod rtc;
 pub mod segment;
 pub mod paging;
 pub mod memory_manager;
@@ -24,27 +29,39 @@ pub mod task;
 pub mod window;
 pub mod timer;
 pub mod serial;
+pub mod shell;
 pub mod entry;
 pub mod math;
 pub mod io_port;
 pub mod acpi;
+pub mod aml;
+pub mod power;
 pub mod panic;
 pub mod keyboard;
 pub mod preemptive;
 pub mod backtrace;
+pub mod crash_dump;
+pub mod dwarf;
+pub mod gdb;
+pub mod boot_config;
+pub mod multiboot2;
 
 extern crate alloc;
 
 pub trait Testable {
     fn run(&self);
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Testable for T where T: Fn() {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<Self>());
+        serial_print!("{}...\t", self.name());
         self();
         serial_println!("[ok]");
     }
+    fn name(&self) -> &'static str {
+        core::any::type_name::<Self>()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -66,8 +83,10 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    let filter = boot_config::test_filter();
+    let selected = || tests.iter().filter(|t| filter.is_none_or(|f| t.name().contains(f)));
+    serial_println!("Running {} tests", selected().count());
+    for test in selected() {
         test.run();
     }
     exit_qemu(QemuExitCode::Success);
@@ -77,14 +96,16 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 entry!(crate::kernel_test);
 
 #[cfg(test)]
-pub extern "sysv64" fn kernel_test(_config: *const common::Config) -> ! {
+pub extern "sysv64" fn kernel_test(config: *const common::Config) -> ! {
     // ロガーとアロケータのみ初期化
+    let config = unsafe { entry::check_config(config) };
     logger::init_serial_and_logger();
+    unsafe { boot_config::init_boot_config(config.boot_config, config.boot_config_len); }
     log::set_max_level(log::LevelFilter::Trace);
     unsafe {
         segment::init_segment();
-        paging::setup_identity_page_table();
-        memory_manager::init_memory_manager(memmap_ptr);
+        paging::setup_page_table(config);
+        memory_manager::init_memory_manager(&config.memmap);
         allocator::init_allocator();
     }
     test_main();
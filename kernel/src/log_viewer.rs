@@ -0,0 +1,182 @@
+// logger::LOG_RINGの中身を表示するウィンドウ
+//
+//   F1〜F5      : Error, Warn, Info, Debug, Traceより重要なものだけを表示する
+//   PageUp/Down : 1画面分スクロール
+//   ↑/↓         : 1行スクロール
+//   End         : 最新のログに戻る(以降は新しいログに追従する)
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use heapless::String;
+use log::{Level, LevelFilter};
+use spin::Mutex;
+
+use crate::graphics::PixelColor;
+use crate::logger::{LogEntry, LOG_RING_LEN};
+use crate::timer::sleep;
+use crate::window::{Window, WindowManager};
+
+const WIDTH: usize = 640;
+const ROWS: usize = 16;
+const COLUMNS: usize = (WIDTH - 2 * MARGIN) / 8;
+const MARGIN: usize = 8;
+const TITLE_HEIGHT: usize = 22;
+const HEIGHT: usize = TITLE_HEIGHT + ROWS * 16 + 2 * MARGIN;
+// 更新を確かめる間隔
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+const KEY_F1: u8 = 0x3a;
+const KEY_F5: u8 = 0x3e;
+const KEY_HOME: u8 = 0x4a;
+const KEY_PAGE_UP: u8 = 0x4b;
+const KEY_END: u8 = 0x4d;
+const KEY_PAGE_DOWN: u8 = 0x4e;
+const KEY_DOWN: u8 = 0x51;
+const KEY_UP: u8 = 0x52;
+
+static FILTER: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
+// 最新の行から何行さかのぼって表示しているか。0なら新しいログに追従する
+static SCROLL: AtomicUsize = AtomicUsize::new(0);
+static DIRTY: AtomicBool = AtomicBool::new(true);
+
+fn filter() -> LevelFilter {
+    match FILTER.load(Ordering::Relaxed) {
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+// キーボードから、文字に対応しないキーが押されたときに呼ばれる
+pub fn handle_key(keycode: u8) {
+    match keycode {
+        KEY_F1..=KEY_F5 => {
+            FILTER.store((keycode - KEY_F1 + 1) as usize, Ordering::Relaxed);
+            SCROLL.store(0, Ordering::Relaxed);
+        }
+        KEY_PAGE_UP => { SCROLL.fetch_add(ROWS, Ordering::Relaxed); }
+        KEY_UP => { SCROLL.fetch_add(1, Ordering::Relaxed); }
+        KEY_PAGE_DOWN => scroll_down(ROWS),
+        KEY_DOWN => scroll_down(1),
+        KEY_END => SCROLL.store(0, Ordering::Relaxed),
+        KEY_HOME => SCROLL.store(LOG_RING_LEN, Ordering::Relaxed),
+        _ => return,
+    }
+    DIRTY.store(true, Ordering::Relaxed);
+}
+
+fn scroll_down(n: usize) {
+    let _ = SCROLL.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(n)));
+}
+
+// total行のうち表示する範囲と、さかのぼれる上限に丸めたscroll
+fn visible_range(total: usize, rows: usize, scroll: usize) -> (usize, usize, usize) {
+    let scroll = scroll.min(total.saturating_sub(rows));
+    let end = total - scroll;
+    (end.saturating_sub(rows), end, scroll)
+}
+
+fn level_color(level: Level) -> PixelColor {
+    match level {
+        Level::Error => PixelColor::from_hex(0xff5555),
+        Level::Warn => PixelColor::from_hex(0xffcc44),
+        Level::Info => PixelColor::WHITE,
+        Level::Debug => PixelColor::from_hex(0x88ccff),
+        Level::Trace => PixelColor::from_hex(0x999999),
+    }
+}
+
+// 1行に収まらない分は捨て、改行は空白にする
+struct Line(String<COLUMNS>);
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            let c = if c == '\n' || c == '\t' { ' ' } else { c };
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn redraw(window: &Mutex<Window>) {
+    let filter = filter();
+    let mut entries: Vec<LogEntry> = Vec::new();
+    if !crate::logger::try_for_each_recent(LOG_RING_LEN, |e| {
+        if e.level <= filter {
+            entries.push(e.clone());
+        }
+    }) {
+        // ログを書いている途中なので次の機会に描く
+        DIRTY.store(true, Ordering::Relaxed);
+        return;
+    }
+    let (start, end, scroll) = visible_range(entries.len(), ROWS, SCROLL.load(Ordering::Relaxed));
+    SCROLL.store(scroll, Ordering::Relaxed);
+
+    let id = {
+        let mut w = window.lock();
+        let mut title = Line(String::new());
+        let _ = write!(title, "Log <= {}  ({}/{})", filter, end, entries.len());
+        if scroll == 0 {
+            let _ = title.write_str("  following");
+        }
+        w.draw_basic_window(&title.0);
+        for (row, e) in entries[start..end].iter().enumerate() {
+            let mut line = Line(String::new());
+            // 壁時計が分かれば時刻を、分からなければ起動してからの秒を出す
+            let _ = match crate::rtc::date_time(e.time) {
+                Some(dt) => write!(line, "[{:02}:{:02}:{:02}]", dt.hour, dt.minute, dt.second),
+                None => write!(line, "[{:>11}]", e.time),
+            };
+            let _ = write!(line, " {:<5} {}: {}", e.level, e.module, e.message);
+            w.write_string(&line.0, level_color(e.level), MARGIN, TITLE_HEIGHT + MARGIN + row * 16);
+        }
+        w.id()
+    };
+    WindowManager::draw_window(id);
+}
+
+pub async fn log_viewer(window: Arc<Mutex<Window>>) {
+    let mut shown = usize::MAX;
+    loop {
+        let pushed = crate::logger::pushed_count();
+        // 追従していないときは、新しいログが来ても表示位置を変えない
+        let follow = SCROLL.load(Ordering::Relaxed) == 0;
+        if DIRTY.swap(false, Ordering::Relaxed) || (follow && pushed != shown) {
+            shown = pushed;
+            redraw(&window);
+        }
+        sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+// ウィンドウを作り、表示するタスクを返す
+pub fn new(pos_x: isize, pos_y: isize) -> impl Future<Output = ()> {
+    let (id, window) = WindowManager::new_window(WIDTH, HEIGHT, false, pos_x, pos_y, true);
+    WindowManager::up_down(id, 1);
+    log_viewer(window)
+}
+
+mod test {
+    #[test_case]
+    fn visible_range_follows_latest() {
+        assert_eq!(super::visible_range(100, 16, 0), (84, 100, 0));
+        assert_eq!(super::visible_range(10, 16, 0), (0, 10, 0));
+    }
+
+    #[test_case]
+    fn visible_range_clamps_scroll() {
+        assert_eq!(super::visible_range(100, 16, 20), (64, 80, 20));
+        assert_eq!(super::visible_range(100, 16, 1000), (0, 16, 84));
+        assert_eq!(super::visible_range(10, 16, 5), (0, 10, 0));
+    }
+}
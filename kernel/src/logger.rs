@@ -1,4 +1,9 @@
-use log::{Record, Metadata};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use heapless::{Deque, String};
+use log::{Level, LevelFilter, Record, Metadata};
+use spin::Mutex;
 
 macro_rules! __log {
     ($($arg:tt)*) => {
@@ -10,11 +15,104 @@ macro_rules! __log {
 
 pub struct Logger;
 
+// シリアルと画面に出すかどうかのフィルタ。リングバッファにはフィルタに関係なくすべて残す
+// `info,kernel::usb=trace,kernel::pci=warn`のように、既定のレベルとモジュールごとのレベルを並べる
+// モジュールは前方一致(::の区切りで)で、最も長く一致したものを使う
+const MAX_DIRECTIVES: usize = 16;
+const DIRECTIVE_MODULE_LEN: usize = 48;
+
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    default: LevelFilter,
+    directives: heapless::Vec<(String<DIRECTIVE_MODULE_LEN>, LevelFilter), MAX_DIRECTIVES>,
+}
+
+impl LogFilter {
+    pub const fn new(default: LevelFilter) -> Self {
+        LogFilter { default, directives: heapless::Vec::new() }
+    }
+
+    // 解釈できなかった項目をエラーとして返す
+    pub fn parse(text: &str) -> Result<Self, &str> {
+        // モジュールだけを指定したときは、それ以外を全部出すより何も出さないほうが意図に近いので、既定はOffから始める
+        let mut filter = LogFilter::new(LevelFilter::Off);
+        let mut has_default = false;
+        for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                None => {
+                    filter.default = item.parse().map_err(|_| item)?;
+                    has_default = true;
+                }
+                Some((module, level)) => {
+                    let level = level.parse().map_err(|_| item)?;
+                    let module = String::try_from(module).map_err(|_| item)?;
+                    if let Some(d) = filter.directives.iter_mut().find(|(m, _)| *m == module) {
+                        d.1 = level;
+                    } else {
+                        filter.directives.push((module, level)).map_err(|_| item)?;
+                    }
+                }
+            }
+        }
+        // 空や区切りだけの指定では何も出なくなるので、誤りとして扱う
+        if !has_default && filter.directives.is_empty() {
+            return Err(text);
+        }
+        Ok(filter)
+    }
+
+    pub fn level(&self, module: &str) -> LevelFilter {
+        self.directives.iter()
+            .filter(|(m, _)| {
+                module.strip_prefix(m.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl core::fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", level_name(self.default))?;
+        for (module, level) in self.directives.iter() {
+            write!(f, ",{}={}", module, level_name(*level))?;
+        }
+        Ok(())
+    }
+}
+
+// boot.cfgと同じ小文字で表示する
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+static FILTER: Mutex<LogFilter> = Mutex::new(LogFilter::new(LevelFilter::Trace));
+
+pub fn set_filter(filter: LogFilter) {
+    *FILTER.lock() = filter;
+}
+
+pub fn filter() -> LogFilter {
+    FILTER.lock().clone()
+}
+
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // 割り込みの中で書き換え中に当たったら出しておく
+        FILTER.try_lock().is_none_or(|filter| metadata.level() <= filter.level(metadata.target()))
     }
     fn log(&self, record: &Record) {
+        push_recent(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
         if let Some(filename) = record.file() {
             if let Some(linenum) = record.line() {
                 __log!("[{}]: {}@{}: {}", record.level(), filename, linenum, record.args());
@@ -24,7 +122,6 @@ impl log::Log for Logger {
         } else {
             __log!("[{}]: {}", record.level(), record.args());
         }
-
     }
     fn flush(&self) {}
 }
@@ -38,3 +135,113 @@ pub fn init_serial_and_logger() {
         panic!();
     });
 }
+
+// 出力レベルより下のものも含めて、最近のログを取っておく
+// ログビューアとパニック時の出力で使う
+pub const LOG_RING_LEN: usize = 256;
+const LOG_MODULE_LEN: usize = 48;
+const LOG_MESSAGE_LEN: usize = 160;
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    // 起動してからの時刻(time::init_clockの前は0)
+    pub time: crate::time::Instant,
+    pub level: Level,
+    // module_pathがなければtarget
+    pub module: String<LOG_MODULE_LEN>,
+    pub message: String<LOG_MESSAGE_LEN>,
+}
+
+static LOG_RING: Mutex<Deque<LogEntry, LOG_RING_LEN>> = Mutex::new(Deque::new());
+// これまでにリングに入れた数。ビューアが更新を知るのに使う
+static LOG_PUSHED: AtomicUsize = AtomicUsize::new(0);
+
+// 入りきらない分は捨てる
+struct Truncate<'a, const N: usize>(&'a mut String<N>);
+
+impl<const N: usize> Write for Truncate<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn push_recent(record: &Record) {
+    let mut entry = LogEntry {
+        time: crate::time::Instant::now(),
+        level: record.level(),
+        module: String::new(),
+        message: String::new(),
+    };
+    let _ = Truncate(&mut entry.module).write_str(record.module_path().unwrap_or(record.target()));
+    let _ = write!(Truncate(&mut entry.message), "{}", record.args());
+
+    // 割り込みの中からも呼ばれるので、取れなければあきらめる
+    let Some(mut ring) = LOG_RING.try_lock() else {
+        return;
+    };
+    if ring.is_full() {
+        ring.pop_front();
+    }
+    let _ = ring.push_back(entry);
+    LOG_PUSHED.fetch_add(1, Ordering::Release);
+}
+
+pub fn pushed_count() -> usize {
+    LOG_PUSHED.load(Ordering::Acquire)
+}
+
+// 新しいものからlast個を、古いものから順に渡す。ロックが取れなければfalse
+pub fn try_for_each_recent(last: usize, mut f: impl FnMut(&LogEntry)) -> bool {
+    let Some(ring) = LOG_RING.try_lock() else {
+        return false;
+    };
+    ring.iter().skip(ring.len().saturating_sub(last)).for_each(|e| f(e));
+    true
+}
+
+mod test {
+    #[test_case]
+    fn log_filter_picks_longest_module() {
+        use log::LevelFilter;
+        use super::LogFilter;
+        let filter = LogFilter::parse("warn,kernel::usb=trace,kernel::usb::keyboard=error,kernel::pci=info").unwrap();
+        assert_eq!(filter.level("kernel::timer"), LevelFilter::Warn);
+        assert_eq!(filter.level("kernel::usb"), LevelFilter::Trace);
+        assert_eq!(filter.level("kernel::usb::controller"), LevelFilter::Trace);
+        assert_eq!(filter.level("kernel::usb::keyboard"), LevelFilter::Error);
+        assert_eq!(filter.level("kernel::pci"), LevelFilter::Info);
+        // ::の区切りでしか一致しない
+        assert_eq!(filter.level("kernel::pcix"), LevelFilter::Warn);
+    }
+
+    #[test_case]
+    fn log_filter_without_default_is_off() {
+        use log::LevelFilter;
+        use super::LogFilter;
+        let filter = LogFilter::parse("kernel::usb=debug").unwrap();
+        assert_eq!(filter.level("kernel::usb::trb"), LevelFilter::Debug);
+        assert_eq!(filter.level("kernel::pci"), LevelFilter::Off);
+    }
+
+    #[test_case]
+    fn log_filter_rejects_invalid_items() {
+        use super::LogFilter;
+        assert_eq!(LogFilter::parse("info,kernel::usb=loud").unwrap_err(), "kernel::usb=loud");
+        assert_eq!(LogFilter::parse("verbose").unwrap_err(), "verbose");
+        assert!(LogFilter::parse("").is_err());
+    }
+
+    #[test_case]
+    fn log_filter_display_round_trips() {
+        use core::fmt::Write;
+        use super::LogFilter;
+        let mut s: heapless::String<64> = heapless::String::new();
+        write!(s, "{}", LogFilter::parse("Info, kernel::usb=TRACE").unwrap()).unwrap();
+        assert_eq!(s.as_str(), "info,kernel::usb=trace");
+    }
+}
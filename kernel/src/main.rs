@@ -13,9 +13,9 @@ use alloc::boxed::Box;
 use alloc::format;
 use kernel::preemptive::context::PreemptiveTask;
 use kernel::serial_println;
-use kernel::timer::Timer;
+use core::time::Duration;
 use kernel::timer::get_tick;
-use kernel::timer::timer_manager;
+use kernel::timer::{interval, sleep, timer_manager};
 use kernel::usb::controller::init_xhc;
 use log::{debug, error};
 
@@ -47,12 +47,10 @@ async fn counter(window: alloc::sync::Arc<spin::Mutex<kernel::window::Window>>)
     };
     WindowManager::draw_window(id);
 
-    let mut timeout = 200;
-    let mut value = 1;
-    let mut timer = Timer::new(timeout, value);
+    let mut ticks = interval(Duration::from_secs(1));
     loop {
-        let v = timer.await;
-        serial_println!("Timer: {} {}", timeout, v);
+        let at = ticks.tick().await;
+        serial_println!("Timer: {}", at);
         let id = {
             let mut lck = window.lock();
             lck.draw_basic_window("Hello Window");
@@ -60,16 +58,13 @@ async fn counter(window: alloc::sync::Arc<spin::Mutex<kernel::window::Window>>)
             lck.id()
         };
         WindowManager::draw_window(id);
-        timeout += 100;
-        value += 1;
-        timer = Timer::new(timeout, value);
         kernel::preemptive::context::test_func();
     }
 }
 
 async fn counter2() {
-    Timer::new(600, 100).await;
-    log::warn!("Timer: 600 100");
+    sleep(Duration::from_secs(6)).await;
+    log::warn!("Timer: 6 s");
 }
 
 fn sync_counter() -> ! {
@@ -85,16 +80,19 @@ kernel::entry!(kernel_main_new_stack);
 pub extern "sysv64" fn kernel_main_new_stack(config: *const common::Config) -> ! {
     // 初期化は割り込みなしにしておく
     unsafe { disable_interrupt() };
-    // safety: 信頼しないと始まらない
-    let config = unsafe { &*config };
+    // safety: headerが合っていれば信頼しないと始まらない
+    let config = unsafe { kernel::entry::check_config(config) };
     unsafe { kernel::panic::init_default_panic_print(&raw const config.frame_buffer_config); }
-    unsafe { kernel::backtrace::init_backtrace(config.base, config.symtab, config.symtab_num, config.strtab); }
+    unsafe { kernel::backtrace::init_backtrace(config.base, config.symtab, config.symtab_num, config.strtab, config.debug_line, config.debug_line_len); }
 
     kernel::logger::init_serial_and_logger();
-    log::set_max_level(log::LevelFilter::Warn);
+    unsafe { kernel::boot_config::init_boot_config(config.boot_config, config.boot_config_len); }
+    // すべてのログをリングバッファに残し、フィルタを通ったものだけを出力する
+    log::set_max_level(log::LevelFilter::Trace);
+    kernel::logger::set_filter(kernel::boot_config::log_filter());
     unsafe {
         kernel::segment::init_segment();
-        kernel::paging::setup_identity_page_table();
+        kernel::paging::setup_page_table(config);
         kernel::memory_manager::init_memory_manager(&config.memmap);
         kernel::allocator::init_allocator();
     }
@@ -111,10 +109,29 @@ pub extern "sysv64" fn kernel_main_new_stack(config: *const common::Config) -> !
 
     kernel::println!("hello");
 
-    let fadt = unsafe { kernel::acpi::get_fadt(config.acpi_table_ptr) };
+    kernel::graphics::init_video_modes(&config.video_modes);
+    if let Some(modes) = kernel::graphics::video_modes() {
+        for (i, m) in modes.modes().iter().enumerate() {
+            log::info!("video mode {}: {}x{} {:?}{}", i, m.horizontal_resolution, m.vertical_resolution, m.pixel_format, if i == modes.current { " (current)" } else { "" });
+        }
+    }
+    for s in config.segments.segments() {
+        log::debug!("kernel segment: {:#x}-{:#x} {}", s.addr, s.addr + s.size, s.flags);
+    }
+
+    if !unsafe { kernel::acpi::init_acpi(config.acpi_table_ptr) } {
+        log::warn!("ACPI tables are not found.");
+    }
+    kernel::aml::init_aml();
+    let fadt = kernel::acpi::fadt();
     if fadt.is_none() {
         log::warn!("FADT is not found.");
     }
+    // Local APICの場所もMADTから決まるので、タイマより先に読む
+    unsafe { kernel::ioapic::init_ioapic(kernel::acpi::madt()); }
+    unsafe { kernel::hpet::init_hpet(kernel::acpi::hpet()); }
+    kernel::time::init_clock(fadt);
+    kernel::rtc::init_rtc(fadt);
     initialize_apic_timer(fadt);
 
     let res = pci::scan_all_bus();
@@ -131,6 +148,16 @@ pub extern "sysv64" fn kernel_main_new_stack(config: *const common::Config) -> !
 
     unsafe { interrupt::init_interrupt(); }
 
+    let gdb_port = kernel::boot_config::gdb_port();
+    if let Some(port) = gdb_port {
+        if kernel::gdb::init(port) {
+            log::warn!("waiting for GDB on serial port {:#x}", port);
+            kernel::gdb::breakpoint();
+        } else {
+            log::warn!("serial port {:#x} for GDB is not found", port);
+        }
+    }
+
     let xhc = Box::leak(init_xhc(&devices).unwrap());
     xhc.run();
     xhc.configure_port();
@@ -139,11 +166,22 @@ pub extern "sysv64" fn kernel_main_new_stack(config: *const common::Config) -> !
     let (main_window_id, main_window) = WindowManager::new_window(160, 52, false, 300, 100, true);
     WindowManager::up_down(main_window_id, 1);
 
+    // ここから先のシリアルの送受信は割り込みで行う
+    // GDBがCOM1を使うなら、受信を取り合わないようにシェルは動かさない
+    let shell = gdb_port != Some(0x3f8) || !kernel::gdb::enabled();
+    if shell {
+        kernel::serial::init_serial_interrupt();
+    }
+
     let mut executor = task::executor::Executor::new();
     executor.spawn(task::Task::new(xhc.process_event()));
     executor.spawn(task::Task::new(counter(main_window)));
     executor.spawn(task::Task::new(timer_manager()));
     executor.spawn(task::Task::new(counter2()));
+    executor.spawn(task::Task::new(kernel::log_viewer::new(480, 200)));
+    if shell {
+        executor.spawn(task::Task::new(kernel::shell::serial_shell()));
+    }
     executor.spawn(task::Task::new(PreemptiveTask::new(sync_counter)));
     executor.run();
 }
@@ -1,5 +1,5 @@
 use spin::Mutex;
-use uefi::{boot::PAGE_SIZE, mem::memory_map::MemoryMap};
+use common::memory_map::{MemoryMap, PAGE_SIZE};
 
 use crate::make_error;
 
@@ -16,6 +16,10 @@ impl FrameID {
     pub const fn frame(&self) -> usize {
         self.0 * BYTES_PER_FRAME
     }
+    // direct map経由でこのフレームを指すポインタ
+    pub const fn as_ptr(&self) -> *mut u8 {
+        crate::paging::phys_to_virt(self.frame() as u64) as *mut u8
+    }
     fn add(&self, idx: usize) -> Self {
         FrameID(self.0 + idx)
     }
@@ -27,6 +31,12 @@ pub const FRAME_COUNT: usize = MAX_PHYSICAL_MEMORY_BYTES / BYTES_PER_FRAME;
 type MapLineType = usize;
 pub const BITS_PER_MAPLINE: usize = 8 * core::mem::size_of::<MapLineType>();
 
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub allocated: usize,
+}
+
 pub static MANAGER: Mutex<BitmapMemoryManager> = Mutex::new(BitmapMemoryManager::new()); // todo fix
 
 pub struct BitmapMemoryManager {
@@ -89,6 +99,16 @@ impl BitmapMemoryManager {
             start += i + 1;
         }
     }
+    // 管理している範囲のフレーム数と、そのうち使用中の数
+    pub fn stats(&self) -> FrameStats {
+        let mut allocated = 0;
+        for i in self.range_begin.0..self.range_end.0 {
+            if self.get_bit(&FrameID(i)) {
+                allocated += 1;
+            }
+        }
+        FrameStats { total: self.range_end.0 - self.range_begin.0, allocated }
+    }
     pub fn free(&mut self, start_frame: &FrameID, num_frame: usize) -> Result<(), crate::error::Error> {
         for i in 0..num_frame {
             self.set_bit(&start_frame.add(i), false);
@@ -97,10 +117,11 @@ impl BitmapMemoryManager {
     }
 }
 
-pub unsafe fn init_memory_manager(memmap: &uefi::mem::memory_map::MemoryMapOwned) {
+// safety: memmapが有効な記述子を指していること
+pub unsafe fn init_memory_manager(memmap: &MemoryMap) {
     let mut memory_manager = MANAGER.lock();
     let mut available_end: usize = 0;
-    for desc in memmap.entries() {
+    for desc in unsafe { memmap.entries() } {
         let phys_start = desc.phys_start as usize;
         let page_count = desc.page_count as usize;
         if available_end < phys_start {
@@ -110,9 +131,7 @@ pub unsafe fn init_memory_manager(memmap: &uefi::mem::memory_map::MemoryMapOwned
             )
         }
         let physical_end = phys_start + page_count * PAGE_SIZE;
-        if desc.ty == uefi::mem::memory_map::MemoryType::CONVENTIONAL ||
-           desc.ty == uefi::mem::memory_map::MemoryType::BOOT_SERVICES_CODE ||
-           desc.ty == uefi::mem::memory_map::MemoryType::BOOT_SERVICES_DATA {
+        if desc.ty.is_available() {
             available_end = physical_end;
         } else {
             memory_manager.mark_allocated(
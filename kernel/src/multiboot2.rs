@@ -0,0 +1,534 @@
+// Multiboot2(GRUB)から起動するための処理
+// GRUBはカーネルを32bitプロテクトモードで呼ぶので、ここで64bitモードに移行し、
+// 自分で再配置をしてからMultiboot2の情報をcommon::Configに詰め替えてkernel_mainを呼ぶ
+//
+// - ヘッダはファイル先頭32KiB以内にある必要がある。multiboot2.ldでELFヘッダの直後に置いている
+//   (`grub-file --is-x86-multiboot2`で確認できる)
+// - relocatableタグを付けているので、GRUBはe_entryをロードした分だけずらして呼ぶ
+// - e_entry(kernel_main)はUEFIのローダと共用で、entry!の先頭でどちらから来たかを判別する
+// - カーネルは物理アドレスと同じ下位半分のアドレスのまま動く(上位半分への配置とKASLRはUEFIのローダだけ)
+
+use core::cell::SyncUnsafeCell;
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::ptr::null;
+
+use common::elf::{Elf64Ehdr, Elf64Phdr, Elf64Rela, Elf64Sym, LoadSegmentList, R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, R_X86_64_RELATIVE, SHN_UNDEF};
+use common::memory_map::{MemoryDescriptor, MemoryMap, MemoryType, PAGE_SIZE};
+use common::writer_config::{FrameBufferConfig, PixelBitmask, PixelFormat, VideoMode, VideoModeList};
+use common::address::direct_map;
+use common::{BootInfoHeader, Config};
+
+const BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_NVS: u32 = 4;
+const MMAP_BADRAM: u32 = 5;
+
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+const MAX_DESCRIPTORS: usize = 256;
+
+unsafe extern "C" {
+    // 32bitモードの入口。entry!から参照する
+    pub fn multiboot2_entry32();
+    // entry!で定義される64bitモードの入口
+    fn kernel_main(config: *const Config) -> !;
+}
+
+core::arch::global_asm!(
+    ".pushsection .multiboot2, \"aR\"",
+    ".balign 8",
+    "2:",
+    ".long 0xe85250d6", // magic
+    ".long 0", // i386
+    ".long 3f - 2b",
+    ".long 0x100000000 - (0xe85250d6 + (3f - 2b))",
+    // framebuffer tag: 解像度は任意、32bppを希望(optional)
+    ".balign 8",
+    ".short 5, 1",
+    ".long 20",
+    ".long 0, 0, 32",
+    // relocatable tag: 2MiB以上4GiB未満の2MiB境界にできるだけ低く置く
+    ".balign 8",
+    ".short 10, 0",
+    ".long 24",
+    ".long 0x200000",
+    ".long 0xffffffff",
+    ".long 0x200000",
+    ".long 1",
+    // end tag
+    ".balign 8",
+    ".short 0, 0",
+    ".long 8",
+    "3:",
+    ".popsection",
+
+    ".pushsection .multiboot2.text, \"ax\"",
+    ".code32",
+    ".global multiboot2_entry32",
+    "multiboot2_entry32:",
+    "cli",
+    "cld",
+    "cmp eax, {magic}",
+    "jne 9f",
+    "mov edi, ebx", // Multiboot2 information
+    // ESPは未定義なので、自分の位置を知るまで低位のメモリを借りる
+    "mov esp, 0x8000",
+    "call 1f",
+    // 使うシンボルのmultiboot2_offsetsからの差
+    // LLVMはメモリオペランドや即値に二つのシンボルを書けないので、ここに置いてebp相対で読む
+    "multiboot2_offsets:",
+    ".long multiboot2_stack_top - multiboot2_offsets", // +0
+    ".long multiboot2_pml4 - multiboot2_offsets", // +4
+    ".long multiboot2_pdpt - multiboot2_offsets", // +8
+    ".long multiboot2_pd - multiboot2_offsets", // +12
+    ".long multiboot2_gdt - multiboot2_offsets", // +16
+    ".long multiboot2_gdtr - multiboot2_offsets", // +20
+    ".long 6f - multiboot2_offsets", // +24
+    "1:",
+    "pop ebp", // ebp = 実行時のmultiboot2_offsetsのアドレス
+    "mov esp, [ebp + 0]",
+    "add esp, ebp",
+
+    // レガシーPICからの割り込みを止める
+    "mov al, 0xff",
+    "out 0xa1, al",
+    "out 0x21, al",
+
+    // 0-4GiBを2MiBページでidentity mapする(カーネルイメージもこの中にある)
+    "mov esi, [ebp + 12]",
+    "add esi, ebp",
+    "mov edx, [ebp + 8]",
+    "add edx, ebp",
+    "xor ecx, ecx",
+    "4:",
+    "mov eax, ecx",
+    "shl eax, 12",
+    "add eax, esi",
+    "or eax, 3",
+    "mov [edx + ecx * 8], eax",
+    "mov dword ptr [edx + ecx * 8 + 4], 0",
+    "inc ecx",
+    "cmp ecx, 4",
+    "jb 4b",
+    "xor ecx, ecx",
+    "5:",
+    "mov eax, ecx",
+    "shl eax, 21",
+    "or eax, 0x83",
+    "mov [esi + ecx * 8], eax",
+    "mov eax, ecx",
+    "shr eax, 11",
+    "mov [esi + ecx * 8 + 4], eax",
+    "inc ecx",
+    "cmp ecx, 2048",
+    "jb 5b",
+    "mov eax, [ebp + 8]",
+    "add eax, ebp",
+    "or eax, 3",
+    "mov edx, [ebp + 4]",
+    "add edx, ebp",
+    "mov [edx], eax",
+    "mov dword ptr [edx + 4], 0",
+    // 同じものをdirect map(common::address)にも置く
+    "mov [edx + {direct_map_index} * 8], eax",
+    "mov dword ptr [edx + {direct_map_index} * 8 + 4], 0",
+
+    // PAE, LME, PGの順に有効にしてlong modeへ
+    "mov cr3, edx",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, 1 << 8",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, (1 << 31) | 1",
+    "mov cr0, eax",
+
+    "mov eax, [ebp + 16]",
+    "add eax, ebp",
+    "mov ecx, [ebp + 20]",
+    "add ecx, ebp",
+    "mov [ecx + 2], eax",
+    "lgdt [ecx]",
+    "mov eax, [ebp + 24]",
+    "add eax, ebp",
+    "push 0x08",
+    "push eax",
+    "retf",
+    "9:",
+    "cli",
+    "hlt",
+    "jmp 9b",
+
+    ".code64",
+    "6:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov edi, edi",
+    // 再配置前なのでアドレスはすべてrip相対で求めて渡す
+    "lea rsi, [rip + __ehdr_start]",
+    "lea rdx, [rip + _DYNAMIC]",
+    "lea rcx, [rip + _end]",
+    "xor ebp, ebp",
+    "call {start}",
+    "7:",
+    "cli",
+    "hlt",
+    "jmp 7b",
+    ".popsection",
+
+    ".pushsection .multiboot2.data, \"aw\"",
+    ".balign 8",
+    "multiboot2_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff", // 64bit code
+    ".quad 0x00cf92000000ffff", // data
+    "multiboot2_gdtr:",
+    ".short 3 * 8 - 1",
+    ".quad 0",
+    ".popsection",
+
+    ".pushsection .multiboot2.bss, \"aw\", @nobits",
+    ".balign 4096",
+    "multiboot2_pml4:",
+    ".skip 4096",
+    "multiboot2_pdpt:",
+    ".skip 4096",
+    "multiboot2_pd:",
+    ".skip 4096 * 4",
+    "multiboot2_stack:",
+    ".skip 4096 * 4",
+    "multiboot2_stack_top:",
+    ".popsection",
+
+    magic = const BOOTLOADER_MAGIC,
+    direct_map_index = const (common::address::DIRECT_MAP_BASE >> 39) & 0x1ff,
+    start = sym multiboot2_start,
+);
+
+static mut CONFIG: MaybeUninit<Config> = MaybeUninit::uninit();
+static DESCRIPTORS: SyncUnsafeCell<[MemoryDescriptor; MAX_DESCRIPTORS]> = SyncUnsafeCell::new([
+    MemoryDescriptor { ty: MemoryType::RESERVED, phys_start: 0, page_count: 0, attribute: 0 }; MAX_DESCRIPTORS
+]);
+
+#[repr(C)]
+struct Elf64Dyn {
+    tag: i64,
+    val: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Shdr {
+    name: u32,
+    ty: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_SYMTAB: i64 = 6;
+const SHT_SYMTAB: u32 = 2;
+
+// UEFIのローダがしている再配置を自分でする
+// 再配置前に動くので、staticやvtableなど絶対アドレスを持つものに触ってはいけない
+// (panicも使えないので、未対応の再配置は無視する)
+unsafe fn self_relocate(base: usize, dynamic: *const Elf64Dyn) {
+    let mut rela = 0;
+    let mut rela_size = 0;
+    let mut rela_ent = 0;
+    let mut symtab = 0;
+    let mut d = dynamic;
+    loop {
+        let entry = unsafe { &*d };
+        if entry.tag == DT_NULL {
+            break;
+        } else if entry.tag == DT_RELA {
+            rela = entry.val as usize;
+        } else if entry.tag == DT_RELASZ {
+            rela_size = entry.val as usize;
+        } else if entry.tag == DT_RELAENT {
+            rela_ent = entry.val as usize;
+        } else if entry.tag == DT_SYMTAB {
+            symtab = entry.val as usize;
+        }
+        d = unsafe { d.add(1) };
+    }
+    if rela == 0 || rela_ent == 0 {
+        return;
+    }
+    // 未定義のシンボルは自分の中には解決先がないので0にする
+    let symbol = |index: u32| -> u64 {
+        if symtab == 0 {
+            return 0;
+        }
+        let sym = unsafe { read::<Elf64Sym>(base + symtab + index as usize * size_of::<Elf64Sym>()) };
+        if sym.st_shndx == SHN_UNDEF { 0 } else { base as u64 + sym.st_value }
+    };
+    let mut offset = 0;
+    while offset < rela_size {
+        let r = unsafe { read::<Elf64Rela>(base + rela + offset) };
+        let to = (base + r.r_offset as usize) as *mut u64;
+        let value = match r.ty() {
+            R_X86_64_RELATIVE => Some((base as u64).wrapping_add_signed(r.r_addend)),
+            R_X86_64_64 => Some(symbol(r.sym()).wrapping_add_signed(r.r_addend)),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => Some(symbol(r.sym())),
+            _ => None,
+        };
+        if let Some(value) = value {
+            unsafe { to.write_unaligned(value); }
+        }
+        offset += rela_ent;
+    }
+}
+
+unsafe extern "sysv64" fn multiboot2_start(info: usize, base: usize, dynamic: *const Elf64Dyn, end: usize) -> ! {
+    unsafe {
+        self_relocate(base, dynamic);
+        let config = translate(info, base, end);
+        kernel_main(config)
+    }
+}
+
+struct Tags {
+    ptr: usize,
+    end: usize,
+}
+
+impl Iterator for Tags {
+    // (type, 先頭アドレス, size)
+    type Item = (u32, usize, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ptr + 8 > self.end {
+            return None;
+        }
+        let ty = unsafe { *(self.ptr as *const u32) };
+        let size = unsafe { *((self.ptr + 4) as *const u32) } as usize;
+        if ty == TAG_END || size < 8 {
+            return None;
+        }
+        let tag = (ty, self.ptr, size);
+        self.ptr += (size + 7) & !7;
+        Some(tag)
+    }
+}
+
+unsafe fn read<T: Copy>(addr: usize) -> T {
+    unsafe { (addr as *const T).read_unaligned() }
+}
+
+// Multiboot2の情報をUEFIローダと同じ形に詰め替える
+// 下位n bitが立った値。タグの値は信用できないので、32以上なら全部立てる
+fn low_bits(n: u32) -> u32 {
+    1u32.checked_shl(n).map_or(u32::MAX, |v| v - 1)
+}
+
+unsafe fn translate(info: usize, base: usize, end: usize) -> *const Config {
+    let total_size = unsafe { read::<u32>(info) } as usize;
+    let tags = || Tags { ptr: info + 8, end: info + total_size };
+
+    let mut frame_buffer_config = FrameBufferConfig {
+        frame_buffer: core::ptr::null_mut(),
+        pixels_per_scan_line: 0,
+        horizontal_resolution: 0,
+        vertical_resolution: 0,
+        pixel_format: PixelFormat::BltOnly,
+    };
+    let mut acpi_table_ptr: *const c_void = null();
+    let mut cmdline: &'static str = "";
+    let (mut symtab, mut symtab_num, mut strtab) = (null(), 0, null());
+    let (mut debug_line, mut debug_line_len) = (null(), 0);
+    let descriptors = unsafe { &mut *DESCRIPTORS.get() };
+    let mut len = 0;
+
+    // カーネル自身、Multiboot2の情報、低位1MiBは使わせない
+    let mut reserved = [
+        (0u64, 0x100000u64, MemoryType::RESERVED),
+        (base as u64, end as u64, MemoryType::LOADER_CODE),
+        (info as u64, (info + total_size) as u64, MemoryType::LOADER_DATA),
+    ];
+    reserved.sort_unstable_by_key(|r| r.0);
+
+    for (ty, tag, size) in tags() {
+        match ty {
+            TAG_CMDLINE => {
+                let s = unsafe { core::ffi::CStr::from_ptr((tag + 8) as *const core::ffi::c_char) };
+                cmdline = s.to_str().unwrap_or("");
+            }
+            TAG_MMAP => {
+                let entry_size = unsafe { read::<u32>(tag + 8) } as usize;
+                let mut entry = tag + 16;
+                while entry_size != 0 && entry + entry_size <= tag + size {
+                    let addr = unsafe { read::<u64>(entry) };
+                    let length = unsafe { read::<u64>(entry + 8) };
+                    let ty = unsafe { read::<u32>(entry + 16) };
+                    let memory_type = match ty {
+                        MMAP_AVAILABLE => MemoryType::CONVENTIONAL,
+                        MMAP_ACPI_RECLAIMABLE => MemoryType::ACPI_RECLAIM,
+                        MMAP_NVS => MemoryType::ACPI_NON_VOLATILE,
+                        MMAP_BADRAM => MemoryType::UNUSABLE,
+                        _ => MemoryType::RESERVED,
+                    };
+                    if memory_type == MemoryType::CONVENTIONAL {
+                        push_available(descriptors, &mut len, addr, addr + length, &reserved);
+                    } else {
+                        push_descriptor(descriptors, &mut len, memory_type, addr, addr + length);
+                    }
+                    entry += entry_size;
+                }
+            }
+            TAG_FRAMEBUFFER => {
+                let addr = unsafe { read::<u64>(tag + 8) };
+                let pitch = unsafe { read::<u32>(tag + 16) } as usize;
+                let width = unsafe { read::<u32>(tag + 20) } as usize;
+                let height = unsafe { read::<u32>(tag + 24) } as usize;
+                let bpp = unsafe { read::<u8>(tag + 28) };
+                let fb_type = unsafe { read::<u8>(tag + 29) };
+                if fb_type == FRAMEBUFFER_TYPE_RGB && bpp % 8 == 0 && bpp != 0 {
+                    let field = |offset: usize| {
+                        let position = unsafe { read::<u8>(tag + 32 + offset) } as u32;
+                        let size = unsafe { read::<u8>(tag + 33 + offset) } as u32;
+                        low_bits(size).checked_shl(position).unwrap_or(0)
+                    };
+                    let mask = PixelBitmask { red: field(0), green: field(2), blue: field(4), reserved: 0 };
+                    frame_buffer_config = FrameBufferConfig {
+                        frame_buffer: direct_map(addr) as *mut u8,
+                        pixels_per_scan_line: pitch / (bpp as usize / 8),
+                        horizontal_resolution: width,
+                        vertical_resolution: height,
+                        pixel_format: match (bpp, mask.red, mask.green, mask.blue) {
+                            (32, 0xff, 0xff00, 0xff0000) => PixelFormat::Rgb,
+                            (32, 0xff0000, 0xff00, 0xff) => PixelFormat::Bgr,
+                            _ => PixelFormat::Bitmask(PixelBitmask {
+                                // bppまで使っていることを示すため余りをreservedにする
+                                reserved: low_bits(bpp as u32) & !(mask.red | mask.green | mask.blue),
+                                ..mask
+                            }),
+                        },
+                    };
+                }
+            }
+            TAG_ELF_SECTIONS => {
+                let num = unsafe { read::<u32>(tag + 8) } as usize;
+                let entsize = unsafe { read::<u32>(tag + 12) } as usize;
+                let shstrndx = unsafe { read::<u32>(tag + 16) } as usize;
+                let section = |i: usize| unsafe { read::<Elf64Shdr>(tag + 20 + i * entsize) };
+                if entsize >= size_of::<Elf64Shdr>() {
+                    // GRUBはALLOCでないセクションも読み込んでaddrを埋めてくれる
+                    let shstrtab = if shstrndx < num { section(shstrndx).addr as usize } else { 0 };
+                    for i in 0..num {
+                        let sh = section(i);
+                        if shstrtab != 0 && sh.addr != 0 && unsafe { read::<[u8; 12]>(shstrtab + sh.name as usize) } == *b".debug_line\0" {
+                            debug_line = direct_map(sh.addr) as *const u8;
+                            debug_line_len = sh.size as usize;
+                        }
+                        if sh.ty == SHT_SYMTAB && sh.addr != 0 && sh.entsize != 0 && (sh.link as usize) < num {
+                            symtab = direct_map(sh.addr) as *const c_void;
+                            symtab_num = (sh.size / sh.entsize) as usize;
+                            strtab = direct_map(section(sh.link as usize).addr) as *const c_void;
+                        }
+                    }
+                }
+            }
+            // ACPI 2.0以降のRSDPを優先する
+            TAG_ACPI_OLD if acpi_table_ptr.is_null() => {
+                acpi_table_ptr = direct_map((tag + 8) as u64) as *const c_void;
+            }
+            TAG_ACPI_NEW => {
+                acpi_table_ptr = direct_map((tag + 8) as u64) as *const c_void;
+            }
+            _ => {}
+        }
+    }
+    for &(start, end, ty) in reserved.iter() {
+        push_descriptor(descriptors, &mut len, ty, start, end);
+    }
+    descriptors[..len].sort_unstable_by_key(|d| d.phys_start);
+
+    // ELFヘッダとprogram headerは先頭のPT_LOADに含まれてロードされている
+    let ehdr = unsafe { read::<Elf64Ehdr>(base) };
+    let phdrs = unsafe { core::slice::from_raw_parts((base + ehdr.e_phoff as usize) as *const Elf64Phdr, ehdr.e_phnum as usize) };
+    let segments = LoadSegmentList::from_program_headers(phdrs, base);
+
+    let mut video_modes = VideoModeList::empty();
+    video_modes.push(VideoMode {
+        horizontal_resolution: frame_buffer_config.horizontal_resolution,
+        vertical_resolution: frame_buffer_config.vertical_resolution,
+        pixel_format: frame_buffer_config.pixel_format,
+    });
+
+    let config = unsafe { &mut *(&raw mut CONFIG) };
+    config.write(Config {
+        header: BootInfoHeader::new(),
+        frame_buffer_config,
+        memmap: MemoryMap { descriptors: descriptors.as_ptr(), len },
+        acpi_table_ptr,
+        base,
+        kernel_phys_base: base,
+        symtab,
+        symtab_num,
+        strtab,
+        debug_line,
+        debug_line_len,
+        video_modes,
+        boot_config: direct_map(cmdline.as_ptr() as u64) as *const u8,
+        boot_config_len: cmdline.len(),
+        segments,
+    })
+}
+
+fn push_descriptor(descriptors: &mut [MemoryDescriptor], len: &mut usize, ty: MemoryType, start: u64, end: u64) {
+    let start = start & !(PAGE_SIZE as u64 - 1);
+    let end = (end + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    if start >= end || *len >= descriptors.len() {
+        return;
+    }
+    descriptors[*len] = MemoryDescriptor { ty, phys_start: start, page_count: (end - start) / PAGE_SIZE as u64, attribute: 0 };
+    *len += 1;
+}
+
+// 使える領域からreservedと重なる部分を除いて追加する
+// 使える領域はページ内に収まるように内側に丸める
+fn push_available(descriptors: &mut [MemoryDescriptor], len: &mut usize, start: u64, end: u64, reserved: &[(u64, u64, MemoryType)]) {
+    let mut start = (start + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    let end = end & !(PAGE_SIZE as u64 - 1);
+    for &(r_start, r_end, _) in reserved {
+        if r_end <= start || end <= r_start {
+            continue;
+        }
+        if start < r_start {
+            push_descriptor(descriptors, len, MemoryType::CONVENTIONAL, start, r_start & !(PAGE_SIZE as u64 - 1));
+        }
+        start = u64::max(start, (r_end + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1));
+    }
+    if start < end {
+        push_descriptor(descriptors, len, MemoryType::CONVENTIONAL, start, end);
+    }
+}
@@ -1,11 +1,16 @@
 use core::{arch::asm, cell::SyncUnsafeCell};
 
-const PAGE_DIRECTORY_COUNT: usize = 64;
+use common::address::{DIRECT_MAP_BASE, DIRECT_MAP_SIZE};
+use conquer_once::spin::OnceCell;
+
+const PAGE_DIRECTORY_COUNT: usize = DIRECT_MAP_SIZE / PAGE_SIZE_1G as usize;
 
 const PAGE_SIZE_4K: u64 = 4096;
 const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
 const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
 
+const DIRECT_MAP_PML4_INDEX: usize = (DIRECT_MAP_BASE >> 39) & 0x1ff;
+
 #[repr(align(4096))]
 struct AlignedArray([u64; 512]);
 
@@ -16,21 +21,97 @@ static PML4_TABLE: SyncUnsafeCell<AlignedArray> = SyncUnsafeCell::new(AlignedArr
 static PDP_TABLE: SyncUnsafeCell<AlignedArray> = SyncUnsafeCell::new(AlignedArray([0; 512]));
 static PAGE_DIRECTORY:  SyncUnsafeCell<PageDir> = SyncUnsafeCell::new(PageDir([[0; 512]; PAGE_DIRECTORY_COUNT]));
 
-pub unsafe fn setup_identity_page_table() {
+// カーネルイメージの仮想アドレスと物理アドレスの対応
+struct KernelImage {
+    base: usize,
+    phys_base: u64,
+    size: usize,
+}
+
+static KERNEL_IMAGE: OnceCell<KernelImage> = OnceCell::uninit();
+
+// 物理アドレスをカーネルから触れる仮想アドレスにする
+pub const fn phys_to_virt(phys: u64) -> usize {
+    common::address::direct_map(phys)
+}
+
+// デバイスに渡すためなどに、仮想アドレスを物理アドレスに戻す
+// direct mapとカーネルイメージ(static変数)以外は線形でないので扱えない
+pub fn virt_to_phys(virt: usize) -> u64 {
+    if (DIRECT_MAP_BASE..DIRECT_MAP_BASE + DIRECT_MAP_SIZE).contains(&virt) {
+        return (virt - DIRECT_MAP_BASE) as u64;
+    }
+    if let Some(image) = KERNEL_IMAGE.get() {
+        if (image.base..image.base + image.size).contains(&virt) {
+            return image.phys_base + (virt - image.base) as u64;
+        }
+    }
+    panic!("virt_to_phys: {:x} is not linearly mapped", virt);
+}
+
+// 今のページテーブルを引いて物理アドレスにする。マップされていなければNone
+// ページフォルトを起こせないところ(デバッガなど)で、触ってよいかを確かめるのに使う
+pub fn translate(virt: usize) -> Option<u64> {
+    const PRESENT: u64 = 1;
+    const HUGE: u64 = 1 << 7;
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    let virt = virt as u64;
+    let mut table = get_cr3() & ADDRESS_MASK;
+    for (level, page_size) in [(39, 0), (30, PAGE_SIZE_1G), (21, PAGE_SIZE_2M), (12, PAGE_SIZE_4K)] {
+        let index = (virt >> level) & 0x1ff;
+        let entry = unsafe { (phys_to_virt(table) as *const u64).add(index as usize).read_volatile() };
+        if entry & PRESENT == 0 {
+            return None;
+        }
+        if level == 12 || (page_size != 0 && entry & HUGE != 0) {
+            let page_size = if level == 12 { PAGE_SIZE_4K } else { page_size };
+            return Some((entry & ADDRESS_MASK & !(page_size - 1)) + (virt & (page_size - 1)));
+        }
+        table = entry & ADDRESS_MASK;
+    }
+    None
+}
+
+// direct mapを作り、カーネルイメージはブートローダが作ったマップを引き継ぐ
+// 下位半分はユーザ空間のために空けるので、ブートローダのidentity mapはここで外れる
+// safety: configのカーネルイメージの情報が正しく、以降ブートローダのページテーブルを解放しないこと
+pub unsafe fn setup_page_table(config: &common::Config) {
+    let size = config.segments.segments().iter().map(|s| s.addr + s.size - config.base).max().unwrap_or(0);
+    let image = KernelImage { base: config.base, phys_base: config.kernel_phys_base as u64, size };
+    if KERNEL_IMAGE.try_init_once(|| image).is_err() {
+        log::warn!("page table is already initialized");
+        return;
+    }
+    let kernel_pml4_index = (config.base >> 39) & 0x1ff;
+    assert_ne!(kernel_pml4_index, DIRECT_MAP_PML4_INDEX, "kernel overlaps the direct map");
+
     let pml4 = PML4_TABLE.get();
     let pdp = PDP_TABLE.get();
     let page_directory = PAGE_DIRECTORY.get();
 
     unsafe {
-        (*pml4).0[0] = (pdp as u64) | 0x3;
+        (*pml4).0[DIRECT_MAP_PML4_INDEX] = virt_to_phys(pdp as usize) | 0x3;
         for i_pdpt in 0..PAGE_DIRECTORY_COUNT {
-            (*pdp).0[i_pdpt] = (&raw mut (*page_directory).0[i_pdpt] as u64) | 0x3;
+            (*pdp).0[i_pdpt] = virt_to_phys(&raw mut (*page_directory).0[i_pdpt] as usize) | 0x3;
             for i_pd in 0..512 {
                 (*page_directory).0[i_pdpt][i_pd] = i_pdpt as u64 * PAGE_SIZE_1G + i_pd as u64 * PAGE_SIZE_2M | 0x83;
             }
         }
-        set_cr3(pml4 as u64);
+        let current = &*(phys_to_virt(get_cr3() & !0xfff) as *const AlignedArray);
+        (*pml4).0[kernel_pml4_index] = current.0[kernel_pml4_index];
+        set_cr3(virt_to_phys(pml4 as usize));
+    }
+}
+
+fn get_cr3() -> u64 {
+    let value;
+    unsafe {
+        asm!(
+            "mov {}, cr3",
+            out(reg) value,
+        )
     }
+    value
 }
 
 unsafe fn set_cr3(value: u64) {
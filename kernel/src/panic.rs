@@ -6,7 +6,7 @@ use core::ptr::null;
 
 use common::writer_config::FrameBufferConfig;
 
-use crate::graphics::bits_per_pixel;
+use crate::graphics::{PixelColor, bits_per_pixel, pack_pixel};
 use crate::serial_println;
 use crate::exit_qemu;
 use crate::QemuExitCode;
@@ -30,11 +30,19 @@ static mut PANIC_WRITER: PanicWriter = PanicWriter { ptr: null(), x: 0, y: 0 };
 
 impl Write for PanicWriter {
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        let Ok(_) = (unsafe { bits_per_pixel((*self.ptr).pixel_format) }) else {
+        let format = unsafe { (*self.ptr).pixel_format };
+        let (Ok(per_pixel), Ok(white)) = (bits_per_pixel(format), pack_pixel(format, PixelColor::WHITE)) else {
             return Ok(())
         };
-        // 現状bits_per_pixelは32しか返ってこないのでOK
-        let buffer = unsafe { (*self.ptr).frame_buffer as *mut u32 };
+        // 16/24bitにも対応するため1byteずつ書く
+        let bytes_per_pixel = per_pixel / 8;
+        let buffer = unsafe { (*self.ptr).frame_buffer };
+        let put = |idx: usize, value: u32| {
+            let bytes = value.to_le_bytes();
+            for i in 0..bytes_per_pixel {
+                unsafe { *buffer.add(bytes_per_pixel * idx + i) = bytes[i] }
+            }
+        };
         for c in s.bytes() {
             if c == b'\n' {
                 self.x = 2;
@@ -52,10 +60,11 @@ impl Write for PanicWriter {
                 for dy in 0..16 {
                     let val = unsafe { f.get_unchecked(dy) };
                     for dx in 0..8 {
+                        let idx = unsafe { (*self.ptr).pixels_per_scan_line * (self.y + dy) + self.x + dx };
                         if (val << dx) & 0x80 != 0 {
-                            unsafe { *buffer.add((*self.ptr).pixels_per_scan_line * (self.y + dy) + self.x + dx) = 0xffffffff }
+                            put(idx, white);
                         } else {
-                            unsafe { *buffer.add((*self.ptr).pixels_per_scan_line * (self.y + dy) + self.x + dx) = 0 }
+                            put(idx, 0);
                         }
                     }
                 }
@@ -80,12 +89,24 @@ pub unsafe fn default_panic_print<T: Display>(val: T) {
     }
 }
 
+// パニック時に出す直近のログの数
+pub const PANIC_LOG_ENTRIES: usize = 32;
+
 pub unsafe fn default_panic_handler(info: &PanicInfo) -> ! {
+    let regs = crate::crash_dump::Registers::capture();
     unsafe {
         disable_interrupt();
-        crate::backtrace::print_backtrace();
         serial_println!("{}", info);
+        // バックトレースなどはダンプに含まれる
+        crate::crash_dump::write_crash_dump(info, &regs);
         default_panic_print(info);
+        // 画面にはダンプが出ないので、直前のログもここに出す
+        default_panic_print("\n\nrecent logs:\n");
+        crate::logger::try_for_each_recent(PANIC_LOG_ENTRIES, |entry| {
+            default_panic_print(format_args!("[{:>12}] {:<5} {}: {}\n", entry.time, entry.level, entry.module, entry.message));
+        });
+        // GDBがつながっていれば、ここで調べられる
+        crate::gdb::enter_from_panic(&regs);
         loop {
             asm!("hlt");
         }
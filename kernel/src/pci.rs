@@ -3,7 +3,7 @@ use alloc::vec::Vec;
 use spin::Mutex;
 use crate::error::*;
 use crate::make_error;
-use crate::io_port::{ind, outd};
+use crate::io_port::{inb, ind, inw, outb, outd, outw};
 
 const CONFIG_ADDR: u16 = 0x0cf8;
 const CONFIG_DATA: u16 = 0x0cfc;
@@ -24,6 +24,9 @@ impl Device {
     pub fn read_vendor_id(&self) -> u16 {
         unsafe { read_vendor_id(self.bus, self.device, self.func) }
     }
+    pub fn read_device_id(&self) -> u16 {
+        unsafe { read_device_id(self.bus, self.device, self.func) }
+    }
     pub fn bus(&self) -> u8 {
         self.bus
     }
@@ -59,6 +62,15 @@ pub struct ClassCode {
 }
 
 impl ClassCode {
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+    pub fn sub(&self) -> u8 {
+        self.sub
+    }
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
     pub fn match1(&self, b: u8) -> bool {
         b == self.base
     }
@@ -149,6 +161,31 @@ pub unsafe fn read_data() -> u32 {
     unsafe { ind(CONFIG_DATA) }
 }
 
+// widthバイト(1, 2, 4)をreg_addrから読み書きする。CONFIG_DATAの途中のバイトから読めば、4バイトに揃っていなくてよい
+pub unsafe fn read_config(bus: u8, device: u8, func: u8, reg_addr: u8, width: u8) -> u32 {
+    let port = CONFIG_DATA + (reg_addr & 3) as u16;
+    unsafe {
+        write_address(make_address(bus, device, func, reg_addr));
+        match width {
+            1 => inb(port) as u32,
+            2 => inw(port) as u32,
+            _ => read_data(),
+        }
+    }
+}
+
+pub unsafe fn write_config(bus: u8, device: u8, func: u8, reg_addr: u8, width: u8, value: u32) {
+    let port = CONFIG_DATA + (reg_addr & 3) as u16;
+    unsafe {
+        write_address(make_address(bus, device, func, reg_addr));
+        match width {
+            1 => outb(port, value as u8),
+            2 => outw(port, value as u16),
+            _ => write_data(value),
+        }
+    }
+}
+
 pub unsafe fn read_vendor_id(bus: u8, device: u8, func: u8) -> u16 {
     unsafe {
         write_address(make_address(bus, device, func, 0x00));
@@ -0,0 +1,194 @@
+// 電源断と再起動
+// 電源断はACPIのS5(soft-off)で、FADTのPM1制御レジスタにDSDTの\_S5に書かれた値を書く
+// \_S5はAMLのインタプリタで評価し、読み込めていなければバイト列から探す
+// 再起動はFADTのリセットレジスタ、キーボードコントローラ、トリプルフォルトの順に試す
+
+use core::arch::asm;
+
+use crate::acpi::{FADT, GenericAddress};
+use crate::io_port::{inb, inw, outb, outw};
+use crate::paging::phys_to_virt;
+
+// PM1制御レジスタ
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// AML
+const NAME_OP: u8 = 0x08;
+const ROOT_CHAR: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+
+// 書いてすぐに止まらなかったときにあきらめるまでの時間(ms)
+const WAIT_MS: u32 = 500;
+
+// DSDTからName(_S5, Package(){SLP_TYPa, SLP_TYPb, ...})を探す
+// AMLを解釈せず、バイト列の並びだけを見る
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let pos = (0..aml.len().saturating_sub(3)).find(|&i| {
+        &aml[i..i + 4] == b"_S5_" && (i >= 1 && aml[i - 1] == NAME_OP || i >= 2 && aml[i - 1] == ROOT_CHAR && aml[i - 2] == NAME_OP)
+    })?;
+    let package = aml.get(pos + 4..)?;
+    if *package.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLengthは先頭の上位2bitが後に続くバイト数
+    let pkg_length_len = 1 + (*package.get(1)? >> 6) as usize;
+    // PackageOp, PkgLength, NumElementsの後に要素が並ぶ
+    let elements = package.get(1 + pkg_length_len + 1..)?;
+    let (a, rest) = byte_data(elements)?;
+    let b = byte_data(rest).map_or(0, |(b, _)| b);
+    Some((a, b))
+}
+
+fn byte_data(aml: &[u8]) -> Option<(u8, &[u8])> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, &aml[1..])),
+        ONE_OP => Some((1, &aml[1..])),
+        BYTE_PREFIX => Some((*aml.get(1)?, &aml[2..])),
+        _ => None,
+    }
+}
+
+fn wait(fadt: Option<&FADT>, ms: u32) {
+    match fadt {
+        Some(fadt) => fadt.wait_milliseconds(ms),
+        // 目安
+        None => for _ in 0..ms * 100_000 { core::hint::spin_loop() },
+    }
+}
+
+// ファームウェアがACPIモードにしていなければ、SMIで切り替えてもらう
+unsafe fn enable_acpi(fadt: &FADT) {
+    let pm1a = fadt.pm1a_cnt_blk as u16;
+    if unsafe { inw(pm1a) } & SCI_EN != 0 || fadt.smi_cmd == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe { outb(fadt.smi_cmd as u16, fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { inw(pm1a) } & SCI_EN != 0 {
+            return;
+        }
+        fadt.wait_milliseconds(10);
+    }
+    log::warn!("failed to enable ACPI mode");
+}
+
+unsafe fn write_sleep_type(port: u16, slp_typ: u8) {
+    unsafe {
+        let value = inw(port) & !SLP_TYP_MASK;
+        outw(port, value | (slp_typ as u16) << SLP_TYP_SHIFT | SLP_EN);
+    }
+}
+
+fn try_power_off() -> Result<(), &'static str> {
+    let fadt = crate::acpi::fadt().ok_or("FADT is not found")?;
+    let (slp_typa, slp_typb) = match crate::aml::with(|aml| aml.sleep_type(5)) {
+        Some(Ok(s5)) => s5,
+        _ => {
+            let dsdt = crate::acpi::dsdt().ok_or("DSDT is not found")?;
+            find_s5(dsdt.data()).ok_or("\\_S5 is not found")?
+        }
+    };
+    if fadt.pm1a_cnt_blk == 0 {
+        return Err("PM1a control block is not found");
+    }
+    unsafe {
+        enable_acpi(fadt);
+        crate::interrupt::disable_interrupt();
+        write_sleep_type(fadt.pm1a_cnt_blk as u16, slp_typa);
+        if fadt.pm1b_cnt_blk != 0 {
+            write_sleep_type(fadt.pm1b_cnt_blk as u16, slp_typb);
+        }
+    }
+    wait(Some(fadt), WAIT_MS);
+    Err("the machine is still running")
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt") }
+    }
+}
+
+// ACPIのS5で電源を切る。切れなければ止まるだけ
+pub fn power_off() -> ! {
+    log::info!("power off");
+    if let Err(e) = try_power_off() {
+        log::error!("failed to power off: {}", e);
+    }
+    halt()
+}
+
+unsafe fn write_reset_register(reg: GenericAddress, value: u8) -> bool {
+    let address = reg.address;
+    match reg.space_id {
+        ADDRESS_SPACE_MEMORY => unsafe { (phys_to_virt(address) as *mut u8).write_volatile(value) },
+        ADDRESS_SPACE_IO => unsafe { outb(address as u16, value) },
+        space => {
+            log::warn!("reset register in address space {} is not supported", space);
+            return false;
+        }
+    }
+    true
+}
+
+pub fn reboot() -> ! {
+    log::info!("reboot");
+    let fadt = crate::acpi::fadt();
+    unsafe { crate::interrupt::disable_interrupt() };
+
+    if let Some((reg, value)) = fadt.and_then(|f| f.reset_register()) {
+        if unsafe { write_reset_register(reg, value) } {
+            wait(fadt, WAIT_MS);
+        }
+        log::warn!("reset register did not work");
+    }
+
+    // キーボードコントローラにCPUのリセット線を叩かせる
+    for _ in 0..0x10000 {
+        if unsafe { inb(KBC_STATUS) } & KBC_INPUT_FULL == 0 {
+            break;
+        }
+    }
+    unsafe { outb(KBC_STATUS, KBC_PULSE_RESET) };
+    wait(fadt, WAIT_MS);
+    log::warn!("keyboard controller reset did not work");
+
+    // 空のIDTで例外を起こすとトリプルフォルトでリセットされる
+    let idtr = [0u8; 10];
+    unsafe { asm!("lidt [{}]", "int3", in(reg) idtr.as_ptr()) };
+    halt()
+}
+
+mod test {
+    #[test_case]
+    fn s5_package_is_found() {
+        use super::find_s5;
+        // QEMU: Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+        let qemu: &[u8] = &[0x10, 0x05, b'_', b'S', b'4', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(find_s5(qemu), Some((0, 0)));
+        // Name (\_S5, Package (0x02) { 0x07, One })
+        let bytes: &[u8] = &[0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a, 0x07, 0x01];
+        assert_eq!(find_s5(bytes), Some((7, 1)));
+    }
+
+    #[test_case]
+    fn s5_outside_name_is_ignored() {
+        use super::find_s5;
+        // Method (_S5_)やパッケージでないものは使わない
+        assert_eq!(find_s5(&[0x14, 0x06, b'_', b'S', b'5', b'_', 0x00]), None);
+        assert_eq!(find_s5(&[0x08, b'_', b'S', b'5', b'_', 0x0a, 0x05]), None);
+    }
+}
@@ -302,7 +302,10 @@ impl Future for PreemptiveTask {
         if PREEMPTIVE_CONTEXT_ADDR.compare_exchange_weak(null_mut(), &raw mut s.context, core::sync::atomic::Ordering::Relaxed, core::sync::atomic::Ordering::Relaxed).is_err() {
             panic!("PreemptiveTask is already running");
         }
-        PREEMPTIVE_TIMER.store(get_tick() + 2, core::sync::atomic::Ordering::Relaxed);
+        let deadline = get_tick() + 2;
+        PREEMPTIVE_TIMER.store(deadline, core::sync::atomic::Ordering::Relaxed);
+        // ticklessでは、その時刻に割り込みが来るよう頼んでおかないと戻ってこない
+        crate::timer::wake_at(deadline);
         unsafe { context_switch(&mut s.context, &mut *(&raw mut KERNEL_CONTEXT)); }
         cx.waker().wake_by_ref(); // 常にタスクに入れておく
         core::task::Poll::Pending
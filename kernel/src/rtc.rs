@@ -0,0 +1,418 @@
+// CMOSのリアルタイムクロックと、それをもとにした壁時計
+// RTCはUTCとみなす。起動時に一度読み、その後はtime::Instantとの差から今の時刻を求める
+// 値はBCDか2進数、時は12時間制か24時間制のどれかで、状態レジスタBで決まる
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::io_port::{inb, outb};
+use crate::time::Instant;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// レジスタ
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// STATUS_A
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// STATUS_B
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const SET: u8 = 1 << 7;
+// 12時間制のときの午後
+const HOUR_PM: u8 = 1 << 7;
+
+// 更新中の状態は最大でも2ms程度しか続かない
+const UIP_WAIT_LIMIT: usize = 1_000_000;
+const READ_RETRY: usize = 8;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    InvalidDateTime,
+    // 更新中のフラグが落ちないか、読むたびに値が変わる
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidDateTime => write!(f, "invalid date or time"),
+            Error::Timeout => write!(f, "RTC does not settle"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// 1970-01-01からの日数(proleptic Gregorian)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    // RTCとFATで表せる範囲だけを有効とする
+    pub fn is_valid(&self) -> bool {
+        (1980..=2099).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let time = seconds % 86400;
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    pub fn unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    // 0が日曜日
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01は木曜日
+        ((days_from_civil(self.year as i64, self.month as i64, self.day as i64) + 4).rem_euclid(7)) as u8
+    }
+
+    // FATのディレクトリエントリの日付と時刻。秒は2秒単位になる
+    pub fn fat_date(&self) -> u16 {
+        (self.year.saturating_sub(1980) << 9) | (self.month as u16) << 5 | self.day as u16
+    }
+
+    pub fn fat_time(&self) -> u16 {
+        (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16
+    }
+
+    // "2026-10-19 12:34:56"か"2026-10-19T12:34:56"
+    pub fn parse(text: &str) -> Option<DateTime> {
+        let (date, time) = text.trim().split_once([' ', 'T'])?;
+        let mut date = date.split('-');
+        let mut time = time.trim().split(':').map(str::parse::<u8>);
+        let dt = DateTime {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.ok()?,
+            minute: time.next()?.ok()?,
+            second: time.next()?.ok()?,
+        };
+        (date.next().is_none() && time.next().is_none() && dt.is_valid()).then_some(dt)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// RTCのレジスタに入っているままの値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    weekday: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+fn decode(raw: &Raw, status_b: u8) -> DateTime {
+    let convert = |v: u8| if status_b & BINARY != 0 { v } else { from_bcd(v) };
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12時間制では0時が12 AM、12時が12 PM
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    // 世紀のレジスタがなければ2000年代とする
+    let century = raw.century.map_or(20, convert) as u16;
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+fn encode(dt: &DateTime, status_b: u8, has_century: bool) -> Raw {
+    let convert = |v: u8| if status_b & BINARY != 0 { v } else { to_bcd(v) };
+    let hour = if status_b & HOUR_24 != 0 {
+        convert(dt.hour)
+    } else {
+        let pm = if dt.hour >= 12 { HOUR_PM } else { 0 };
+        convert(if dt.hour % 12 == 0 { 12 } else { dt.hour % 12 }) | pm
+    };
+    Raw {
+        second: convert(dt.second),
+        minute: convert(dt.minute),
+        hour,
+        // RTCの曜日は日曜日が1
+        weekday: convert(dt.weekday() + 1),
+        day: convert(dt.day),
+        month: convert(dt.month),
+        year: convert((dt.year % 100) as u8),
+        century: has_century.then(|| convert((dt.year / 100) as u8)),
+    }
+}
+
+// FADTのCENTURYで示されたレジスタ。0ならない
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+// CMOSのアドレスとデータのポートは組で使うので、まとめてロックする
+static CMOS: Mutex<()> = Mutex::new(());
+
+unsafe fn read_register(register: u8) -> u8 {
+    unsafe {
+        outb(CMOS_ADDRESS, register);
+        inb(CMOS_DATA)
+    }
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    unsafe {
+        outb(CMOS_ADDRESS, register);
+        outb(CMOS_DATA, value);
+    }
+}
+
+fn century_register() -> Option<u8> {
+    Some(CENTURY_REGISTER.load(Ordering::Relaxed)).filter(|&r| r != 0)
+}
+
+unsafe fn wait_update_done() -> Result<()> {
+    for _ in 0..UIP_WAIT_LIMIT {
+        if unsafe { read_register(STATUS_A) } & UPDATE_IN_PROGRESS == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Error::Timeout)
+}
+
+unsafe fn read_raw() -> Raw {
+    unsafe {
+        Raw {
+            second: read_register(SECONDS),
+            minute: read_register(MINUTES),
+            hour: read_register(HOURS),
+            weekday: read_register(WEEKDAY),
+            day: read_register(DAY),
+            month: read_register(MONTH),
+            year: read_register(YEAR),
+            century: century_register().map(|r| read_register(r)),
+        }
+    }
+}
+
+// 更新の途中を読まないよう、更新中でないときに2回続けて同じ値が読めるまで繰り返す
+pub fn read_rtc() -> Result<DateTime> {
+    let _lock = CMOS.lock();
+    unsafe {
+        wait_update_done()?;
+        let mut last = read_raw();
+        for _ in 0..READ_RETRY {
+            wait_update_done()?;
+            let raw = read_raw();
+            if raw == last {
+                let dt = decode(&raw, read_register(STATUS_B));
+                return if dt.is_valid() { Ok(dt) } else { Err(Error::InvalidDateTime) };
+            }
+            last = raw;
+        }
+    }
+    Err(Error::Timeout)
+}
+
+// 書いている間はSETで更新を止めておく
+pub fn write_rtc(dt: &DateTime) -> Result<()> {
+    if !dt.is_valid() {
+        return Err(Error::InvalidDateTime);
+    }
+    let _lock = CMOS.lock();
+    unsafe {
+        let status_b = read_register(STATUS_B);
+        let raw = encode(dt, status_b, century_register().is_some());
+        write_register(STATUS_B, status_b | SET);
+        write_register(SECONDS, raw.second);
+        write_register(MINUTES, raw.minute);
+        write_register(HOURS, raw.hour);
+        write_register(WEEKDAY, raw.weekday);
+        write_register(DAY, raw.day);
+        write_register(MONTH, raw.month);
+        write_register(YEAR, raw.year);
+        if let (Some(register), Some(value)) = (century_register(), raw.century) {
+            write_register(register, value);
+        }
+        write_register(STATUS_B, status_b & !SET);
+    }
+    Ok(())
+}
+
+// Instant(0)のときのUNIX時間(ns)。0なら分からない
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+
+fn set_boot_time(dt: &DateTime) {
+    let now = dt.unix_seconds() * NANOS_PER_SECOND;
+    BOOT_UNIX_NS.store(now.saturating_sub(Instant::now().as_nanos()).max(1), Ordering::Relaxed);
+}
+
+// time::init_clockの後に呼ぶ
+pub fn init_rtc(fadt: Option<&crate::acpi::FADT>) {
+    if let Some(register) = fadt.and_then(|fadt| fadt.century_register()) {
+        CENTURY_REGISTER.store(register, Ordering::Relaxed);
+    }
+    match read_rtc() {
+        Ok(dt) => {
+            log::info!("RTC: {} UTC", dt);
+            set_boot_time(&dt);
+        }
+        Err(e) => log::warn!("failed to read RTC: {}", e),
+    }
+}
+
+// 時刻atのUNIX時間(ns)。RTCが読めていなければNone
+pub fn unix_nanos(at: Instant) -> Option<u64> {
+    let boot = BOOT_UNIX_NS.load(Ordering::Relaxed);
+    (boot != 0).then(|| boot + at.as_nanos())
+}
+
+pub fn date_time(at: Instant) -> Option<DateTime> {
+    unix_nanos(at).map(|ns| DateTime::from_unix_seconds(ns / NANOS_PER_SECOND))
+}
+
+pub fn now() -> Option<DateTime> {
+    date_time(Instant::now())
+}
+
+// RTCに書き、壁時計も合わせる
+pub fn set_now(dt: &DateTime) -> Result<()> {
+    write_rtc(dt)?;
+    set_boot_time(dt);
+    Ok(())
+}
+
+mod test {
+    #[test_case]
+    fn rtc_registers_are_decoded() {
+        use super::{BINARY, DateTime, HOUR_24, Raw, decode};
+        // BCD, 12時間制の午後11時59分
+        let raw = Raw { second: 0x58, minute: 0x59, hour: 0x80 | 0x11, weekday: 2, day: 0x19, month: 0x10, year: 0x26, century: None };
+        assert_eq!(decode(&raw, 0), DateTime { year: 2026, month: 10, day: 19, hour: 23, minute: 59, second: 58 });
+        // 12 AMは0時
+        let raw = Raw { hour: 0x12, ..raw };
+        assert_eq!(decode(&raw, 0).hour, 0);
+        // 2進数, 24時間制, 世紀あり
+        let raw = Raw { second: 5, minute: 6, hour: 7, weekday: 1, day: 31, month: 12, year: 99, century: Some(20) };
+        assert_eq!(decode(&raw, BINARY | HOUR_24), DateTime { year: 2099, month: 12, day: 31, hour: 7, minute: 6, second: 5 });
+    }
+
+    #[test_case]
+    fn rtc_registers_round_trip() {
+        use super::{BINARY, DateTime, HOUR_24, decode, encode};
+        for hour in [0, 1, 11, 12, 13, 23] {
+            let dt = DateTime { year: 2024, month: 2, day: 29, hour, minute: 30, second: 15 };
+            for status_b in [0, HOUR_24, BINARY, BINARY | HOUR_24] {
+                assert_eq!(decode(&encode(&dt, status_b, true), status_b), dt);
+            }
+        }
+        // 2024-02-29は木曜日で、RTCでは5
+        let dt = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 0, second: 0 };
+        assert_eq!(encode(&dt, HOUR_24, false).weekday, 0x05);
+        assert_eq!(encode(&dt, 0, false).hour, 0x80 | 0x12);
+    }
+
+    #[test_case]
+    fn date_time_converts() {
+        use super::DateTime;
+        let dt = DateTime { year: 2026, month: 10, day: 19, hour: 12, minute: 34, second: 56 };
+        assert_eq!(dt.unix_seconds(), 1_792_413_296);
+        assert_eq!(DateTime::from_unix_seconds(1_792_413_296), dt);
+        assert_eq!(DateTime::from_unix_seconds(0), DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+        assert_eq!(dt.weekday(), 1);
+        assert_eq!(DateTime::parse("2026-10-19T12:34:56"), Some(dt));
+        assert_eq!(DateTime::parse("2026-02-29 00:00:00"), None);
+        // u8に収まらない月は切り詰めずにはじく
+        assert_eq!(DateTime::parse("2026-257-19 00:00:00"), None);
+        assert_eq!(alloc::format!("{}", dt), "2026-10-19 12:34:56");
+        // 2026年(46)10月19日, 12時34分56秒(28)
+        assert_eq!(dt.fat_date(), 46 << 9 | 10 << 5 | 19);
+        assert_eq!(dt.fat_time(), 12 << 11 | 34 << 5 | 28);
+    }
+}
@@ -1,12 +1,36 @@
-use core::{arch::asm, fmt::Write, sync::atomic::AtomicBool};
+use core::{fmt::Write, future::Future, pin::Pin, sync::atomic::{AtomicBool, Ordering}, task::{Context, Poll}};
 
+use futures_util::task::AtomicWaker;
+use heapless::Deque;
 use spin::Mutex;
+use crate::interrupt::{InterruptFrame, InterruptVector, without_interrupts};
 use crate::io_port::{inb, outb};
+use crate::ioapic::LegacyIrq;
 
+// COM1
+// init_serialの後はポーリングで送り、init_serial_interruptの後は
+// 送受信ともリングバッファを通して割り込みで読み書きする
 const PORT: u16 = 0x3f8;
 
+const DATA: u16 = PORT;
+const INTERRUPT_ENABLE: u16 = PORT + 1;
+const INTERRUPT_ID: u16 = PORT + 2;
+const LINE_STATUS: u16 = PORT + 5;
+const MODEM_STATUS: u16 = PORT + 6;
+
+const IER_RX_AVAILABLE: u8 = 1;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const LSR_DATA_READY: u8 = 1;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+const IIR_NO_INTERRUPT: u8 = 1;
+
+// 送信FIFOが空になってから詰められる数
+const FIFO_SIZE: usize = 16;
+const TX_BUFFER_LEN: usize = 16 * 1024;
+const RX_BUFFER_LEN: usize = 4 * 1024;
+
 pub fn init_serial() -> bool {
-    if IS_USABLE.load(core::sync::atomic::Ordering::Relaxed) {
+    if IS_USABLE.load(Ordering::Relaxed) {
         return true;
     }
     unsafe {
@@ -24,29 +48,175 @@ pub fn init_serial() -> bool {
             return false
         }
 
+        // OUT2を立てないと割り込みが出ない
         outb(PORT + 4, 0x0f);
     }
-    IS_USABLE.store(true, core::sync::atomic::Ordering::Relaxed);
+    IS_USABLE.store(true, Ordering::Relaxed);
     return true
 }
 
-unsafe fn is_transmit_empty() -> u8 {
-    unsafe { inb(PORT + 5) & 0x20 }
+// IDTを設定した後に呼ぶ
+pub fn init_serial_interrupt() {
+    if !IS_USABLE.load(Ordering::Relaxed) {
+        return;
+    }
+    without_interrupts(|| {
+        // それまでのポーリングでの送信が終わってから切り替える
+        unsafe {
+            while !is_transmit_empty() {}
+            crate::ioapic::route_irq(LegacyIrq::Com1, InterruptVector::COM1 as u8, crate::interrupt::local_apic_id());
+            INTERRUPT_MODE.store(true, Ordering::Relaxed);
+            outb(INTERRUPT_ENABLE, IER_RX_AVAILABLE);
+            // 有効にする前に届いていた分を拾っておく
+            receive(&mut RX_BUFFER.lock());
+        }
+    });
+}
+
+unsafe fn is_transmit_empty() -> bool {
+    unsafe { inb(LINE_STATUS) & LSR_TX_EMPTY != 0 }
 }
 
 unsafe fn write_serial(value: u8) {
-    while unsafe { is_transmit_empty() } == 0 {}
+    while !unsafe { is_transmit_empty() } {}
+
+    unsafe { outb(DATA, value) }
+}
+
+static TX_BUFFER: Mutex<Deque<u8, TX_BUFFER_LEN>> = Mutex::new(Deque::new());
+static RX_BUFFER: Mutex<Deque<u8, RX_BUFFER_LEN>> = Mutex::new(Deque::new());
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+static INTERRUPT_MODE: AtomicBool = AtomicBool::new(false);
+
+// 送信FIFOが空なら詰められるだけ詰め、残っていれば空になったときの割り込みを待つ
+unsafe fn transmit(tx: &mut Deque<u8, TX_BUFFER_LEN>) {
+    unsafe {
+        if is_transmit_empty() {
+            for _ in 0..FIFO_SIZE {
+                let Some(b) = tx.pop_front() else {
+                    break;
+                };
+                outb(DATA, b);
+            }
+        }
+        let ier = if tx.is_empty() { IER_RX_AVAILABLE } else { IER_RX_AVAILABLE | IER_TX_EMPTY };
+        outb(INTERRUPT_ENABLE, ier);
+    }
+}
+
+// 溢れた分は捨てる
+unsafe fn receive(rx: &mut Deque<u8, RX_BUFFER_LEN>) -> bool {
+    let mut received = false;
+    unsafe {
+        while inb(LINE_STATUS) & LSR_DATA_READY != 0 {
+            let _ = rx.push_back(inb(DATA));
+            received = true;
+        }
+    }
+    received
+}
+
+fn write_bytes(bytes: &[u8]) {
+    if !INTERRUPT_MODE.load(Ordering::Relaxed) {
+        for &b in bytes {
+            unsafe { write_serial(b) }
+        }
+        return;
+    }
+    // 割り込みハンドラもロックを取るので、割り込みを止めてから取る
+    without_interrupts(|| {
+        let mut tx = TX_BUFFER.lock();
+        for &b in bytes {
+            if tx.push_back(b).is_err() {
+                // 一杯なら待って空ける
+                while let Some(b) = tx.pop_front() {
+                    unsafe { write_serial(b) }
+                }
+                let _ = tx.push_back(b);
+            }
+        }
+        unsafe { transmit(&mut tx) }
+    });
+}
 
-    unsafe { outb(PORT, value) }
+pub extern "x86-interrupt" fn int_handler_com1(_frame: InterruptFrame) {
+    // エッジトリガなので、要因が残っているうちに戻ると次の割り込みが来なくなる
+    loop {
+        let id = unsafe { inb(INTERRUPT_ID) };
+        if id & IIR_NO_INTERRUPT != 0 {
+            break;
+        }
+        match id & 0x0e {
+            // 受信, 受信タイムアウト
+            0x04 | 0x0c => {
+                if unsafe { receive(&mut RX_BUFFER.lock()) } {
+                    RX_WAKER.wake();
+                }
+            }
+            0x02 => unsafe { transmit(&mut TX_BUFFER.lock()) },
+            0x06 => { unsafe { inb(LINE_STATUS); } }
+            _ => { unsafe { inb(MODEM_STATUS); } }
+        }
+    }
+    crate::interrupt::notify_end_of_interrupt();
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    if !INTERRUPT_MODE.load(Ordering::Relaxed) {
+        if !IS_USABLE.load(Ordering::Relaxed) || unsafe { inb(LINE_STATUS) } & LSR_DATA_READY == 0 {
+            return None;
+        }
+        return Some(unsafe { inb(DATA) });
+    }
+    without_interrupts(|| RX_BUFFER.lock().pop_front())
+}
+
+// 受信した1byteを返す。init_serial_interruptの前は起こされないので使えない
+pub fn read_byte() -> ReadByte {
+    ReadByte
+}
+
+pub struct ReadByte;
+
+impl Future for ReadByte {
+    type Output = u8;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        if let Some(b) = try_read_byte() {
+            return Poll::Ready(b);
+        }
+        RX_WAKER.register(cx.waker());
+        match try_read_byte() {
+            Some(b) => {
+                RX_WAKER.take();
+                Poll::Ready(b)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// 少なくとも1byte届くまで待ち、その時点で届いている分をbufに読む
+pub async fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    buf[0] = read_byte().await;
+    let mut n = 1;
+    while n < buf.len() {
+        let Some(b) = try_read_byte() else {
+            break;
+        };
+        buf[n] = b;
+        n += 1;
+    }
+    n
 }
 
 struct Serial;
 
 impl core::fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.as_bytes() {
-            unsafe { write_serial(*c) }
-        }
+        write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -66,10 +236,29 @@ macro_rules! serial_println {
 }
 
 pub fn _serial_print(args: core::fmt::Arguments) {
-    if IS_USABLE.load(core::sync::atomic::Ordering::Relaxed) {
+    if IS_USABLE.load(Ordering::Relaxed) {
         let Some(mut s) = SERIAL.try_lock() else {
             return;
          };
         s.write_fmt(args).unwrap();
     }
 }
+
+// パニック中はロックを持ったまま止まっていることがあるので、ロックを無視して書く
+// safety: 他に書き込んでいる者がいないこと(割り込みを止めたパニック処理の中など)
+pub unsafe fn write_bytes_unlocked(bytes: &[u8]) {
+    if IS_USABLE.load(Ordering::Relaxed) {
+        if INTERRUPT_MODE.swap(false, Ordering::Relaxed) {
+            unsafe { outb(INTERRUPT_ENABLE, 0) };
+            // 送り残しを先に出す
+            if let Some(mut tx) = TX_BUFFER.try_lock() {
+                while let Some(b) = tx.pop_front() {
+                    unsafe { write_serial(b) }
+                }
+            }
+        }
+        for &b in bytes {
+            unsafe { write_serial(b) }
+        }
+    }
+}
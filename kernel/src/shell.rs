@@ -0,0 +1,458 @@
+// シリアルのシェル
+// 画面のないCIやexpectのスクリプトから、PCIデバイスやメモリ、タスク、タイマーの状態を見る
+//
+//   ←/→, Ctrl-B/F : カーソル移動      Ctrl-A/E, Home/End : 行頭/行末
+//   ↑/↓           : 履歴              Backspace, Delete  : 1文字消す
+//   Ctrl-U/K      : カーソルより前/後ろを消す
+//   Ctrl-W        : 前の単語を消す    Ctrl-C             : 入力をやめる
+//   Ctrl-L        : 画面を消す
+
+use core::fmt::{self, Write};
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const PROMPT: &str = "kernel> ";
+const HISTORY_LEN: usize = 32;
+const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    // ESC [ の後。数字の引数は一つだけ見る
+    Csi(u8),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    None,
+    Line(String),
+    Cancel,
+}
+
+// 1byteずつ受け取り、端末へのエコーをoutに書く
+// 扱うのはASCIIの印字可能文字だけなので、カーソルの位置はbyteの位置と同じ
+pub struct LineEditor {
+    prompt: &'static str,
+    line: String,
+    cursor: usize,
+    history: VecDeque<String>,
+    // 履歴をたどっている間の位置と、たどる前に書いていた行
+    history_pos: Option<usize>,
+    editing: String,
+    escape: Escape,
+    last_cr: bool,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt,
+            line: String::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_pos: None,
+            editing: String::new(),
+            escape: Escape::None,
+            last_cr: false,
+        }
+    }
+
+    pub fn feed(&mut self, b: u8, out: &mut dyn Write) -> Result<Event, fmt::Error> {
+        // CR LFで送ってくる端末もあるので、CRの直後のLFは無視する
+        let last_cr = core::mem::replace(&mut self.last_cr, b == b'\r');
+        match self.escape {
+            Escape::None => {}
+            Escape::Esc => {
+                self.escape = if b == b'[' || b == b'O' { Escape::Csi(0) } else { Escape::None };
+                return Ok(Event::None);
+            }
+            Escape::Csi(param) => {
+                if b.is_ascii_digit() {
+                    self.escape = Escape::Csi(param.saturating_mul(10).saturating_add(b - b'0'));
+                    return Ok(Event::None);
+                }
+                self.escape = Escape::None;
+                match (b, param) {
+                    (b'A', _) => self.history_prev(out)?,
+                    (b'B', _) => self.history_next(out)?,
+                    (b'C', _) => self.move_to(self.cursor + 1, out)?,
+                    (b'D', _) => self.move_to(self.cursor.saturating_sub(1), out)?,
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => self.move_to(0, out)?,
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => self.move_to(self.line.len(), out)?,
+                    (b'~', 3) => self.delete(self.cursor, self.cursor + 1, out)?,
+                    _ => {}
+                }
+                return Ok(Event::None);
+            }
+        }
+        match b {
+            b'\n' if last_cr => {}
+            b'\r' | b'\n' => {
+                out.write_str("\r\n")?;
+                let line = core::mem::take(&mut self.line);
+                self.cursor = 0;
+                self.history_pos = None;
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY_LEN {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                return Ok(Event::Line(line));
+            }
+            0x1b => self.escape = Escape::Esc,
+            0x01 => self.move_to(0, out)?,
+            0x02 => self.move_to(self.cursor.saturating_sub(1), out)?,
+            0x03 => {
+                out.write_str("^C\r\n")?;
+                self.line.clear();
+                self.cursor = 0;
+                self.history_pos = None;
+                return Ok(Event::Cancel);
+            }
+            0x05 => self.move_to(self.line.len(), out)?,
+            0x06 => self.move_to(self.cursor + 1, out)?,
+            0x08 | 0x7f => {
+                if self.cursor > 0 {
+                    self.delete(self.cursor - 1, self.cursor, out)?;
+                }
+            }
+            0x0b => self.delete(self.cursor, self.line.len(), out)?,
+            0x0c => {
+                out.write_str("\x1b[2J\x1b[H")?;
+                self.redraw(out)?;
+            }
+            0x15 => self.delete(0, self.cursor, out)?,
+            0x17 => {
+                let before = self.line[..self.cursor].trim_end_matches(' ');
+                let start = before.rfind(' ').map_or(0, |i| i + 1);
+                self.delete(start, self.cursor, out)?;
+            }
+            0x20..=0x7e => {
+                if self.line.len() < MAX_LINE_LEN {
+                    self.line.insert(self.cursor, b as char);
+                    self.cursor += 1;
+                    if self.cursor == self.line.len() {
+                        out.write_char(b as char)?;
+                    } else {
+                        self.redraw(out)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(Event::None)
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    // 行を書き直し、カーソルを戻す
+    fn redraw(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "\r{}{}\x1b[K", self.prompt, self.line)?;
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            write!(out, "\x1b[{}D", back)?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, pos: usize, out: &mut dyn Write) -> fmt::Result {
+        let pos = pos.min(self.line.len());
+        if pos < self.cursor {
+            write!(out, "\x1b[{}D", self.cursor - pos)?;
+        } else if pos > self.cursor {
+            write!(out, "\x1b[{}C", pos - self.cursor)?;
+        }
+        self.cursor = pos;
+        Ok(())
+    }
+
+    fn delete(&mut self, start: usize, end: usize, out: &mut dyn Write) -> fmt::Result {
+        let end = end.min(self.line.len());
+        if start >= end {
+            return Ok(());
+        }
+        self.line.replace_range(start..end, "");
+        self.cursor = start;
+        self.redraw(out)
+    }
+
+    fn set_line(&mut self, line: String, out: &mut dyn Write) -> fmt::Result {
+        self.line = line;
+        self.cursor = self.line.len();
+        self.redraw(out)
+    }
+
+    fn history_prev(&mut self, out: &mut dyn Write) -> fmt::Result {
+        let pos = match self.history_pos {
+            None if self.history.is_empty() => return Ok(()),
+            None => {
+                self.editing = self.line.clone();
+                self.history.len() - 1
+            }
+            Some(0) => return Ok(()),
+            Some(pos) => pos - 1,
+        };
+        self.history_pos = Some(pos);
+        self.set_line(self.history[pos].clone(), out)
+    }
+
+    fn history_next(&mut self, out: &mut dyn Write) -> fmt::Result {
+        let Some(pos) = self.history_pos else {
+            return Ok(());
+        };
+        if pos + 1 < self.history.len() {
+            self.history_pos = Some(pos + 1);
+            self.set_line(self.history[pos + 1].clone(), out)
+        } else {
+            self.history_pos = None;
+            let editing = core::mem::take(&mut self.editing);
+            self.set_line(editing, out)
+        }
+    }
+}
+
+type Command = fn(args: &[&str], out: &mut dyn Write) -> fmt::Result;
+
+const COMMANDS: &[(&str, &str, Command)] = &[
+    ("help", "show this message", help),
+    ("pci", "list PCI devices", pci),
+    ("memory", "show frame and heap usage", memory),
+    ("tasks", "list running tasks", tasks),
+    ("timers", "show the tick and pending timers", timers),
+    ("date", "show or set the date in UTC (date 2026-10-19 12:34:56)", date),
+    ("acpi", "list ACPI tables", acpi),
+    ("aml", "list ACPI devices or evaluate a name (aml \\_SB.PCI0._CRS)", aml),
+    ("poweroff", "turn the machine off (ACPI S5)", poweroff),
+    ("reboot", "reset the machine", reboot),
+    ("log", "show or set the log filter (log warn,kernel::usb=trace)", log_filter),
+];
+
+fn help(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    for (name, description, _) in COMMANDS {
+        writeln!(out, "  {:<8} {}", name, description)?;
+    }
+    Ok(())
+}
+
+fn pci(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let devices = match crate::pci::scan_all_bus() {
+        Ok(d) => d,
+        Err((d, e)) => {
+            writeln!(out, "scan failed: {}", e)?;
+            d
+        }
+    };
+    for dev in devices.iter() {
+        let class = dev.class_code();
+        writeln!(out, "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} header {:02x}",
+            dev.bus(), dev.device(), dev.func(), dev.read_vendor_id(), dev.read_device_id(),
+            class.base(), class.sub(), class.interface(), dev.header_type() & 0x7f)?;
+    }
+    Ok(())
+}
+
+fn memory(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    use crate::memory_manager::{BYTES_PER_FRAME, MANAGER};
+    let stats = MANAGER.lock().stats();
+    writeln!(out, "frames: {}/{} allocated ({} MiB / {} MiB)", stats.allocated, stats.total,
+        stats.allocated * BYTES_PER_FRAME / 1024 / 1024, stats.total * BYTES_PER_FRAME / 1024 / 1024)?;
+    match crate::allocator::heap_usage() {
+        Some(heap) => writeln!(out, "heap  : {}/{} bytes used", heap.used, heap.total),
+        None => writeln!(out, "heap  : busy"),
+    }
+}
+
+fn tasks(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let mut list = Vec::new();
+    crate::task::try_for_each_task(|t| list.push(*t));
+    writeln!(out, "{:>4} {:>10} name", "id", "polls")?;
+    for t in list.iter() {
+        writeln!(out, "{:>4} {:>10} {}", t.id, t.polls, t.name)?;
+    }
+    Ok(())
+}
+
+fn timers(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let tick = crate::timer::get_tick();
+    writeln!(out, "tick: {} ({} Hz, {:?})", tick, crate::timer::TIMER_FREQ, crate::timer::mode())?;
+    if let Some(hpet) = crate::hpet::hpet() {
+        writeln!(out, "hpet: {} ns ({} Hz)", hpet.nanoseconds(), hpet.frequency())?;
+    }
+    if let Some(source) = crate::time::source() {
+        write!(out, "clock: {} s ({:?}", crate::time::Instant::now(), source)?;
+        if let Some(frequency) = crate::time::tsc_frequency() {
+            write!(out, " {} Hz", frequency)?;
+        }
+        writeln!(out, ")")?;
+    }
+    let mut list = Vec::new();
+    crate::timer::try_for_each_timer(|deadline| list.push(deadline));
+    list.sort_unstable();
+    let now = crate::time::Instant::now();
+    for deadline in list.iter() {
+        writeln!(out, "  deadline {} (in {:?})", deadline, deadline.duration_since(now))?;
+    }
+    Ok(())
+}
+
+fn date(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    if !args.is_empty() {
+        let text = args.join(" ");
+        let Some(dt) = crate::rtc::DateTime::parse(&text) else {
+            return writeln!(out, "invalid date: {}", text);
+        };
+        if let Err(e) = crate::rtc::set_now(&dt) {
+            return writeln!(out, "failed to set RTC: {}", e);
+        }
+    }
+    match crate::rtc::now() {
+        Some(dt) => writeln!(out, "{} UTC", dt),
+        None => writeln!(out, "RTC is not available"),
+    }
+}
+
+fn acpi(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    crate::acpi::dump(out)
+}
+
+fn aml(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    crate::aml::dump(args.first().copied(), out)
+}
+
+fn poweroff(_args: &[&str], _out: &mut dyn Write) -> fmt::Result {
+    crate::power::power_off()
+}
+
+fn reboot(_args: &[&str], _out: &mut dyn Write) -> fmt::Result {
+    crate::power::reboot()
+}
+
+fn log_filter(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    if let Some(text) = args.first() {
+        match crate::logger::LogFilter::parse(text) {
+            Ok(filter) => crate::logger::set_filter(filter),
+            Err(item) => return writeln!(out, "invalid log filter: {}", item),
+        }
+    }
+    writeln!(out, "{}", crate::logger::filter())
+}
+
+pub fn execute(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = args.split_first() else {
+        return Ok(());
+    };
+    match COMMANDS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, _, command)) => command(args, out),
+        None => writeln!(out, "unknown command: {} (try help)", name),
+    }
+}
+
+struct SerialOut;
+
+impl Write for SerialOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+pub async fn serial_shell() {
+    let mut editor = LineEditor::new(PROMPT);
+    let mut buf = [0u8; 64];
+    crate::serial_print!("\n{}", PROMPT);
+    loop {
+        let n = crate::serial::read(&mut buf).await;
+        for &b in &buf[..n] {
+            // エコーはまとめて書く
+            let mut echo = String::new();
+            let event = editor.feed(b, &mut echo);
+            SerialOut.write_str(&echo).unwrap();
+            match event {
+                Ok(Event::Line(line)) => {
+                    execute(&line, &mut SerialOut).unwrap();
+                    SerialOut.write_str(PROMPT).unwrap();
+                }
+                Ok(Event::Cancel) => SerialOut.write_str(PROMPT).unwrap(),
+                Ok(Event::None) | Err(_) => {}
+            }
+        }
+    }
+}
+
+mod test {
+    #[test_case]
+    fn line_editor_edits_in_the_middle() {
+        use super::{Event, LineEditor};
+        let mut editor = LineEditor::new("> ");
+        let mut out = alloc::string::String::new();
+        // "tsks" と打ち、3文字戻って'a'を入れる
+        for &b in b"tsks\x1b[D\x1b[D\x1b[D" {
+            assert_eq!(editor.feed(b, &mut out), Ok(Event::None));
+        }
+        assert_eq!(editor.cursor(), 1);
+        editor.feed(b'a', &mut out).unwrap();
+        assert_eq!(editor.line(), "tasks");
+        assert_eq!(editor.cursor(), 2);
+        assert_eq!(editor.feed(b'\r', &mut out), Ok(Event::Line("tasks".into())));
+        // CR LFのLFは無視する
+        assert_eq!(editor.feed(b'\n', &mut out), Ok(Event::None));
+        assert_eq!(editor.line(), "");
+    }
+
+    #[test_case]
+    fn line_editor_deletes() {
+        use super::LineEditor;
+        let mut editor = LineEditor::new("> ");
+        let mut out = alloc::string::String::new();
+        for &b in b"log warn,kernel\x17" {
+            editor.feed(b, &mut out).unwrap();
+        }
+        assert_eq!(editor.line(), "log ");
+        for &b in b"info\x7f\x7f\x01\x0b" {
+            editor.feed(b, &mut out).unwrap();
+        }
+        assert_eq!(editor.line(), "");
+    }
+
+    #[test_case]
+    fn line_editor_walks_history() {
+        use super::{Event, LineEditor};
+        let mut editor = LineEditor::new("> ");
+        let mut out = alloc::string::String::new();
+        for &b in b"pci\rmemory\rtim" {
+            editor.feed(b, &mut out).unwrap();
+        }
+        for &b in b"\x1b[A\x1b[A" {
+            editor.feed(b, &mut out).unwrap();
+        }
+        assert_eq!(editor.line(), "pci");
+        for &b in b"\x1b[B\x1b[B" {
+            editor.feed(b, &mut out).unwrap();
+        }
+        // たどる前に書いていた行に戻る
+        assert_eq!(editor.line(), "tim");
+        assert_eq!(editor.feed(b'\x03', &mut out), Ok(Event::Cancel));
+        assert_eq!(editor.line(), "");
+    }
+
+    #[test_case]
+    fn execute_reports_unknown_commands() {
+        let mut out = alloc::string::String::new();
+        super::execute("frobnicate now", &mut out).unwrap();
+        assert_eq!(out, "unknown command: frobnicate (try help)\n");
+        out.clear();
+        super::execute("   ", &mut out).unwrap();
+        assert_eq!(out, "");
+    }
+}
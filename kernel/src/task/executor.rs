@@ -1,6 +1,6 @@
 use crate::interrupt::{disable_interrupt, enable_and_halt_interrupt, enable_interrupt};
 
-use super::{Task, TaskId};
+use super::{Task, TaskId, count_poll, register_task, unregister_task};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Waker, Context, Poll};
 use crossbeam::queue::ArrayQueue;
@@ -21,6 +21,7 @@ impl Executor {
     }
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        register_task(&task);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks")
         }
@@ -34,10 +35,12 @@ impl Executor {
             };
             let waker = self.waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, Arc::clone(&self.task_queue)));
             let mut context = Context::from_waker(waker);
+            count_poll(task_id);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     self.tasks.remove(&task_id);
                     self.waker_cache.remove(&task_id);
+                    unregister_task(task_id);
                 }
                 Poll::Pending => {}
             };
@@ -1,17 +1,21 @@
 pub mod executor;
 
 use core::{future::Future, pin::Pin, task::{Context, Poll}, sync::atomic::AtomicUsize};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap};
+use spin::Mutex;
 
 pub struct Task {
     id: TaskId,
+    name: &'static str,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new<F: Future<Output = ()> + 'static>(future: F) -> Task {
         Task {
             id: TaskId::new(),
+            // async fnの型名はその関数のパスになる
+            name: core::any::type_name::<F>(),
             future: Box::pin(future)
         }
     }
@@ -29,3 +33,36 @@ impl TaskId {
         TaskId(NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed))
     }
 }
+
+// クラッシュダンプやシェルから見るための、実行中のタスクの一覧
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: usize,
+    pub name: &'static str,
+    pub polls: u64,
+}
+
+static TASKS: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+fn register_task(task: &Task) {
+    TASKS.lock().insert(task.id, TaskInfo { id: task.id.0, name: task.name, polls: 0 });
+}
+
+fn unregister_task(id: TaskId) {
+    TASKS.lock().remove(&id);
+}
+
+fn count_poll(id: TaskId) {
+    if let Some(info) = TASKS.lock().get_mut(&id) {
+        info.polls += 1;
+    }
+}
+
+// パニック中にも呼ぶので、ロックが取れなければfalse
+pub fn try_for_each_task(mut f: impl FnMut(&TaskInfo)) -> bool {
+    let Some(tasks) = TASKS.try_lock() else {
+        return false;
+    };
+    tasks.values().for_each(|t| f(t));
+    true
+}
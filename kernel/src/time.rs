@@ -0,0 +1,226 @@
+// 単調増加する時計
+// TSCが不変(電源状態や周波数で速さが変わらない)なら、HPETかACPI PMタイマで周波数を測ってTSCを使う
+// そうでなければHPETを直接読み、どちらもなければLAPICタイマのtickで代わりにする
+// init_clockを呼んだときが0になる
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt::{self, Write};
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+use conquer_once::spin::OnceCell;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// 較正に使う時間
+const CALIBRATION_MS: u32 = 50;
+
+// CPUID
+const EXTENDED_MAX_LEAF: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+const TSC_CRYSTAL_RATIO: u32 = 0x15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(match CLOCK.get() {
+            Some(clock) => clock.nanoseconds(),
+            None => 0,
+        })
+    }
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant(nanos)
+    }
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok().and_then(|d| self.0.checked_add(d)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).unwrap_or(Instant(u64::MAX))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+// 秒.マイクロ秒
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 幅の指定が全体に効くよう、いったん文字列にする
+        let mut text: heapless::String<32> = heapless::String::new();
+        write!(text, "{}.{:06}", self.0 / NANOS_PER_SECOND, self.0 % NANOS_PER_SECOND / 1000)?;
+        f.pad(&text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Tsc,
+    Hpet,
+    Tick,
+}
+
+struct Clock {
+    source: Source,
+    // TSCのときの周波数と、0にするカウント
+    tsc_frequency: u64,
+    tsc_base: u64,
+    tsc_scale: u128,
+    hpet_base: u64,
+}
+
+impl Clock {
+    fn nanoseconds(&self) -> u64 {
+        match self.source {
+            Source::Tsc => tsc_to_ns(rdtsc().saturating_sub(self.tsc_base), self.tsc_scale),
+            Source::Hpet => crate::hpet::nanoseconds().unwrap_or(0).saturating_sub(self.hpet_base),
+            Source::Tick => crate::timer::get_tick() as u64 * (NANOS_PER_SECOND / crate::timer::TIMER_FREQ as u64),
+        }
+    }
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// ns = (ticks * scale) >> 64。毎回の割り算を避ける
+// 32bitの小数部では1日で数十usずれるので、64bitにしておく
+fn scale_for(frequency: u64) -> u128 {
+    ((NANOS_PER_SECOND as u128) << 64).div_ceil(frequency as u128)
+}
+
+fn tsc_to_ns(ticks: u64, scale: u128) -> u64 {
+    ((ticks as u128 * scale) >> 64) as u64
+}
+
+pub fn has_invariant_tsc() -> bool {
+    let max = __cpuid(EXTENDED_MAX_LEAF).eax;
+    max >= ADVANCED_POWER_MANAGEMENT && __cpuid(ADVANCED_POWER_MANAGEMENT).edx & INVARIANT_TSC != 0
+}
+
+// CPUIDのleaf 0x15にクリスタルの周波数まで書かれていれば、測らなくても分かる
+fn tsc_frequency_from_cpuid() -> Option<u64> {
+    if __cpuid(0).eax < TSC_CRYSTAL_RATIO {
+        return None;
+    }
+    let leaf = __cpuid(TSC_CRYSTAL_RATIO);
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+fn measure_tsc_frequency(fadt: Option<&crate::acpi::FADT>) -> Option<(u64, &'static str)> {
+    if let Some(hpet) = crate::hpet::hpet() {
+        let (start_ns, start) = (hpet.nanoseconds(), rdtsc());
+        hpet.wait_milliseconds(CALIBRATION_MS);
+        let (end_ns, end) = (hpet.nanoseconds(), rdtsc());
+        let frequency = (end - start) as u128 * NANOS_PER_SECOND as u128 / (end_ns - start_ns).max(1) as u128;
+        return Some((frequency as u64, "HPET"));
+    }
+    let fadt = fadt?;
+    let start = rdtsc();
+    fadt.wait_milliseconds(CALIBRATION_MS);
+    Some(((rdtsc() - start) * (1000 / CALIBRATION_MS as u64), "PM timer"))
+}
+
+// init_hpetの後に呼ぶ
+pub fn init_clock(fadt: Option<&crate::acpi::FADT>) {
+    let invariant = has_invariant_tsc();
+    let tsc = if invariant {
+        tsc_frequency_from_cpuid().map(|f| (f, "CPUID")).or_else(|| measure_tsc_frequency(fadt))
+    } else {
+        None
+    };
+    let mut clock = Clock { source: Source::Tick, tsc_frequency: 0, tsc_base: 0, tsc_scale: 0, hpet_base: 0 };
+    if let Some((frequency, by)) = tsc.filter(|&(f, _)| f != 0) {
+        log::info!("clock: invariant TSC {} Hz (from {})", frequency, by);
+        clock.source = Source::Tsc;
+        clock.tsc_frequency = frequency;
+        clock.tsc_scale = scale_for(frequency);
+        clock.tsc_base = rdtsc();
+    } else if let Some(now) = crate::hpet::nanoseconds() {
+        log::info!("clock: HPET (TSC is {})", if invariant { "not calibrated" } else { "not invariant" });
+        clock.source = Source::Hpet;
+        clock.hpet_base = now;
+    } else {
+        log::warn!("clock: no TSC or HPET, falling back to {} Hz ticks", crate::timer::TIMER_FREQ);
+    }
+    if CLOCK.try_init_once(|| clock).is_err() {
+        log::warn!("clock is already initialized");
+    }
+}
+
+pub fn source() -> Option<Source> {
+    CLOCK.get().map(|c| c.source)
+}
+
+// TSCを使っていればその周波数
+pub fn tsc_frequency() -> Option<u64> {
+    CLOCK.get().filter(|c| c.source == Source::Tsc).map(|c| c.tsc_frequency)
+}
+
+// TSCを使っていれば、時刻atになるときのTSCの値。LAPICのTSC-deadlineモードで使う
+pub fn tsc_at(at: Instant) -> Option<u64> {
+    let clock = CLOCK.get().filter(|c| c.source == Source::Tsc)?;
+    // 早く着きすぎないよう切り上げる
+    let ticks = (at.0 as u128 * clock.tsc_frequency as u128).div_ceil(NANOS_PER_SECOND as u128);
+    Some(clock.tsc_base.saturating_add(ticks as u64))
+}
+
+// fにかかった時間を測る
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let value = f();
+    (value, start.elapsed())
+}
+
+mod test {
+    #[test_case]
+    fn tsc_ticks_are_scaled() {
+        use super::{scale_for, tsc_to_ns};
+        let scale = scale_for(3_000_000_000);
+        assert_eq!(tsc_to_ns(3_000_000_000, scale), 1_000_000_000);
+        assert_eq!(tsc_to_ns(3, scale), 1);
+        // 100日分でもずれは1us以内
+        let ticks = 3_000_000_000 * 86_400 * 100;
+        assert!(tsc_to_ns(ticks, scale).abs_diff(86_400 * 100 * 1_000_000_000) < 1000);
+        let scale = scale_for(2_400_000_000);
+        assert_eq!(tsc_to_ns(12, scale), 5);
+    }
+
+    #[test_case]
+    fn instants_are_ordered() {
+        use core::time::Duration;
+        use super::Instant;
+        let a = Instant::from_nanos(1_500_000_000);
+        let b = a + Duration::from_micros(250);
+        assert!(a < b);
+        assert_eq!(b - a, Duration::from_micros(250));
+        // 逆に引いても負にはならない
+        assert_eq!(a - b, Duration::ZERO);
+        assert_eq!(a.checked_add(Duration::MAX), None);
+        assert_eq!(alloc::format!("{}", b), "1.500250");
+        assert_eq!(alloc::format!("{:>10}", a), "  1.500000");
+    }
+}
@@ -1,105 +1,215 @@
-use core::{cmp::{Ordering, Reverse}, future::Pending, sync::atomic::{AtomicBool, AtomicUsize}};
-use alloc::{collections::BinaryHeap, sync::Arc};
+use core::{arch::{asm, x86_64::__cpuid}, cmp::{Ordering, Reverse}, future::Pending, pin::Pin, sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize}, task::{Context, Poll}, time::Duration};
+use alloc::{boxed::Box, collections::BinaryHeap, sync::Arc};
+use conquer_once::spin::OnceCell;
 use futures_util::task::AtomicWaker;
 use spin::Mutex;
 
+use crate::interrupt::{local_apic, without_interrupts};
 use crate::preemptive::context::check_and_stop_preemptive;
+use crate::time::Instant;
 
 const COUNT_MAX: u32 = 0xffffffff;
-const LVT_TIMER: *mut u32 = 0xfee00320 as *mut u32;
-const INITIAL_COUNT: *mut u32 = 0xfee00380 as *mut u32;
-const CURRENT_COUNT: *mut u32 = 0xfee00390 as *mut u32;
-const DIVIDE_CONFIGURATION: *mut u32 = 0xfee003e0 as *mut u32;
+// Local APICのレジスタのオフセット
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIGURATION: usize = 0x3e0;
+// LVT_TIMERのモード
+const LVT_TIMER_ONESHOT: u32 = 0b00 << 17;
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+// CPUID.01H:ECX
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
 
-const TIMER_FREQ: u32 = 100;
+pub const TIMER_FREQ: u32 = 100;
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TIMER_FREQ as u64;
+// ticklessでも、これより長くは割り込みを止めない
+// HPETの32bitカウンタの桁上がりを見逃さないためでもある
+const MAX_SLEEP_NS: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    // TIMER_FREQで割り込み、その回数をtickとする
+    Periodic,
+    // 次の期限に一度だけ割り込む。tickは時計から求める
+    OneShot,
+    TscDeadline,
+}
 
 static TICK: AtomicUsize = AtomicUsize::new(0);
-static PRIORITY_QUEUE: Mutex<BinaryHeap<Timer>> = Mutex::new(BinaryHeap::new());
+static MODE: OnceCell<TimerMode> = OnceCell::uninit();
+static LAPIC_TIMER_FREQ: AtomicU32 = AtomicU32::new(0);
+// ticklessで次に割り込む時刻(ns)。0なら何も設定されていない
+static ARMED_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static PRIORITY_QUEUE: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
 static TIMER_MANAGER_WAKER: AtomicWaker = AtomicWaker::new();
+// 期限が同じタイマーを作った順に起こすための通し番号
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
 
 struct TimerInner {
-    timeout: Reverse<usize>,
-    value: usize,
+    deadline: Instant,
+    sequence: u64,
     waker: AtomicWaker,
+    // PRIORITY_QUEUEに入っているか。二重に積まないためと、Dropで探すかを決めるのに使う
+    queued: AtomicBool,
 }
 
-impl TimerInner {
-    fn new(timeout: usize, value: usize) -> TimerInner {
-        TimerInner { timeout: Reverse(timeout), value, waker: AtomicWaker::new() }
-    }
-    fn timeout(&self) -> usize {
-        self.timeout.0
-    }
-    fn value(&self) -> usize {
-        self.value
-    }
-    fn waker(&self) -> &AtomicWaker {
-        &self.waker
+// PRIORITY_QUEUEに入れるもの。期限が早いほど大きい
+struct Entry {
+    inner: Arc<TimerInner>,
+}
+
+impl Entry {
+    fn key(&self) -> Reverse<(Instant, u64)> {
+        Reverse((self.inner.deadline, self.inner.sequence))
     }
 }
 
-impl PartialEq for TimerInner {
+impl PartialEq for Entry {
     fn eq(&self, other: &Self) -> bool {
-        self.timeout == other.timeout
+        self.key() == other.key()
     }
 }
 
-impl Eq for TimerInner {}
+impl Eq for Entry {}
 
-impl PartialOrd for TimerInner {
+impl PartialOrd for Entry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.timeout.cmp(&other.timeout))
+        Some(self.cmp(other))
     }
 }
 
-impl Ord for TimerInner {
+impl Ord for Entry {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.timeout.cmp(&other.timeout)
+        self.key().cmp(&other.key())
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
-pub struct Timer {
+// deadlineになると完了するFuture。完了する前にDropすればキューから外れる
+pub struct Sleep {
     inner: Arc<TimerInner>,
 }
 
-impl Timer {
-    pub fn new(timeout: usize, value: usize) -> Timer {
-        Timer { inner: Arc::new(TimerInner::new(timeout, value)) }
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    let sequence = NEXT_SEQUENCE.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    Sleep { inner: Arc::new(TimerInner { deadline, sequence, waker: AtomicWaker::new(), queued: AtomicBool::new(false) }) }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.inner.deadline
     }
-    fn add(&self) {
-        let mut lck = PRIORITY_QUEUE.lock();
-        lck.push(Timer { inner: Arc::clone(&self.inner) });
+    fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.inner.deadline
+    }
+    fn enqueue(&self) {
+        if self.inner.queued.swap(true, core::sync::atomic::Ordering::AcqRel) {
+            return;
+        }
+        PRIORITY_QUEUE.lock().push(Entry { inner: Arc::clone(&self.inner) });
+        arm_earlier(self.inner.deadline.as_nanos());
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+        self.inner.waker.register(cx.waker());
+        self.enqueue();
+        // 登録している間に過ぎていれば、起こされるのを待たない
+        if self.is_elapsed() {
+            self.inner.waker.take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
     }
-    // pub fn timeout(&self) -> usize {
-    //     self.timeout.0
-    // }
-    // pub fn value(&self) -> usize {
-    //     self.value
-    // }
-    // pub fn waker(&self) -> &AtomicWaker {
-    //     &self.waker
-    // }
 }
 
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.inner.queued.load(core::sync::atomic::Ordering::Acquire) {
+            PRIORITY_QUEUE.lock().retain(|e| !Arc::ptr_eq(&e.inner, &self.inner));
+        }
+    }
+}
 
-impl Future for Timer {
-    type Output = usize;
-    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> core::task::Poll<Self::Output> {
-        let tick = get_tick();
-        if tick > self.inner.timeout() {
-            core::task::Poll::Ready(self.inner.value())
-        } else {
-            self.inner.waker().register(cx.waker());
-            self.add();
-
-            if get_tick() > self.inner.timeout() {
-                self.inner.waker().take();
-                core::task::Poll::Ready(self.inner.value())
-            } else {
-                core::task::Poll::Pending
-            }
+// periodごとに完了する。最初はperiod後
+// 処理が遅れて期限をいくつも過ぎていれば、まとめて返さずに次の期限まで飛ばす
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must be non-zero");
+    Interval { period, sleep: sleep(period) }
+}
+
+// deadlineの次の期限。nowを過ぎていれば、nowより後の最初の期限にする
+fn next_deadline(deadline: Instant, period: Duration, now: Instant) -> Instant {
+    let next = deadline + period;
+    if next > now {
+        return next;
+    }
+    let period_ns = period.as_nanos() as u64;
+    let missed = (now.as_nanos() - next.as_nanos()) / period_ns + 1;
+    Instant::from_nanos(next.as_nanos().saturating_add(missed.saturating_mul(period_ns)))
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+    // 期限が来ていれば、その期限を返して次を設定する
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
         }
+        let deadline = self.sleep.deadline();
+        self.sleep = sleep_until(next_deadline(deadline, self.period, Instant::now()));
+        Poll::Ready(deadline)
+    }
+    pub fn tick(&mut self) -> impl Future<Output = Instant> + '_ {
+        core::future::poll_fn(|cx| self.poll_tick(cx))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl core::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+// futureがdurationの間に終わらなければErr(Elapsed)。そのときfutureはDropされる
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future: Box::pin(future), sleep: sleep(duration) }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        // 同時に終わっていれば結果を優先する
+        if let Poll::Ready(value) = this.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(value));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
     }
 }
 
@@ -108,75 +218,210 @@ struct TimerManager;
 
 impl Future for TimerManager {
     type Output = ();
-    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> core::task::Poll<Self::Output> {
-        let tick = get_tick();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let now = Instant::now();
         // ロックはとれるなら取る
         if let Some(mut lck) = PRIORITY_QUEUE.try_lock() {
-            while let Some(t) = lck.peek() {
-                if t.inner.timeout() > tick {
+            while let Some(e) = lck.peek() {
+                if e.inner.deadline > now {
                     break;
                 }
-                t.inner.waker().wake();
-                lck.pop();
+                let e = lck.pop().unwrap();
+                e.inner.queued.store(false, core::sync::atomic::Ordering::Release);
+                e.inner.waker.wake();
             }
+            arm_next(lck.peek().map(|e| e.inner.deadline.as_nanos()));
+        } else {
+            // 取れなければ次のtickで見直す
+            arm_next(Some(tick_start_ns(get_tick() + 1)));
         }
         TIMER_MANAGER_WAKER.register(cx.waker());
-        core::task::Poll::Pending
+        Poll::Pending
     }
 }
 
+// 待っているタイマーの期限を渡す。順番は決まっていない
+// ロックが取れなければfalse
+pub fn try_for_each_timer(mut f: impl FnMut(Instant)) -> bool {
+    let Some(queue) = PRIORITY_QUEUE.try_lock() else {
+        return false;
+    };
+    queue.iter().for_each(|e| f(e.inner.deadline));
+    true
+}
+
 pub async fn timer_manager() {
     TimerManager.await
 }
 
+// 較正にはHPET、なければACPI PMタイマを使う
+// time::init_clockの後に呼ぶ。TSCかHPETの時計があればticklessにする
 pub fn initialize_apic_timer(fadt: Option<&'static crate::acpi::FADT>) {
+    let lapic_timer_freq = if let Some(hpet) = crate::hpet::hpet() {
+        Some(("HPET", measure_lapic_timer(|| hpet.wait_milliseconds(100))))
+    } else {
+        fadt.map(|fadt| ("PM timer", measure_lapic_timer(|| fadt.wait_milliseconds(100))))
+    };
+    let mode = select_mode(lapic_timer_freq.is_some());
+    let vector = crate::interrupt::InterruptVector::LAPICTimer as u32;
     unsafe {
-        if let Some(fadt) = fadt {
-            *DIVIDE_CONFIGURATION = 0b1011;
-            *LVT_TIMER = 0b001 << 16;
+        *local_apic(DIVIDE_CONFIGURATION) = 0b1011;
+        match lapic_timer_freq {
+            Some((source, freq)) => {
+                log::info!("LAPIC timer: {} Hz (calibrated with {})", freq, source);
+                LAPIC_TIMER_FREQ.store(freq, core::sync::atomic::Ordering::Relaxed);
+            }
+            None => log::warn!("LAPIC timer is not calibrated"),
+        }
+        match mode {
+            TimerMode::Periodic => {
+                *local_apic(LVT_TIMER) = LVT_TIMER_PERIODIC | vector;
+                *local_apic(INITIAL_COUNT) = lapic_timer_freq.map_or(0x1000000, |(_, freq)| freq / TIMER_FREQ);
+            }
+            TimerMode::OneShot => *local_apic(LVT_TIMER) = LVT_TIMER_ONESHOT | vector,
+            TimerMode::TscDeadline => *local_apic(LVT_TIMER) = LVT_TIMER_TSC_DEADLINE | vector,
+        }
+    }
+    log::info!("LAPIC timer mode: {:?}", mode);
+    let _ = MODE.try_init_once(|| mode);
+}
 
-            start_lapic_timer();
-            fadt.wait_milliseconds(100);
-            let elapsed = lapic_timer_elapsed();
-            stop_lapic_timer();
+fn select_mode(calibrated: bool) -> TimerMode {
+    match crate::time::source() {
+        Some(crate::time::Source::Tsc) if __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0 => TimerMode::TscDeadline,
+        Some(crate::time::Source::Tsc | crate::time::Source::Hpet) if calibrated => TimerMode::OneShot,
+        _ => TimerMode::Periodic,
+    }
+}
 
-            let lapic_timer_freq = elapsed * 10; // 1s
+pub fn mode() -> TimerMode {
+    MODE.get().copied().unwrap_or(TimerMode::Periodic)
+}
 
-            *DIVIDE_CONFIGURATION = 0b1011;
-            *LVT_TIMER = (0b010 << 16) | crate::interrupt::InterruptVector::LAPICTimer as u32;
-            *INITIAL_COUNT = lapic_timer_freq / TIMER_FREQ;
-        } else {
-            *DIVIDE_CONFIGURATION = 0b1011;
-            *LVT_TIMER = (0b010 << 16) | crate::interrupt::InterruptVector::LAPICTimer as u32;
-            *INITIAL_COUNT = 0x1000000;
+fn is_tickless() -> bool {
+    mode() != TimerMode::Periodic
+}
+
+// tickが始まる時刻(ns)
+fn tick_start_ns(tick: usize) -> u64 {
+    (tick as u64).saturating_mul(NANOS_PER_TICK)
+}
+
+fn ns_to_tick(ns: u64) -> usize {
+    (ns / NANOS_PER_TICK) as usize
+}
+
+// ns後に割り込むためのワンショットの初期カウント。0だと止まってしまう
+fn oneshot_count(ns: u64, freq: u32) -> u32 {
+    (ns as u128 * freq as u128 / 1_000_000_000).clamp(1, COUNT_MAX as u128) as u32
+}
+
+unsafe fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+}
+
+// 時刻deadline(ns)に割り込むようにする。過ぎていればすぐに割り込む
+fn arm(deadline: u64) {
+    let now = Instant::now().as_nanos();
+    ARMED_DEADLINE.store(deadline, core::sync::atomic::Ordering::Relaxed);
+    unsafe {
+        match mode() {
+            TimerMode::Periodic => {}
+            TimerMode::OneShot => {
+                let freq = LAPIC_TIMER_FREQ.load(core::sync::atomic::Ordering::Relaxed);
+                *local_apic(INITIAL_COUNT) = oneshot_count(deadline.saturating_sub(now), freq);
+            }
+            TimerMode::TscDeadline => {
+                let tsc = crate::time::tsc_at(Instant::from_nanos(deadline)).unwrap_or(1);
+                write_msr(IA32_TSC_DEADLINE, tsc);
+            }
         }
     }
 }
 
+// 設定済みのものより早ければ設定し直す
+fn arm_earlier(deadline: u64) {
+    if !is_tickless() {
+        return;
+    }
+    without_interrupts(|| {
+        let armed = ARMED_DEADLINE.load(core::sync::atomic::Ordering::Relaxed);
+        if armed == 0 || deadline < armed {
+            arm(deadline);
+        }
+    });
+}
+
+// キューの先頭の期限で割り込むようにする。なくてもMAX_SLEEP_NSごとには起きる
+// 先に設定された早い期限(プリエンプションなど)が残っていれば、そちらを優先する
+fn arm_next(deadline: Option<u64>) {
+    if !is_tickless() {
+        return;
+    }
+    without_interrupts(|| {
+        let now = Instant::now().as_nanos();
+        let deadline = deadline.unwrap_or(u64::MAX).min(now.saturating_add(MAX_SLEEP_NS));
+        let armed = ARMED_DEADLINE.load(core::sync::atomic::Ordering::Relaxed);
+        if armed <= now || deadline < armed {
+            arm(deadline);
+        }
+    });
+}
+
+// get_tick()がtickになったら割り込みが来るようにする
+// 周期モードでは毎tick割り込むので何もしない
+pub fn wake_at(tick: usize) {
+    arm_earlier(tick_start_ns(tick));
+}
+
+// 100ms待つ間に進んだカウントから1秒あたりのカウントを求める
+fn measure_lapic_timer(wait_100ms: impl FnOnce()) -> u32 {
+    unsafe {
+        *local_apic(DIVIDE_CONFIGURATION) = 0b1011;
+        *local_apic(LVT_TIMER) = 0b001 << 16;
+    }
+    start_lapic_timer();
+    wait_100ms();
+    let elapsed = lapic_timer_elapsed();
+    stop_lapic_timer();
+    elapsed * 10
+}
+
 fn start_lapic_timer() {
     unsafe {
-        *INITIAL_COUNT = COUNT_MAX;
+        *local_apic(INITIAL_COUNT) = COUNT_MAX;
     }
 }
 
 fn lapic_timer_elapsed() -> u32 {
     unsafe {
-        COUNT_MAX - *CURRENT_COUNT
+        COUNT_MAX - *local_apic(CURRENT_COUNT)
     }
 }
 
 fn stop_lapic_timer() {
     unsafe {
-        *INITIAL_COUNT = 0;
+        *local_apic(INITIAL_COUNT) = 0;
     }
 }
 
+// ticklessでは時計から求める
 pub fn get_tick() -> usize {
+    if is_tickless() {
+        return ns_to_tick(Instant::now().as_nanos());
+    }
     TICK.load(core::sync::atomic::Ordering::Relaxed)
 }
 
 pub extern "x86-interrupt" fn int_handler_lapic_timer(_frame: crate::interrupt::InterruptFrame) {
-    TICK.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    if is_tickless() {
+        // ワンショットは上限で早めに来ることもあるので、次はtimer_managerに必ず設定させる
+        ARMED_DEADLINE.store(0, core::sync::atomic::Ordering::Relaxed);
+    } else {
+        TICK.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
     TIMER_MANAGER_WAKER.wake();
     crate::interrupt::notify_end_of_interrupt();
 
@@ -186,29 +431,105 @@ pub extern "x86-interrupt" fn int_handler_lapic_timer(_frame: crate::interrupt::
 mod test {
     #[test_case]
     fn timer_ord_test() {
-        use super::Timer;
-        let t1 = Timer::new(1, 2);
-        let t2 = Timer::new(2, 3);
-        let t3 = Timer::new(1, 3);
-        assert!(t1 == t3);
+        use core::time::Duration;
+        use alloc::sync::Arc;
+        use super::{Entry, sleep_until};
+        use crate::time::Instant;
+        let at = |s| Instant::from_nanos(0) + Duration::from_secs(s);
+        let (s1, s2, s3) = (sleep_until(at(1)), sleep_until(at(2)), sleep_until(at(1)));
+        let entry = |s: &super::Sleep| Entry { inner: Arc::clone(&s.inner) };
+        let (t1, t2, t3) = (entry(&s1), entry(&s2), entry(&s3));
         assert!(t2 < t1);
         assert!(t1 > t2);
         assert!(t1 == t1);
         assert!(t3 > t2);
+        // 期限が同じなら先に作ったほうが先
+        assert!(t1 > t3);
     }
     #[test_case]
     fn timer_binary_heap() {
-        use super::Timer;
+        use core::time::Duration;
         use alloc::collections::BinaryHeap;
-        let mut heap = BinaryHeap::new();
-        heap.push(Timer::new(1, 2));
-        heap.push(Timer::new(2, 3));
-        heap.push(Timer::new(1, 3));
+        use alloc::sync::Arc;
+        use super::{Entry, sleep_until};
+        use crate::time::Instant;
+        let at = |s| Instant::from_nanos(0) + Duration::from_secs(s);
+        let sleeps = [sleep_until(at(1)), sleep_until(at(2)), sleep_until(at(1))];
+        let mut heap: BinaryHeap<Entry> = sleeps.iter().map(|s| Entry { inner: Arc::clone(&s.inner) }).collect();
         let v = heap.pop().unwrap();
-        assert!(v.inner.timeout() == 1);
+        assert!(Arc::ptr_eq(&v.inner, &sleeps[0].inner));
         let v2 = heap.pop().unwrap();
-        assert!(v2.inner.timeout() == 1);
+        assert!(Arc::ptr_eq(&v2.inner, &sleeps[2].inner));
         let v3 = heap.pop().unwrap();
-        assert!(v3.inner.timeout() == 2);
+        assert!(v3.inner.deadline == at(2));
+    }
+    #[allow(dead_code)]
+    fn pending_timers() -> usize {
+        let mut count = 0;
+        assert!(super::try_for_each_timer(|_| count += 1));
+        count
+    }
+    #[test_case]
+    fn dropped_sleep_is_cancelled() {
+        use core::pin::Pin;
+        use core::task::{Context, Waker};
+        use core::time::Duration;
+        use super::sleep;
+        let before = pending_timers();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut s = sleep(Duration::from_secs(3600));
+        assert!(Pin::new(&mut s).poll(&mut cx).is_pending());
+        // 何度pollしてもキューには一つだけ
+        assert!(Pin::new(&mut s).poll(&mut cx).is_pending());
+        assert_eq!(pending_timers(), before + 1);
+        drop(s);
+        assert_eq!(pending_timers(), before);
+        // 過ぎた期限ならキューに入れずに終わる
+        let mut s = sleep(Duration::ZERO);
+        assert!(Pin::new(&mut s).poll(&mut cx).is_ready());
+        assert_eq!(pending_timers(), before);
+    }
+    #[test_case]
+    fn timeout_cancels_its_timer() {
+        use core::pin::Pin;
+        use core::task::{Context, Poll, Waker};
+        use core::time::Duration;
+        use super::{Elapsed, timeout};
+        let before = pending_timers();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut t = timeout(Duration::from_secs(3600), core::future::ready(5));
+        assert_eq!(Pin::new(&mut t).poll(&mut cx), Poll::Ready(Ok(5)));
+        let mut t = timeout(Duration::ZERO, core::future::pending::<()>());
+        assert_eq!(Pin::new(&mut t).poll(&mut cx), Poll::Ready(Err(Elapsed)));
+        let mut t = timeout(Duration::from_secs(3600), core::future::pending::<()>());
+        assert!(Pin::new(&mut t).poll(&mut cx).is_pending());
+        assert_eq!(pending_timers(), before + 1);
+        drop(t);
+        assert_eq!(pending_timers(), before);
+    }
+    #[test_case]
+    fn interval_skips_missed_ticks() {
+        use core::time::Duration;
+        use super::next_deadline;
+        use crate::time::Instant;
+        let ms = |n: u64| Instant::from_nanos(n * 1_000_000);
+        let period = Duration::from_millis(100);
+        assert_eq!(next_deadline(ms(100), period, ms(150)), ms(200));
+        // 遅れても、過ぎた分をまとめて返さない
+        assert_eq!(next_deadline(ms(100), period, ms(520)), ms(600));
+        assert_eq!(next_deadline(ms(100), period, ms(200)), ms(300));
+    }
+    #[test_case]
+    fn tickless_deadlines() {
+        use super::{COUNT_MAX, ns_to_tick, oneshot_count, tick_start_ns};
+        assert_eq!(tick_start_ns(3), 30_000_000);
+        assert_eq!(ns_to_tick(tick_start_ns(3)), 3);
+        assert_eq!(ns_to_tick(tick_start_ns(3) - 1), 2);
+        assert_eq!(tick_start_ns(usize::MAX), u64::MAX);
+        // 1GHzのLAPICタイマで1ms
+        assert_eq!(oneshot_count(1_000_000, 1_000_000_000), 1_000_000);
+        // 過ぎた期限でも止めずにすぐ割り込ませる
+        assert_eq!(oneshot_count(0, 1_000_000_000), 1);
+        assert_eq!(oneshot_count(10_000_000_000, 1_000_000_000), COUNT_MAX);
     }
 }
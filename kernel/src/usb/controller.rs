@@ -70,6 +70,7 @@ impl XhcController {
         }
 
 
+        let mmio_base = crate::paging::phys_to_virt(mmio_base) as u64;
         let cap_reg = unsafe { &*(mmio_base as *const registers::CapabilityRegisters) };
         debug!("cap reg: {}", cap_reg.length());
 
@@ -143,18 +144,18 @@ impl XhcController {
         let dcbaap = cap_reg.dcbaap();
         let ptr = allocator.alloc_with_boundary_zeroed(Layout::new::<DeviceContextBaseAddressArray>(), pagesize);
         let dcbaap_ptr = unsafe { &mut *(ptr as *mut DeviceContextBaseAddressArray) }; // DCBAA.lock().x.as_ptr() as u64;
-        dcbaap.set_dcbaap(ptr as u64);
+        dcbaap.set_dcbaap(crate::paging::virt_to_phys(ptr as usize));
 
         if max_scratchpad_buffers > 0 {
             let arr = allocator.alloc_with_boundary_zeroed(Layout::from_size_align(max_scratchpad_buffers * 8, 64).unwrap(), pagesize) as *mut u64;
             for i in 0..max_scratchpad_buffers {
                 unsafe {
                     arr.add(i).write(
-                        allocator.alloc_with_boundary_zeroed(Layout::from_size_align(pagesize, pagesize).unwrap(), 0) as usize as u64
+                        crate::paging::virt_to_phys(allocator.alloc_with_boundary_zeroed(Layout::from_size_align(pagesize, pagesize).unwrap(), 0) as usize)
                     );
                 }
             }
-            dcbaap_ptr.0[0] = arr as usize as u64;
+            dcbaap_ptr.0[0] = crate::paging::virt_to_phys(arr as usize);
         }
 
         let crcr = cap_reg.crcr();
@@ -162,7 +163,7 @@ impl XhcController {
         let cr_ptr = unsafe { &mut *(ptr as *mut TRBTable) };
         assert!(ptr as u64 & 0x3f == 0);
         unsafe {
-            crcr.set_value(ptr as u64 | 1);
+            crcr.set_value(crate::paging::virt_to_phys(ptr as usize) | 1);
         }
         // crcr.set_pointer(ptr);
         // crcr.set_ring_cycle_state(true);
@@ -172,15 +173,15 @@ impl XhcController {
         assert!(ptr as u64 & 0x3f == 0);
         let erste_ptr = allocator.alloc_with_boundary_zeroed(Layout::new::<MemPoolERSTE>(), 0);
         let erste_lock = unsafe { &mut *(erste_ptr as *mut MemPoolERSTE) }; // ERSTE_BUF.lock();
-        erste_lock.x[0].addr = ptr as u64;
+        erste_lock.x[0].addr = crate::paging::virt_to_phys(ptr as usize);
         erste_lock.x[0].size = TRB_BUF_LEN as u16;
 
         unsafe {
             let runtime = cap_reg.runtime();
             let interrupt_regs = runtime.interrupt_set();
             interrupt_regs[0].event_ring_segment_table_size.write(1);
-            interrupt_regs[0].event_ring_dequeue_pointer.write(ptr as u64);
-            let ptr = erste_lock.x.as_ptr() as u64;
+            interrupt_regs[0].event_ring_dequeue_pointer.write(crate::paging::virt_to_phys(ptr as usize));
+            let ptr = crate::paging::virt_to_phys(erste_lock.x.as_ptr() as usize);
             assert!(ptr & 0x3f == 0);
             interrupt_regs[0].event_ring_segment_table_base_addr.write(ptr);
             interrupt_regs[0].moderation.write(4000);
@@ -320,7 +321,7 @@ impl XhcController {
             }
         };
 
-        let ptr = dev.transfer_rings[0].x.0.as_ptr() as *const TRB as u64;
+        let ptr = crate::paging::virt_to_phys(dev.transfer_rings[0].x.0.as_ptr() as usize);
         assert!(ptr & 0x3f == 0);
         dev.input_ctx.ep_ctx(0)[1] = max_packet << 16 | 4 << 3 | 3 << 1;
         dev.input_ctx.ep_ctx(0)[2] = (ptr & 0xffffffc0) as u32 | 1;
@@ -417,7 +418,7 @@ pub fn init_xhc(devices: &[Device]) -> Result<Box<XhcController>, crate::error::
     info!("xHC has been found: {}.{}.{}", xhc_dev.bus(), xhc_dev.device(), xhc_dev.func());
 
     unsafe {
-        let bsp_local_apic_id = (*(0xfee00020 as *const u32) >> 24) as u8;
+        let bsp_local_apic_id = crate::interrupt::local_apic_id();
         crate::pci::configure_msi_fixed_destination(xhc_dev, bsp_local_apic_id, crate::pci::MSITriggerMode::Level, crate::pci::MSIDeliveryMode::Fixed, crate::interrupt::InterruptVector::XHCI as u8, 0);
     }
 
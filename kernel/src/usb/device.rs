@@ -110,8 +110,8 @@ impl DeviceContextEnum {
     }
     pub fn as_inner_ptr(&self) -> u64 {
         match self {
-            DeviceContextEnum::V1(x) => *x as *const DeviceContext as u64,
-            DeviceContextEnum::V2(x) => *x as *const DeviceContext64 as u64,
+            DeviceContextEnum::V1(x) => crate::paging::virt_to_phys(*x as *const DeviceContext as usize),
+            DeviceContextEnum::V2(x) => crate::paging::virt_to_phys(*x as *const DeviceContext64 as usize),
         }
     }
 }
@@ -142,8 +142,8 @@ impl InputContextEnum {
     }
     pub fn as_inner_ptr(&self) -> u64 {
         match self {
-            InputContextEnum::V1(x) => *x as *const InputContext as u64,
-            InputContextEnum::V2(x) => *x as *const InputContext64 as u64,
+            InputContextEnum::V1(x) => crate::paging::virt_to_phys(*x as *const InputContext as usize),
+            InputContextEnum::V2(x) => crate::paging::virt_to_phys(*x as *const InputContext64 as usize),
         }
     }
 }
@@ -180,7 +180,7 @@ impl XhciDevice {
                 2 << 10 | 3 << 16 | 1 << 6
             ]
         };
-        let ptr = self.buf.as_ptr() as u64;
+        let ptr = crate::paging::virt_to_phys(self.buf.as_ptr() as usize);
         let data_trb = TRB {
             data: [
                 (ptr & 0xffffffff) as u32,
@@ -198,7 +198,7 @@ impl XhciDevice {
         let ptr = &mut self.transfer_rings[0];
         ptr.push(setup_trb);
         ptr.push(data_trb);
-        SETUP_TRB_MAP.lock().insert(ptr.center() as *const TRB as u64, setup_trb).unwrap();
+        SETUP_TRB_MAP.lock().insert(crate::paging::virt_to_phys(ptr.center() as *const TRB as usize), setup_trb).unwrap();
         ptr.push(status_trb);
 
         self.doorbell().ring(1, 0);
@@ -217,7 +217,7 @@ impl XhciDevice {
                 2 << 10 | 1 << 6
             ]
         };
-        let ptr = self.buf.as_ptr() as u64;
+        let ptr = crate::paging::virt_to_phys(self.buf.as_ptr() as usize);
         let data_trb = TRB {
             data: [
                 (ptr & 0xffffffff) as u32,
@@ -235,7 +235,7 @@ impl XhciDevice {
         let ptr = &mut self.transfer_rings[0];
         ptr.push(setup_trb);
         ptr.push(data_trb);
-        SETUP_TRB_MAP.lock().insert(ptr.center() as *const TRB as u64, setup_trb).unwrap();
+        SETUP_TRB_MAP.lock().insert(crate::paging::virt_to_phys(ptr.center() as *const TRB as usize), setup_trb).unwrap();
         ptr.push(status_trb);
 
         self.doorbell().ring(1, 0);
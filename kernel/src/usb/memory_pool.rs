@@ -25,7 +25,7 @@ impl MemPoolCrTRB {
         }
         self.index += 1;
         if self.index == TRB_BUF_LEN - 1 {
-            let mut link = TRB::new_link_trb(self.x.0.as_ptr() as u64);
+            let mut link = TRB::new_link_trb(crate::paging::virt_to_phys(self.x.0.as_ptr() as usize));
             link.data[3] = link.data[3] | (self.cycle as u32);
             for i in 0..4 {
                 self.x.0[self.index].data[i] = link.data[i];
@@ -74,7 +74,7 @@ impl MemPoolErTRB {
         unsafe {
             let interrupt_reg = xhc.capability.runtime().interrupt_set();
             let p = interrupt_reg[0].event_ring_dequeue_pointer.read() & 0xf;
-            interrupt_reg[0].event_ring_dequeue_pointer.write(p | (&self.x.0[self.index] as *const TRB as u64));
+            interrupt_reg[0].event_ring_dequeue_pointer.write(p | crate::paging::virt_to_phys(&self.x.0[self.index] as *const TRB as usize));
         }
     }
 }
@@ -117,7 +117,7 @@ impl MemPoolTrTRB {
         }
         self.index += 1;
         if self.index == TRB_BUF_LEN - 1 {
-            let mut link = TRB::new_link_trb(self.x.0.as_ptr() as u64);
+            let mut link = TRB::new_link_trb(crate::paging::virt_to_phys(self.x.0.as_ptr() as usize));
             link.data[3] = (link.data[3] & !0x1) | (self.cycle as u32);
             for i in 0..4 {
                 self.x.0[self.index].data[i] = link.data[i];
@@ -112,7 +112,7 @@ impl TRBtrait for CommandCompletionEventTRB {
 
 impl CommandCompletionEventTRB {
     fn ptr(&self) -> &TRB {
-        unsafe { &*((self.trb_ptr & !0xf) as *const TRB) }
+        unsafe { &*(crate::paging::phys_to_virt(self.trb_ptr & !0xf) as *const TRB) }
     }
     fn slot_id(&self) -> u8 {
         (self.data[1] >> 24) as u8
@@ -166,14 +166,14 @@ impl TRBtrait for TransferEventTRB {
 
 impl TransferEventTRB {
     fn ptr(&self) -> &TRB {
-        unsafe { &*(self.trb_ptr as *const TRB) }
+        unsafe { &*(crate::paging::phys_to_virt(self.trb_ptr) as *const TRB) }
     }
     fn slot_id(&self) -> u8 {
         (self.data[1] >> 24) as u8
     }
     fn set_normal_trb(&self, dev: &mut XhciDevice) {
         let dci = (dev.default + 1) * 2 + 1;  // default driver interrupt in
-        let ptr = dev.buf.as_ptr() as u64;
+        let ptr = crate::paging::virt_to_phys(dev.buf.as_ptr() as usize);
         dev.transfer_rings[dci - 1].push(TRB {
             data: [
                 (ptr & 0xffffffff) as u32,
@@ -289,7 +289,7 @@ impl TransferEventTRB {
                         let dci = (buf[2] & 0b111) * 2 + (buf[2] >> 7);
                         debug!("buf[2] & 0b111: {}, buf[2] >> 7: {}", buf[2] & 0b111, buf[2] >> 7);
                         dev.input_ctx.input_control_ctx()[1] |= 1 << (dci as u32);
-                        let ptr = dev.transfer_rings[dci as usize - 1].x.0.as_ptr() as u64;
+                        let ptr = crate::paging::virt_to_phys(dev.transfer_rings[dci as usize - 1].x.0.as_ptr() as usize);
                         let ep_type = match (buf[2] >> 7, buf[3] & 0b11) {
                             (0, 1) => 1u32,
                             (0, 2) => 2,
@@ -331,7 +331,7 @@ impl TransferEventTRB {
                 base += dev.buf[base] as usize;
             }
             debug!("input context control: {:?}", dev.input_ctx.input_control_ctx());
-            let ptr = dev.input_ctx.input_control_ctx().as_ptr() as u64;
+            let ptr = crate::paging::virt_to_phys(dev.input_ctx.input_control_ctx().as_ptr() as usize);
             assert!(ptr & 0x3f == 0);
             xhc.command_ring.push(TRB {
                 data: [
@@ -28,3 +28,9 @@ else
     mcopy -i disk.img kernel/target/x86_64-unknown-none/debug/kernel ::/kernel
     mcopy -i disk.img bootloader/target/x86_64-unknown-uefi/debug/mikanos-rust.efi ::/EFI/BOOT/BOOTX64.EFI
 fi
+
+# 起動オプション(boot.cfg)があれば一緒に入れる
+if [ -e boot.cfg ]
+then
+    mcopy -i disk.img boot.cfg ::/boot.cfg
+fi
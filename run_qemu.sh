@@ -13,6 +13,7 @@ else
     diskimg="disk.img"
 fi
 
+# COM2はboot.cfgでgdb=com2としたときのGDBの接続先(target remote :1234)
 # WSLg環境下では、waylandで起動する場合画面上半分でカーソルが動かなくなる
 # 実際の環境に近づけるためUSBからBootする
 GDK_BACKEND=x11 qemu-system-x86_64 \
@@ -22,6 +23,7 @@ GDK_BACKEND=x11 qemu-system-x86_64 \
     -drive if=pflash,format=raw,readonly=on,file=./lib/OVMF_VARS.fd \
     -drive file=${diskimg},format=raw,if=none,id=stick \
     -serial stdio \
+    -serial tcp::1234,server,nowait \
     -device nec-usb-xhci \
     -device usb-kbd \
     -device usb-mouse \
@@ -0,0 +1,32 @@
+#!/bin/sh
+# FATイメージを作らずにGRUB(Multiboot2)経由で起動する
+# grub-mkrescue(とxorriso)が必要
+# 使い方: ./run_qemu_grub.sh <カーネルのパス> [カーネルコマンドライン]
+
+kernel="${1:-kernel/target/x86_64-unknown-none/debug/kernel}"
+
+if ! grub-file --is-x86-multiboot2 "$kernel"; then
+    echo "$kernel is not a multiboot2 kernel"
+    exit 1
+fi
+
+mkdir -p target/iso/boot/grub
+cp "$kernel" target/iso/boot/kernel
+cat > target/iso/boot/grub/grub.cfg <<EOF
+set timeout=0
+insmod all_video
+menuentry "mikanos-rust" {
+    multiboot2 /boot/kernel $2
+    boot
+}
+EOF
+grub-mkrescue -o target/mikanos.iso target/iso 2> /dev/null
+
+qemu-system-x86_64 \
+    -m 1G \
+    -cdrom target/mikanos.iso \
+    -serial stdio \
+    -device nec-usb-xhci \
+    -device usb-kbd \
+    -device usb-mouse \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04
@@ -0,0 +1,133 @@
+#!/usr/bin/env python3
+# シリアルのログ(またはcrash-*.txt)からクラッシュダンプを取り出して整形する
+# 形式はkernel/src/crash_dump.rsを参照
+#
+#   python3 tools/crash_dump.py serial.log
+#   ./run_qemu.sh | tee serial.log; python3 tools/crash_dump.py < serial.log
+
+import argparse
+import re
+import sys
+import zlib
+
+BEGIN = re.compile(rb"^===== BEGIN CRASH DUMP v(\d+) =====\r?$")
+END = re.compile(rb"^===== END CRASH DUMP length=(\d+) crc32=([0-9a-f]{8}) =====\r?$")
+
+SUPPORTED_VERSION = 1
+LIST_SECTIONS = {
+    "backtrace": ["return address", "offset", "function", "location"],
+    "log": ["time", "level", "module", "message"],
+    "tasks": ["id", "polls", "name"],
+}
+
+
+def unescape(s):
+    out = []
+    it = iter(s)
+    for c in it:
+        if c != "\\":
+            out.append(c)
+            continue
+        n = next(it, "")
+        out.append({"n": "\n", "t": "\t", "\\": "\\"}.get(n, "\\" + n))
+    return "".join(out)
+
+
+def find_dumps(data):
+    lines = data.splitlines(keepends=True)
+    i = 0
+    while i < len(lines):
+        m = BEGIN.match(lines[i].rstrip(b"\n"))
+        if not m:
+            i += 1
+            continue
+        version = int(m.group(1))
+        body = []
+        i += 1
+        while i < len(lines):
+            e = END.match(lines[i].rstrip(b"\n"))
+            if e:
+                yield version, b"".join(body), int(e.group(1)), int(e.group(2), 16)
+                break
+            body.append(lines[i])
+            i += 1
+        else:
+            yield version, b"".join(body), None, None
+        i += 1
+
+
+def parse(body):
+    sections = []
+    for line in body.decode("utf-8", errors="replace").splitlines():
+        line = line.rstrip("\r")
+        if not line:
+            continue
+        if line.startswith("@"):
+            sections.append((line[1:], []))
+        elif sections:
+            sections[-1][1].append(line)
+    return sections
+
+
+def print_section(name, lines):
+    print(f"== {name} ==")
+    if name in LIST_SECTIONS:
+        rows = [[unescape(f) for f in line.split("\t")] for line in lines]
+        if name == "backtrace":
+            for i, row in enumerate(rows):
+                row += [""] * (4 - len(row))
+                location = f" ({row[3]})" if row[3] else ""
+                print(f"  #{i:<2} {row[1]:>8} {row[2]}{location}")
+        elif name == "log":
+            for row in rows:
+                row += [""] * (4 - len(row))
+                print(f"  [{row[0]:>8}] {row[1]:<5} {row[2]}: {row[3]}")
+        else:
+            for row in rows:
+                print("  " + "  ".join(row))
+        if not rows:
+            print("  (empty or unavailable)")
+    elif name == "registers":
+        values = [line.split("=", 1) for line in lines]
+        for i in range(0, len(values), 4):
+            print("  " + "  ".join(f"{k:>6}={v}" for k, v in values[i:i + 4]))
+    elif name == "memory":
+        values = dict(line.split("=", 1) for line in lines if "=" in line)
+        if "frames_total" in values:
+            total, allocated = int(values["frames_total"]), int(values["frames_allocated"])
+            frame = int(values.get("bytes_per_frame", 4096))
+            print(f"  frames: {allocated}/{total} allocated ({allocated * frame // 1024 // 1024} MiB / {total * frame // 1024 // 1024} MiB)")
+        if "heap_total" in values:
+            print(f"  heap  : {int(values['heap_used'])}/{int(values['heap_total'])} bytes used")
+    else:
+        for line in lines:
+            key, _, value = line.partition("=")
+            print(f"  {key}: {unescape(value)}")
+    print()
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Pretty-print kernel crash dumps")
+    parser.add_argument("file", nargs="?", help="serial log or crash dump file (default: stdin)")
+    args = parser.parse_args()
+
+    data = open(args.file, "rb").read() if args.file else sys.stdin.buffer.read()
+    found = False
+    for n, (version, body, length, crc) in enumerate(find_dumps(data)):
+        found = True
+        print(f"######## crash dump #{n} (v{version}) ########")
+        if version != SUPPORTED_VERSION:
+            print(f"warning: unsupported version {version}")
+        if length is None:
+            print("warning: END marker is missing, the dump is incomplete")
+        elif length != len(body) or crc != zlib.crc32(body):
+            print(f"warning: checksum mismatch (length {len(body)}/{length}, crc32 {zlib.crc32(body):08x}/{crc:08x})")
+        for name, lines in parse(body):
+            print_section(name, lines)
+    if not found:
+        print("no crash dump found", file=sys.stderr)
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()